@@ -0,0 +1,34 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use chess_lib::fen::load_fen;
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::moves::legal_moves;
+
+use chess_ai::bench::run_bench;
+
+fn bench_search(c: &mut Criterion) {
+    let mbb = MagicBitBoards::shared();
+
+    let mut group = c.benchmark_group("search");
+    group.sample_size(10);
+    group.bench_function("bench positions depth 4", |b| b.iter(|| run_bench(4, 1 << 16, mbb)));
+    group.finish();
+}
+
+fn bench_evasions(c: &mut Criterion) {
+    let mbb = MagicBitBoards::shared();
+
+    // Single checks from a slider, a knight and a pawn, and a double check.
+    let states: Vec<_> = [
+        "4k3/8/8/8/1b6/8/PPP2PPP/RN2K1NR w K - 0 1",
+        "rnbqkbnr/pppp1ppp/8/8/4P3/3n4/PPPP1PPP/RNBQKBNR w KQkq - 0 1",
+        "rnbqkbnr/ppp1pppp/8/8/8/8/PPPp1PPP/RNBQKBNR w KQkq - 0 1",
+        "4k3/4r3/8/8/1b6/8/8/R3K3 w - - 0 1",
+    ].iter().map(|fen| load_fen(fen)).collect();
+
+    c.bench_function("legal moves in check", |b| b.iter(|| {
+        states.iter().map(|state| legal_moves(state, mbb).len()).sum::<usize>()
+    }));
+}
+
+criterion_group!(benches, bench_search, bench_evasions);
+criterion_main!(benches);
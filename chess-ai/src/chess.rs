@@ -1,13 +1,38 @@
 use chess_lib::magic::MagicBitBoards;
-use chess_lib::moves::legal_moves;
+use chess_lib::moves::{legal_captures, legal_moves};
 use chess_lib::types as chess;
 
+use crate::eval::piece_value;
+use crate::see::static_exchange_eval_move;
 use crate::minimax;
 use crate::montecarlo;
 
+// Which rules end the game in a draw for MCTS. Analysis and puzzles may want to turn some of them
+// off. Minimax always plays by the standard rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrawRules {
+    // Compared against the fifty-move clock, which counts half-moves since the last capture or
+    // pawn move. None disables the rule.
+    pub fifty_move_limit: Option<u8>,
+    pub repetition: bool,
+    pub insufficient_material: bool,
+}
+
+impl Default for DrawRules {
+    fn default() -> DrawRules {
+        DrawRules{
+            // What MCTS has always used, even though the full rule would be 100 half-moves.
+            fifty_move_limit: Some(50),
+            repetition: true,
+            insufficient_material: true,
+        }
+    }
+}
+
 pub struct Chess<'a> {
     pub state: chess::GameState,
     pub mbb: &'a MagicBitBoards,
+    pub draw_rules: DrawRules,
 }
 
 impl <'a> Chess<'a> {
@@ -15,13 +40,42 @@ impl <'a> Chess<'a> {
         Chess{
             state,
             mbb,
+            draw_rules: DrawRules::default(),
+        }
+    }
+
+    pub fn with_draw_rules(mut self, draw_rules: DrawRules) -> Chess<'a> {
+        self.draw_rules = draw_rules;
+        self
+    }
+
+    // The position history lives in the GameState, so clones carry their own copy of it and
+    // sibling search branches never see each other's moves.
+    pub fn is_repetition(&self) -> bool {
+        self.state.is_threefold_repetition()
+    }
+
+    // Drawn by one of the enabled rules, ignoring mate and stalemate.
+    pub fn is_draw_by_rule(&self) -> bool {
+        let rules = self.draw_rules;
+        (rules.repetition && self.is_repetition())
+            || (rules.insufficient_material && self.state.is_insufficient_material())
+            || rules.fifty_move_limit.map(|limit| self.state.fifty_move_clock >= limit).unwrap_or(false)
+    }
+
+    // The outcome for `colour` once the game is over, whoever is to move. None while it's ongoing.
+    pub fn result_for(&self, colour: chess::Colour) -> Option<montecarlo::GameResult> {
+        match montecarlo::Game::game_state(self) {
+            montecarlo::GameState::Ongoing => None,
+            montecarlo::GameState::Finished(result) if colour == self.state.active_colour => Some(result),
+            montecarlo::GameState::Finished(result) => Some(montecarlo::GameResult::reverse(result)),
         }
     }
 }
 
 impl <'a> Clone for Chess<'a> {
     fn clone(&self) -> Chess<'a> {
-        Chess::new(self.state.clone(), &self.mbb)
+        Chess::new(self.state.clone(), &self.mbb).with_draw_rules(self.draw_rules)
     }
 }
 
@@ -36,9 +90,58 @@ impl <'a> minimax::Game for Chess<'a> {
         legal_moves(&self.state, &self.mbb)
     }
 
+    fn legal_captures(&self) -> Vec<Self::Move> {
+        legal_captures(&self.state, &self.mbb)
+    }
+
+    fn is_in_check(&self) -> bool {
+        self.state.is_in_check(&self.mbb)
+    }
+
+    fn is_capture(&self, mv: Self::Move) -> bool {
+        match mv {
+            chess::Move::Normal(_, _, tgt) | chess::Move::Promotion(_, tgt, _) => self.state.find_piece(tgt).is_some(),
+            chess::Move::EnPassant(_, _) => true,
+            _ => false,
+        }
+    }
+
+    fn mvv_lva(&self, mv: Self::Move) -> Option<i64> {
+        let victim_value = |tgt| self.state.find_piece(tgt).map(|(_, pc)| piece_value(pc));
+
+        match mv {
+            chess::Move::Normal(piece, _, tgt) => victim_value(tgt).map(|v| v - piece_value(piece)),
+            chess::Move::EnPassant(_, _) => Some(0),
+            // Promotions gain the value of the new piece, on top of anything captured.
+            chess::Move::Promotion(_, tgt, pc) => {
+                Some(victim_value(tgt).unwrap_or(0) + piece_value(pc) - piece_value(chess::Piece::Pawn))
+            },
+            _ => None,
+        }
+    }
+
+    fn static_exchange_eval(&self, mv: Self::Move) -> i64 {
+        static_exchange_eval_move(&self.state, mv, self.mbb)
+    }
+
+    // Side to move, then the source and target squares from the packed move.
+    fn history_index(&self, mv: Self::Move) -> usize {
+        let colour = match self.state.active_colour {
+            chess::Colour::White => 0,
+            chess::Colour::Black => 1,
+        };
+        colour << 12 | (mv.to_u16() & 0xfff) as usize
+    }
+
     fn zobrist_hash(&self) -> chess::ZobristHash {
         self.state.zh
     }
+
+    fn is_draw(&self) -> bool {
+        self.is_repetition()
+            || self.state.is_insufficient_material()
+            || self.state.fifty_move_clock >= 100
+    }
 }
 
 impl <'a> montecarlo::Game for Chess<'a> {
@@ -52,21 +155,14 @@ impl <'a> montecarlo::Game for Chess<'a> {
         legal_moves(&self.state, &self.mbb)
     }
 
+    // Mate and stalemate come from GameState::status. Its other draws use the standard rules, so
+    // they're replaced by whichever of our draw rules are enabled.
     fn game_state(&self) -> montecarlo::GameState {
-        let num_moves = self.legal_moves().len();
-        if num_moves == 0 {
-            if self.state.is_in_check(&self.mbb) {
-                // Checkmate.
-                montecarlo::GameState::Finished(montecarlo::GameResult::Loss)
-            } else {
-                // Stalemate.
-                montecarlo::GameState::Finished(montecarlo::GameResult::Draw)
-            }
-        } else if self.state.fifty_move_clock >= 50 {
-            // Fifty move rule.
-            montecarlo::GameState::Finished(montecarlo::GameResult::Draw)
-        } else {
-            montecarlo::GameState::Ongoing
+        match self.state.status(self.mbb) {
+            chess::Status::Checkmate => montecarlo::GameState::Finished(montecarlo::GameResult::Loss),
+            chess::Status::Stalemate => montecarlo::GameState::Finished(montecarlo::GameResult::Draw),
+            _ if self.is_draw_by_rule() => montecarlo::GameState::Finished(montecarlo::GameResult::Draw),
+            _ => montecarlo::GameState::Ongoing,
         }
     }
 
@@ -78,3 +174,89 @@ impl <'a> montecarlo::Game for Chess<'a> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use chess_lib::fen::{load_fen, STARTING_POSITION};
+    use chess_lib::magic::MagicBitBoards;
+    use chess_lib::pgn::parse_san;
+    use chess_lib::types::{Colour, Status};
+
+    use crate::chess::{Chess, DrawRules};
+    use crate::minimax::Game;
+    use crate::montecarlo::{self, GameResult, GameState};
+
+    #[test]
+    fn clones_keep_separate_history() {
+        let mbb = MagicBitBoards::default();
+        let mut chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let play = |chess: &mut Chess, moves: &[&str]| {
+            for san in moves.iter() {
+                let mv = parse_san(&chess.state, san, &mbb).unwrap();
+                Game::make_move(chess, mv);
+            }
+        };
+
+        play(&mut chess, &["Nf3", "Nf6", "Ng1", "Ng8", "Nf3", "Nf6", "Ng1"]);
+
+        let mut repeated = chess.clone();
+        let mut sibling = chess.clone();
+        play(&mut repeated, &["Ng8"]);
+        play(&mut sibling, &["Nc6"]);
+
+        assert!(repeated.is_repetition());
+        assert!(repeated.is_draw());
+        assert!(!sibling.is_repetition());
+        assert!(!chess.is_repetition());
+    }
+
+    #[test]
+    fn configurable_fifty_move_rule() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 60 80");
+        let status = |rules: DrawRules| montecarlo::Game::game_state(&Chess::new(state.clone(), &mbb).with_draw_rules(rules).clone());
+
+        assert_eq!(status(DrawRules::default()), GameState::Finished(GameResult::Draw));
+
+        let disabled = DrawRules{fifty_move_limit: None, ..DrawRules::default()};
+        assert_eq!(status(disabled), GameState::Ongoing);
+
+        // Minimax isn't affected, and still waits for the full hundred half-moves.
+        assert!(!Chess::new(state.clone(), &mbb).is_draw());
+        assert!(Chess::new(load_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 100 80"), &mbb).is_draw());
+    }
+
+    #[test]
+    fn game_state_follows_status() {
+        let mbb = MagicBitBoards::default();
+        let game_state = |fen: &str, rules: DrawRules| montecarlo::Game::game_state(&Chess::new(load_fen(fen), &mbb).with_draw_rules(rules));
+        let no_draws = DrawRules{fifty_move_limit: None, repetition: false, insufficient_material: false};
+
+        // Mate still counts when the clock has run out.
+        let mated = "R5k1/5ppp/8/8/8/8/8/6K1 b - - 100 80";
+        assert_eq!(load_fen(mated).status(&mbb), Status::Checkmate);
+        assert_eq!(game_state(mated, DrawRules::default()), GameState::Finished(GameResult::Loss));
+
+        // Draws which status reports are dropped if the rule is turned off.
+        let bare_kings = "4k3/8/8/8/8/8/8/4K3 w - - 0 1";
+        assert_eq!(load_fen(bare_kings).status(&mbb), Status::InsufficientMaterial);
+        assert_eq!(game_state(bare_kings, DrawRules::default()), GameState::Finished(GameResult::Draw));
+        assert_eq!(game_state(bare_kings, no_draws), GameState::Ongoing);
+    }
+
+    #[test]
+    fn result_for_colour() {
+        let mbb = MagicBitBoards::default();
+
+        // Back rank mate, with Black to move.
+        let mated = Chess::new(load_fen("R5k1/5ppp/8/8/8/8/8/6K1 b - - 0 1"), &mbb);
+        assert_eq!(mated.result_for(Colour::White), Some(GameResult::Win));
+        assert_eq!(mated.result_for(Colour::Black), Some(GameResult::Loss));
+
+        let stalemate = Chess::new(load_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), &mbb);
+        assert_eq!(stalemate.result_for(Colour::White), Some(GameResult::Draw));
+        assert_eq!(stalemate.result_for(Colour::Black), Some(GameResult::Draw));
+
+        let ongoing = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        assert_eq!(ongoing.result_for(Colour::White), None);
+    }
+}
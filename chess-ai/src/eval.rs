@@ -1,28 +1,374 @@
-use chess_lib::types::{BitBoard, Colour, Pieces};
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::moves::pawn_attacks;
+use chess_lib::types::{BitBoard, BitCoord, Colour, Pieces, SideState};
 use crate::chess::Chess;
 
 // Score in centipawns.
+// Some terms have separate midgame and endgame values, which are blended according to how much
+// material is left on the board.
 pub fn evaluate(chess: &Chess) -> i64 {
     let (active_side, other_side) = match chess.state.active_colour {
         Colour::White => (&chess.state.white, &chess.state.black),
         Colour::Black => (&chess.state.black, &chess.state.white),
     };
 
-    count_material(&active_side.pieces) - count_material(&other_side.pieces)
+    let active_colour = chess.state.active_colour;
+    let other_colour = Colour::other(active_colour);
+
+    let occupancy = active_side.pieces.all() | other_side.pieces.all();
+
+    let active_pawns = active_side.pieces.pawns;
+    let other_pawns = other_side.pieces.pawns;
+
+    let (active_material_mg, active_material_eg) = count_material(active_side);
+    let (other_material_mg, other_material_eg) = count_material(other_side);
+    let (active_pawns_mg, active_pawns_eg) = pawn_structure(active_pawns, other_pawns, active_colour);
+    let (other_pawns_mg, other_pawns_eg) = pawn_structure(other_pawns, active_pawns, other_colour);
+    let active_attacks = AttackInfo::new(&active_side.pieces, active_colour, occupancy, chess.mbb);
+    let other_attacks = AttackInfo::new(&other_side.pieces, other_colour, occupancy, chess.mbb);
+
+    let mobility = mobility(&active_side.pieces, &active_attacks) - mobility(&other_side.pieces, &other_attacks);
+    let king_safety = king_danger(other_side, &active_side.pieces, &active_attacks, chess.mbb)
+        - king_danger(active_side, &other_side.pieces, &other_attacks, chess.mbb);
+
+    let mg = active_material_mg - other_material_mg + active_pawns_mg - other_pawns_mg + mobility + king_safety;
+    let eg = active_material_eg - other_material_eg + active_pawns_eg - other_pawns_eg + mobility;
+
+    let phase = game_phase(&chess.state.white.pieces) + game_phase(&chess.state.black.pieces);
+    (mg * phase + eg * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+const MAX_PHASE: i64 = 24;
+
+// 24 with all the pieces on the board, down to 0 when only kings and pawns remain.
+// Promotions could take it higher, so it's capped.
+fn game_phase(pieces: &Pieces) -> i64 {
+    let phase = pieces.knights.count() + pieces.bishops.count() + 2 * pieces.rooks.count() + 4 * pieces.queens.count();
+    (phase as i64).min(MAX_PHASE / 2)
+}
+
+pub use chess_lib::material::piece_value;
+
+// Midgame and endgame values, which only differ in where the king wants to be.
+// Kept up to date by make_move, so there's nothing to count.
+fn count_material(side: &SideState) -> (i64, i64) {
+    let score = side.score;
+    (score.material + score.pst_mg, score.material + score.pst_eg)
+}
+
+// The squares one side's pieces attack, so the terms below don't each repeat the lookups.
+// Sliders are blocked by pieces of either colour, and squares occupied by friendly pieces count
+// as attacked, i.e. defended.
+pub struct AttackInfo {
+    // Indexed by the square of the attacking piece.
+    pub from: [BitBoard; 64],
+    pub pawns: BitBoard,
+    pub knights: BitBoard,
+    pub bishops: BitBoard,
+    pub rooks: BitBoard,
+    pub queens: BitBoard,
+    pub king: BitBoard,
+    pub all: BitBoard,
+}
+
+impl AttackInfo {
+    pub fn new(pieces: &Pieces, colour: Colour, occupancy: BitBoard, mbb: &MagicBitBoards) -> AttackInfo {
+        let mut from = [BitBoard::EMPTY; 64];
+        let mut attacks = |bb: BitBoard, lookup: &dyn Fn(BitCoord) -> BitBoard| {
+            bb.iter().fold(BitBoard::EMPTY, |all, c| {
+                let atks = lookup(c);
+                from[c.index() as usize] = atks;
+                all | atks
+            })
+        };
+
+        let pawns = attacks(pieces.pawns, &|c| pawn_attacks(c, colour));
+        let knights = attacks(pieces.knights, &|c| mbb.knight(c));
+        let bishops = attacks(pieces.bishops, &|c| mbb.bishop(c).lookup(occupancy));
+        let rooks = attacks(pieces.rooks, &|c| mbb.rook(c).lookup(occupancy));
+        let queens = attacks(pieces.queens, &|c| mbb.rook(c).lookup(occupancy) | mbb.bishop(c).lookup(occupancy));
+        let king = attacks(pieces.king, &|c| mbb.king(c));
+
+        AttackInfo{
+            from,
+            pawns,
+            knights,
+            bishops,
+            rooks,
+            queens,
+            king,
+            all: pawns | knights | bishops | rooks | queens | king,
+        }
+    }
+}
+
+// Centipawns per square attacked. Uses attack sets rather than legal moves, which is much cheaper
+// and close enough for evaluation purposes.
+const KNIGHT_MOBILITY: i64 = 4;
+const BISHOP_MOBILITY: i64 = 3;
+const ROOK_MOBILITY: i64 = 2;
+const QUEEN_MOBILITY: i64 = 1;
+const KING_MOBILITY: i64 = 1;
+
+fn mobility(pieces: &Pieces, attacks: &AttackInfo) -> i64 {
+    let available = !pieces.all();
+    let count = |bb: BitBoard, weight: i64| -> i64 {
+        bb.iter().map(|c| (attacks.from[c.index() as usize] & available).count() as i64 * weight).sum()
+    };
+
+    count(pieces.knights, KNIGHT_MOBILITY)
+        + count(pieces.bishops, BISHOP_MOBILITY)
+        + count(pieces.rooks, ROOK_MOBILITY)
+        + count(pieces.queens, QUEEN_MOBILITY)
+        + count(pieces.king, KING_MOBILITY)
+}
+
+// Weights for pieces attacking the squares around the enemy king. One attacker on its own can
+// rarely do much, so the total is scaled by how many pieces join in, as a percentage.
+const KNIGHT_KING_ATTACK: i64 = 20;
+const BISHOP_KING_ATTACK: i64 = 20;
+const ROOK_KING_ATTACK: i64 = 40;
+const QUEEN_KING_ATTACK: i64 = 80;
+const KING_ATTACKERS_SCALE: [i64; 8] = [0, 0, 50, 75, 88, 94, 97, 99];
+// For a king still in the centre that can no longer castle.
+const UNCASTLED_KING_PENALTY: i64 = 40;
+
+// Midgame penalty for the given side's king. Only counts in the midgame, since in the endgame the
+// king needs to come out and fight.
+// `other_attacks` are the attacks of `other_pieces`, the opponent's.
+fn king_danger(side: &SideState, other_pieces: &Pieces, other_attacks: &AttackInfo, mbb: &MagicBitBoards) -> i64 {
+    let king = match side.pieces.king.iter().next() {
+        Some(king) => king,
+        None => return 0,
+    };
+
+    let zone = mbb.king(king) | king;
+    if (other_attacks.all & zone).is_empty() {
+        return uncastled_penalty(side, king);
+    }
+
+    let mut count = 0;
+    let mut weight = 0;
+    let attackers = [
+        (other_pieces.knights, KNIGHT_KING_ATTACK),
+        (other_pieces.bishops, BISHOP_KING_ATTACK),
+        (other_pieces.rooks, ROOK_KING_ATTACK),
+        (other_pieces.queens, QUEEN_KING_ATTACK),
+    ];
+    for (bb, piece_weight) in attackers.iter() {
+        for c in bb.iter() {
+            if !(other_attacks.from[c.index() as usize] & zone).is_empty() {
+                count += 1;
+                weight += piece_weight;
+            }
+        }
+    }
+
+    weight * KING_ATTACKERS_SCALE[count.min(7)] / 100 + uncastled_penalty(side, king)
+}
+
+fn uncastled_penalty(side: &SideState, king: BitCoord) -> i64 {
+    let in_centre = (2..=5).contains(&king.file());
+    if in_centre && !side.can_castle_kingside && !side.can_castle_queenside {
+        UNCASTLED_KING_PENALTY
+    } else {
+        0
+    }
 }
 
-fn count_material(pieces: &Pieces) -> i64 {
-    let mut material: i64 = 0;
+const DOUBLED_PAWN_PENALTY: i64 = 15;
+const ISOLATED_PAWN_PENALTY: i64 = 15;
+// Indexed by how many ranks the pawn has advanced from its own back rank.
+const PASSED_PAWN_BONUS: [i64; 8] = [0, 5, 10, 20, 35, 60, 100, 0];
+const PASSED_PAWN_ENDGAME_BONUS: [i64; 8] = [0, 10, 20, 40, 70, 120, 200, 0];
 
-    material += count_piece(pieces.queens, 900);
-    material += count_piece(pieces.rooks, 500);
-    material += count_piece(pieces.bishops, 300);
-    material += count_piece(pieces.knights, 300);
-    material += count_piece(pieces.pawns, 100);
+// File a is the highest bit of each rank.
+const FILE_MASKS: [BitBoard; 8] = [
+    BitBoard(0x80_80_80_80_80_80_80_80),
+    BitBoard(0x40_40_40_40_40_40_40_40),
+    BitBoard(0x20_20_20_20_20_20_20_20),
+    BitBoard(0x10_10_10_10_10_10_10_10),
+    BitBoard(0x08_08_08_08_08_08_08_08),
+    BitBoard(0x04_04_04_04_04_04_04_04),
+    BitBoard(0x02_02_02_02_02_02_02_02),
+    BitBoard(0x01_01_01_01_01_01_01_01),
+];
 
-    material
+fn adjacent_files(file: u32) -> BitBoard {
+    let left = if file > 0 { FILE_MASKS[file as usize - 1] } else { BitBoard::EMPTY };
+    let right = if file < 7 { FILE_MASKS[file as usize + 1] } else { BitBoard::EMPTY };
+    left | right
 }
 
-fn count_piece(bb: BitBoard, value: i64) -> i64 {
-    (bb.count() as i64) * value
+// All squares on ranks in front of the given square, from the point of view of the given colour.
+fn ranks_ahead(coord: BitCoord, colour: Colour) -> BitBoard {
+    let rank = coord.rank();
+    match colour {
+        Colour::White if rank == 7 => BitBoard::EMPTY,
+        Colour::White => BitBoard(!((1u64 << ((rank + 1) * 8)) - 1)),
+        Colour::Black => BitBoard((1u64 << (rank * 8)) - 1),
+    }
+}
+
+// Midgame and endgame values, which differ in how much passed pawns are worth.
+fn pawn_structure(pawns: BitBoard, other_pawns: BitBoard, colour: Colour) -> (i64, i64) {
+    let mut score = 0;
+    let mut passed_mg = 0;
+    let mut passed_eg = 0;
+
+    for file in FILE_MASKS.iter() {
+        let count = (pawns & *file).count() as i64;
+        if count > 1 {
+            score -= (count - 1) * DOUBLED_PAWN_PENALTY;
+        }
+    }
+
+    for c in pawns.iter() {
+        let file = c.file();
+        if (pawns & adjacent_files(file)).is_empty() {
+            score -= ISOLATED_PAWN_PENALTY;
+        }
+
+        let blockers = (FILE_MASKS[file as usize] | adjacent_files(file)) & ranks_ahead(c, colour);
+        if (other_pawns & blockers).is_empty() {
+            let advanced = match colour {
+                Colour::White => c.rank(),
+                Colour::Black => 7 - c.rank(),
+            };
+            passed_mg += PASSED_PAWN_BONUS[advanced as usize];
+            passed_eg += PASSED_PAWN_ENDGAME_BONUS[advanced as usize];
+        }
+    }
+
+    (score + passed_mg, score + passed_eg)
+}
+
+#[cfg(test)]
+mod tests {
+    use chess_lib::fen::load_fen;
+    use chess_lib::magic::MagicBitBoards;
+    use chess_lib::types::GameState;
+
+    use crate::chess::Chess;
+    use crate::eval::*;
+
+    #[test]
+    fn central_pawn_bonus() {
+        let mbb = MagicBitBoards::default();
+        let e4 = Chess::new(load_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"), &mbb);
+        let a4 = Chess::new(load_fen("rnbqkbnr/pppppppp/8/8/P7/8/1PPPPPPP/RNBQKBNR b KQkq a3 0 1"), &mbb);
+
+        // Black to move, so white's evaluation is the negation.
+        assert!(-evaluate(&e4) > -evaluate(&a4));
+    }
+
+    #[test]
+    fn active_rook_bonus() {
+        let mbb = MagicBitBoards::default();
+        let boxed_in = Chess::new(load_fen("4k3/8/8/8/8/8/6PP/6KR w - - 0 1"), &mbb);
+        let active = Chess::new(load_fen("4k3/8/8/8/3R4/8/6PP/6K1 w - - 0 1"), &mbb);
+
+        assert!(evaluate(&active) > evaluate(&boxed_in));
+    }
+
+    #[test]
+    fn doubled_pawns_penalty() {
+        let mbb = MagicBitBoards::default();
+        let sound = Chess::new(load_fen("4k3/8/8/8/3PP3/8/8/4K3 w - - 0 1"), &mbb);
+        let doubled = Chess::new(load_fen("4k3/8/8/3P4/3P4/8/8/4K3 w - - 0 1"), &mbb);
+
+        assert!(evaluate(&doubled) < evaluate(&sound));
+    }
+
+    #[test]
+    fn passed_pawn_bonus() {
+        let mbb = MagicBitBoards::default();
+        let blocked = Chess::new(load_fen("4k3/5p2/8/4P3/8/8/8/4K3 w - - 0 1"), &mbb);
+        let passed = Chess::new(load_fen("4k3/1p6/8/4P3/8/8/8/4K3 w - - 0 1"), &mbb);
+
+        assert!(evaluate(&passed) > evaluate(&blocked));
+    }
+
+    #[test]
+    fn king_centralises_in_endgame() {
+        let mbb = MagicBitBoards::default();
+
+        let castled = Chess::new(load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQ1BKR w - - 0 1"), &mbb);
+        let central = Chess::new(load_fen("rnbqkbnr/pppppppp/8/8/4K3/8/PPPPPPPP/RNBQ1B1R w - - 0 1"), &mbb);
+        assert!(evaluate(&castled) > evaluate(&central));
+
+        let castled = Chess::new(load_fen("4k3/pppppppp/8/8/8/8/PPPPPPPP/6K1 w - - 0 1"), &mbb);
+        let central = Chess::new(load_fen("4k3/pppppppp/8/8/4K3/8/PPPPPPPP/8 w - - 0 1"), &mbb);
+        assert!(evaluate(&central) > evaluate(&castled));
+    }
+
+    #[test]
+    fn exposed_king_penalty() {
+        let mbb = MagicBitBoards::default();
+
+        // The g3 pawn blocks both the queen's diagonal and the rook's file.
+        let sheltered = load_fen("kq4r1/pp6/2p5/8/8/6P1/PPP2P1P/3R2K1 w - - 0 1");
+        let exposed = load_fen("kq4r1/pp6/2p5/8/8/P7/1PP2P1P/3R2K1 w - - 0 1");
+
+        let danger = |state: &GameState| {
+            let occupancy = state.white.pieces.all() | state.black.pieces.all();
+            let attacks = AttackInfo::new(&state.black.pieces, Colour::Black, occupancy, &mbb);
+            king_danger(&state.white, &state.black.pieces, &attacks, &mbb)
+        };
+        assert_eq!(danger(&sheltered), 0);
+        assert_eq!(danger(&exposed), (QUEEN_KING_ATTACK + ROOK_KING_ATTACK) / 2);
+
+        assert!(evaluate(&Chess::new(exposed, &mbb)) < evaluate(&Chess::new(sheltered, &mbb)));
+    }
+
+    #[test]
+    fn attack_info() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let occupancy = state.white.pieces.all() | state.black.pieces.all();
+
+        for (pieces, colour) in [(&state.white.pieces, Colour::White), (&state.black.pieces, Colour::Black)].iter() {
+            let attacks = AttackInfo::new(pieces, *colour, occupancy, &mbb);
+            assert_eq!(attacks.pawns.count(), 8);
+            assert_eq!(attacks.knights.count(), 6);
+            assert_eq!(attacks.bishops.count(), 4);
+            assert_eq!(attacks.rooks.count(), 4);
+            assert_eq!(attacks.queens.count(), 5);
+            assert_eq!(attacks.king.count(), 5);
+            // Everything on the side's first three ranks, except the corners.
+            assert_eq!(attacks.all.count(), 22);
+        }
+    }
+
+    #[test]
+    fn reference_evaluations() {
+        let mbb = MagicBitBoards::default();
+        // Recorded before the attack sets were shared between terms, which shouldn't change them.
+        let cases = [
+            ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0),
+            ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", 115),
+            ("kq4r1/pp6/2p5/8/8/P7/1PP2P1P/3R2K1 w - - 0 1", -673),
+            ("r1bq1rk1/2pnbppp/p2p1n2/1p2p3/3PP3/1BP2N1P/PP3PP1/RNBQR1K1 b - - 1 10", -11),
+        ];
+
+        for (fen, score) in cases.iter() {
+            assert_eq!(evaluate(&Chess::new(load_fen(fen), &mbb)), *score, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn phase() {
+        let full = load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert_eq!(game_phase(&full.white.pieces) + game_phase(&full.black.pieces), 24);
+
+        let bare = load_fen("4k3/pppppppp/8/8/8/8/PPPPPPPP/4K3 w - - 0 1");
+        assert_eq!(game_phase(&bare.white.pieces) + game_phase(&bare.black.pieces), 0);
+    }
+
+    #[test]
+    fn symmetric_position_is_level() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bqkb1r/pppp1ppp/2n2n2/4p3/4P3/2N2N2/PPPP1PPP/R1BQKB1R w KQkq - 4 4"), &mbb);
+        assert_eq!(evaluate(&chess), 0);
+    }
 }
@@ -1,7 +1,12 @@
+pub mod bench;
 pub mod chess;
 pub mod eval;
 pub mod minimax;
 pub mod montecarlo;
+pub mod see;
+pub mod time;
+pub mod uci;
+pub mod xboard;
 
 
 #[cfg(test)]
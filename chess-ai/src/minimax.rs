@@ -1,4 +1,7 @@
 use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use chess_lib::types::ZobristHash;
 use chess_lib::tt;
 
@@ -7,23 +10,171 @@ pub trait Game : Clone {
 
     fn make_move(&mut self, mv: Self::Move);
     fn legal_moves(&self) -> Vec<Self::Move>;
+    // Only the moves which capture material, used by the quiescence search.
+    fn legal_captures(&self) -> Vec<Self::Move>;
+    fn is_in_check(&self) -> bool;
+    fn is_capture(&self, mv: Self::Move) -> bool;
+    // Ordering score for captures and promotions (most valuable victim, least valuable attacker).
+    // None for quiet moves.
+    fn mvv_lva(&self, mv: Self::Move) -> Option<i64>;
+    // Material won or lost by a capture once all the recaptures on its square are played out.
+    fn static_exchange_eval(&self, mv: Self::Move) -> i64;
+    // Slot in the history table for a quiet move, e.g. by side to move, source and target.
+    fn history_index(&self, mv: Self::Move) -> usize;
     fn zobrist_hash(&self) -> ZobristHash;
+    // Drawn by rule (repetition, insufficient material, fifty moves), even if play could go on.
+    fn is_draw(&self) -> bool;
+}
+
+// Score for being checkmated at the root.
+// Mates further away score less, so we prefer faster mates and slower losses.
+pub const MATE: i64 = 1_000_000;
+// Any score this close to MATE must be a mate score.
+const MATE_THRESHOLD: i64 = MATE - 1000;
+
+// Score for a drawn position before contempt is applied.
+pub const DRAW: i64 = 0;
+
+pub fn is_mate_score(score: i64) -> bool {
+    score.abs() >= MATE_THRESHOLD
+}
+
+// Mate scores are relative to the root, but the TT is shared between positions at different plies,
+// so store them relative to the position itself.
+fn score_to_tt(score: i64, ply: u32) -> i64 {
+    if score >= MATE_THRESHOLD {
+        score + ply as i64
+    } else if score <= -MATE_THRESHOLD {
+        score - ply as i64
+    } else {
+        score
+    }
+}
+
+fn score_from_tt(score: i64, ply: u32) -> i64 {
+    if score >= MATE_THRESHOLD {
+        score - ply as i64
+    } else if score <= -MATE_THRESHOLD {
+        score + ply as i64
+    } else {
+        score
+    }
 }
 
 type Evaluator<T> = fn (&T) -> i64;
 
+// Progress report, sent at the end of each completed iterative deepening depth.
+#[derive(Clone, Debug)]
+pub struct SearchInfo<M> {
+    pub depth: u32,
+    // Since the start of the search, not just this depth.
+    pub nodes: u64,
+    pub time: Duration,
+    pub nps: u64,
+    pub score: i64,
+    pub pv: Vec<M>,
+}
+
+// Aspiration windows start this wide either side of the previous score, and grow 4x on each
+// failure. Beyond the max, that side of the window is opened up completely.
+const ASPIRATION_WINDOW: i64 = 50;
+const ASPIRATION_MAX_WINDOW: i64 = 1000;
+// Scores from the shallowest iterations are too unstable to be worth guessing around.
+const ASPIRATION_MIN_DEPTH: u32 = 3;
+
+// Late move reductions: quiet moves ordered after the first few are searched less deeply, unless
+// they turn out to beat alpha.
+const LMR_MIN_DEPTH: u32 = 3;
+const LMR_FULL_DEPTH_MOVES: usize = 3;
+
+// Grows with both depth and move number, but always leaves at least one ply to search.
+fn lmr_reduction(depth: u32, move_index: usize) -> u32 {
+    let r = ((depth as f64).ln() * (move_index as f64).ln() / 2.0) as u32;
+    r.clamp(1, depth - 2)
+}
+
 pub struct AlphaBeta<G : Game> {
     eval: Evaluator<G>,
-    tt: tt::TranspositionTable<CacheData<G::Move>>,
+    tt: Table<G::Move>,
+    // When set, the search is abandoned between root moves once this time has passed.
+    deadline: Option<Instant>,
+    // When set, the search is abandoned as soon as another thread raises the flag.
+    stop: Option<Arc<AtomicBool>>,
+    aborted: bool,
+    nodes: u64,
+    // Two quiet moves per ply which recently caused a beta cutoff.
+    killers: Vec<[Option<G::Move>; 2]>,
+    use_killers: bool,
+    // How often each quiet move has caused a beta cutoff, weighted by depth squared, indexed by
+    // Game::history_index.
+    history: Vec<u64>,
+    use_history: bool,
+    use_aspiration: bool,
+    use_lmr: bool,
+    use_check_extensions: bool,
+    // Centipawns the side to move at the root gives up by accepting a draw. Positive values make
+    // the engine play on in level positions, negative values make it happy to draw.
+    pub contempt: i64,
+    pub on_info: Option<Box<dyn FnMut(SearchInfo<G::Move>)>>,
+    started: Instant,
+}
+
+// Either owned by a single search, or shared between several searching threads.
+enum Table<M : Copy> {
+    Local(tt::TranspositionTable<CacheData<M>>),
+    Shared(Arc<tt::SharedTranspositionTable<CacheData<M>>>),
+}
+
+impl <M : Copy> Table<M> {
+    fn get(&mut self, zh: ZobristHash) -> Option<CacheData<M>> {
+        match self {
+            Table::Local(tt) => tt.get(zh),
+            Table::Shared(tt) => tt.get(zh),
+        }
+    }
+
+    fn peek(&self, zh: ZobristHash) -> Option<CacheData<M>> {
+        match self {
+            Table::Local(tt) => tt.peek(zh),
+            Table::Shared(tt) => tt.peek(zh),
+        }
+    }
+
+    fn insert(&mut self, zh: ZobristHash, data: CacheData<M>) {
+        match self {
+            Table::Local(tt) => tt.insert(zh, data),
+            Table::Shared(tt) => tt.insert(zh, data),
+        }
+    }
+
+    fn new_search(&mut self) {
+        match self {
+            Table::Local(tt) => tt.new_search(),
+            // Shared tables are aged once by their owner, not by every thread.
+            Table::Shared(_) => (),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
 struct CacheData<M : Copy> {
     depth: u32,
     score: i64,
+    bound: Bound,
     best_move: Option<M>,
 }
 
+// What the stored score tells us about the true score of the position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bound {
+    Exact,
+    // Failed high, the true score is at least this.
+    Lower,
+    // Failed low, the true score is at most this.
+    Upper,
+}
+
+#[cfg(test)]
 fn prefer_higher<M : Copy>(prev: CacheData<M>, new: CacheData<M>) -> tt::PolicyResult {
     if prev.depth < new.depth {
         tt::PolicyResult::Replace
@@ -36,79 +187,1034 @@ impl <G: Game> AlphaBeta<G> {
     pub fn new(eval: Evaluator<G>, tt_size: usize) -> AlphaBeta<G> {
         AlphaBeta{
             eval,
-            tt: tt::TranspositionTable::new(tt_size, prefer_higher),
+            tt: Table::Local(tt::TranspositionTable::two_bucket(tt_size, |data| data.depth)),
+            deadline: None,
+            stop: None,
+            aborted: false,
+            nodes: 0,
+            killers: vec![],
+            use_killers: true,
+            history: vec![],
+            use_history: true,
+            use_aspiration: true,
+            use_lmr: true,
+            use_check_extensions: true,
+            contempt: 0,
+            on_info: None,
+            started: Instant::now(),
         }
     }
 
     pub fn tt_stats(&self) -> &tt::TTStats {
-        self.tt.stats()
+        match &self.tt {
+            Table::Local(tt) => tt.stats(),
+            Table::Shared(_) => panic!("Stats are not tracked for shared tables"),
+        }
+    }
+
+    // Number of nodes visited by the last search.
+    pub fn nodes(&self) -> u64 {
+        self.nodes
     }
 
-    pub fn evaluate(&mut self, game: &G, depth: u32) -> (G::Move, i64) {
+    // Reallocates the TT, which forgets everything learned by previous searches.
+    pub fn set_hash_mb(&mut self, mb: usize) {
+        match &mut self.tt {
+            Table::Local(tt) => tt.resize(mb << 20),
+            Table::Shared(_) => panic!("Shared tables are sized by their owner"),
+        }
+    }
+
+    // The best move and its score. There's no move if the game is already over by checkmate or
+    // stalemate, and the score is then final.
+    // Depth 0 is searched as depth 1, since quiescence alone doesn't pick a move.
+    pub fn evaluate(&mut self, game: &G, depth: u32) -> (Option<G::Move>, i64) {
+        self.new_search();
+        if let Some(score) = self.terminal_score(game) {
+            return (None, score);
+        }
+
+        let depth = depth.max(1);
+        let mut score = None;
         for d in 0..=depth {
-            self.eval_recursive(&game, d, i64::MIN + 1, i64::MAX - 1);
+            score = Some(self.search_root(game, d, score));
+            if d > 0 {
+                self.report(game, d);
+            }
+        }
+
+        let (mv, score) = self.root_result(game);
+        (Some(mv), score)
+    }
+
+    // Every root move and its score, best first. Unlike evaluate, each move gets a full window
+    // search, so the scores of the other moves are exact rather than just bounds.
+    // Ties are broken in favour of evaluate's choice, then by legal move order.
+    pub fn evaluate_ranked(&mut self, game: &G, depth: u32) -> Vec<(G::Move, i64)> {
+        self.rank_root_moves(game, depth)
+            .into_iter()
+            .map(|(mv, score, _)| (mv, score))
+            .collect()
+    }
+
+    // The best `k` moves, each with its score and a principal variation starting with that move.
+    pub fn multi_pv(&mut self, game: &G, depth: u32, k: usize) -> Vec<(G::Move, i64, Vec<G::Move>)> {
+        let mut lines = self.rank_root_moves(game, depth);
+        lines.truncate(k);
+        lines
+    }
+
+    fn rank_root_moves(&mut self, game: &G, depth: u32) -> Vec<(G::Move, i64, Vec<G::Move>)> {
+        // Also fills the TT, so the searches below are well ordered.
+        let best_move = match self.evaluate(game, depth) {
+            (Some(mv), _) => mv,
+            (None, _) => return vec![],
+        };
+
+        let mut lines: Vec<(G::Move, i64, Vec<G::Move>)> = game.legal_moves()
+            .into_iter()
+            .map(|mv| {
+                let mut new_state = game.clone();
+                new_state.make_move(mv);
+                let score = -self.eval_recursive(&new_state, depth.saturating_sub(1), 1, i64::MIN + 1, i64::MAX - 1);
+
+                // Read the PV straight away, before other searches overwrite it.
+                let mut pv = vec![mv];
+                pv.extend(self.principal_variation(&new_state, depth.saturating_sub(1) as usize));
+                (mv, score, pv)
+            })
+            .collect();
+
+        lines.sort_by_key(|(mv, score, _)| (-score, *mv != best_move));
+        lines
+    }
+
+    // Iterative deepening until the time budget runs out.
+    // Returns the result of the deepest fully completed search, and that depth.
+    pub fn evaluate_timed(&mut self, game: &G, max_time: Duration) -> (Option<G::Move>, i64, u32) {
+        let deadline = Instant::now() + max_time;
+        self.new_search();
+        if let Some(score) = self.terminal_score(game) {
+            return (None, score, 0);
+        }
+
+        // Always complete depth 1 so we have a move to return.
+        self.eval_recursive(&game, 1, 0, i64::MIN + 1, i64::MAX - 1);
+        let (mut best_move, mut score) = self.root_result(game);
+        let mut depth = 1;
+        self.report(game, depth);
+
+        self.deadline = Some(deadline);
+        while Instant::now() < deadline {
+            self.search_root(game, depth + 1, Some(score));
+            if self.aborted {
+                break;
+            }
+
+            let (mv, s) = self.root_result(game);
+            best_move = mv;
+            score = s;
+            depth += 1;
+            self.report(game, depth);
+        }
+
+        self.deadline = None;
+        self.aborted = false;
+
+        (Some(best_move), score, depth)
+    }
+
+    // Follows the best moves stored in the TT from the given position.
+    pub fn principal_variation(&self, game: &G, max_len: usize) -> Vec<G::Move> {
+        let mut pv: Vec<G::Move> = vec![];
+        let mut seen: Vec<ZobristHash> = vec![];
+        let mut game = game.clone();
+
+        while pv.len() < max_len {
+            let zh = game.zobrist_hash();
+            if seen.contains(&zh) {
+                break;
+            }
+            seen.push(zh);
+
+            let mv = match self.tt.peek(zh).and_then(|data| data.best_move) {
+                Some(mv) => mv,
+                None => break,
+            };
+
+            // Guard against hash collisions giving us nonsense.
+            if !game.legal_moves().contains(&mv) {
+                break;
+            }
+
+            game.make_move(mv);
+            pv.push(mv);
+        }
+
+        pv
+    }
+
+    fn report(&mut self, game: &G, depth: u32) {
+        if self.on_info.is_none() {
+            return;
+        }
+
+        let time = self.started.elapsed();
+        let info = SearchInfo{
+            depth,
+            nodes: self.nodes,
+            time,
+            nps: (self.nodes as f64 / time.as_secs_f64().max(1e-6)) as u64,
+            score: self.root_result(game).1,
+            pv: self.principal_variation(game, depth as usize),
+        };
+
+        if let Some(on_info) = self.on_info.as_mut() {
+            on_info(info);
         }
+    }
 
-        // Resconstruct the results from the TT.
+    fn new_search(&mut self) {
+        self.started = Instant::now();
+        self.nodes = 0;
+        self.killers.clear();
+        self.history.clear();
+        self.tt.new_search();
+    }
+
+    // Checkmate or stalemate, scored as eval_recursive would.
+    fn terminal_score(&self, game: &G) -> Option<i64> {
+        if !game.legal_moves().is_empty() {
+            None
+        } else if game.is_in_check() {
+            Some(-MATE)
+        } else {
+            Some(self.draw_score(0))
+        }
+    }
+
+    // Resconstruct the results from the TT.
+    fn root_result(&mut self, game: &G) -> (G::Move, i64) {
         let root_data = self.tt.get(game.zobrist_hash()).expect("Root node not present in TT after evaluation");
         (root_data.best_move.unwrap(), root_data.score)
     }
 
+    // Searches with a narrow window around the previous iteration's score, since it usually
+    // doesn't move much. If the true score falls outside the window, widen it on that side and
+    // search again.
+    fn search_root(&mut self, game: &G, depth: u32, prev_score: Option<i64>) -> i64 {
+        let prev_score = match prev_score {
+            Some(score) if self.use_aspiration && depth >= ASPIRATION_MIN_DEPTH && !is_mate_score(score) => score,
+            _ => return self.eval_recursive(game, depth, 0, i64::MIN + 1, i64::MAX - 1),
+        };
+
+        let window = |delta: i64, sign: i64| {
+            if delta > ASPIRATION_MAX_WINDOW {
+                sign * (i64::MAX - 1)
+            } else {
+                prev_score + sign * delta
+            }
+        };
+
+        let mut alpha_delta = ASPIRATION_WINDOW;
+        let mut beta_delta = ASPIRATION_WINDOW;
+        loop {
+            let alpha = window(alpha_delta, -1);
+            let beta = window(beta_delta, 1);
+            let score = self.eval_recursive(game, depth, 0, alpha, beta);
+
+            if self.aborted {
+                return score;
+            } else if score <= alpha && alpha > i64::MIN + 1 {
+                alpha_delta *= 4;
+            } else if score >= beta && beta < i64::MAX - 1 {
+                beta_delta *= 4;
+            } else {
+                return score;
+            }
+        }
+    }
+
     fn eval_recursive(
         &mut self,
         game: &G,
         depth: u32,
+        ply: u32,
         mut alpha: i64,
         beta: i64,
     ) -> i64 {
+        self.nodes += 1;
+
+        if self.stop.as_ref().map(|stop| stop.load(Ordering::Relaxed)).unwrap_or(false) {
+            self.aborted = true;
+            return alpha;
+        }
+
+        // The root still has to pick a move, even if the position is already drawn.
+        if ply > 0 && game.is_draw() {
+            return self.draw_score(ply);
+        }
+
+        // Search checks one ply deeper, so forcing lines aren't cut off half way. Extensions can't
+        // run away, since the checking side's moves still use up depth.
+        let in_check = game.is_in_check();
+        let depth = if self.use_check_extensions && ply > 0 && in_check {
+            depth + 1
+        } else {
+            depth
+        };
+
         if depth == 0 {
-            (self.eval)(game)
+            self.quiesce(game, alpha, beta)
         } else {
             let zh = game.zobrist_hash();
 
             let cached_data = self.tt.get(zh);
             let cached_score = cached_data.and_then(|data| {
-                if data.depth >= depth {
-                    Some(data.score)
-                } else {
-                    None
+                if data.depth < depth {
+                    return None;
+                }
+
+                let score = score_from_tt(data.score, ply);
+                match data.bound {
+                    Bound::Exact => Some(score),
+                    Bound::Lower if score >= beta => Some(beta),
+                    Bound::Upper if score <= alpha => Some(alpha),
+                    _ => None,
                 }
             });
 
-            if cached_score.is_some() {
-                return cached_score.unwrap();
+            if let Some(score) = cached_score {
+                return score;
             }
 
             let cached_best_move = cached_data.and_then(|data| data.best_move);
             let mut best_move: Option<G::Move> = None;
             let mut s = alpha;
+            // Unless some move raises alpha, we only know the score is at most alpha.
+            let mut bound = Bound::Upper;
             let moves = game.legal_moves();
 
-            let best_move_first = cached_best_move.into_iter()
-                .chain(moves.into_iter().filter(|m| cached_best_move != Some(*m)));
+            if moves.is_empty() {
+                if !in_check {
+                    // Not cached, since the draw score depends on contempt and whose turn it was
+                    // at the root.
+                    return self.draw_score(ply);
+                }
+
+                // Mate can't change with more depth.
+                let score = -MATE + ply as i64;
+                self.tt.insert(zh, CacheData{depth: u32::MAX, score: score_to_tt(score, ply), bound: Bound::Exact, best_move: None});
+                return score;
+            }
+
+            // Every root move gets a full depth search. Reducing is unsafe when in check too,
+            // since every evasion matters.
+            let can_reduce = self.use_lmr && ply > 0 && depth >= LMR_MIN_DEPTH && !in_check;
+
+            for (ix, m) in self.order_moves(game, moves, cached_best_move, ply).into_iter().enumerate() {
+                if ply == 0 && self.deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                    // Don't store anything for an incomplete root search.
+                    self.aborted = true;
+                    return s;
+                }
 
-            for m in best_move_first {
                 let mut new_state = game.clone();
                 new_state.make_move(m);
-                
-                let eval = -self.eval_recursive(&new_state, depth - 1, -beta, -alpha);
+
+                let reduce = can_reduce && ix >= LMR_FULL_DEPTH_MOVES && game.mvv_lva(m).is_none();
+                let mut eval = alpha + 1;
+                if reduce {
+                    // A null window is enough to tell whether the move beats alpha.
+                    let reduced_depth = depth - 1 - lmr_reduction(depth, ix);
+                    eval = -self.eval_recursive(&new_state, reduced_depth, ply + 1, -alpha - 1, -alpha);
+                }
+                if eval > alpha {
+                    eval = -self.eval_recursive(&new_state, depth - 1, ply + 1, -beta, -alpha);
+                }
+
+                // The subtree result is meaningless, so don't store anything.
+                if self.aborted {
+                    return s;
+                }
 
                 if eval >= beta {
                     s = beta;
+                    bound = Bound::Lower;
                     best_move = Some(m);
+                    if !game.is_capture(m) {
+                        self.store_killer(ply, m);
+                        self.store_history(game, m, depth);
+                    }
                     break;
                 }
 
                 if eval > alpha {
                     alpha = eval;
                     s = eval;
+                    bound = Bound::Exact;
                     best_move = Some(m);
                 }
             }
 
-            self.tt.insert(zh, CacheData{depth, score: s, best_move: best_move });
+            self.tt.insert(zh, CacheData{depth, score: score_to_tt(s, ply), bound, best_move: best_move });
             s
         }
     }
+
+    // Even plies have the root's side to move.
+    fn draw_score(&self, ply: u32) -> i64 {
+        if ply.is_multiple_of(2) {
+            DRAW - self.contempt
+        } else {
+            DRAW + self.contempt
+        }
+    }
+
+    // Search the TT move first, then captures by MVV-LVA, then killers, then other quiet moves by
+    // their history score.
+    fn order_moves(&self, game: &G, mut moves: Vec<G::Move>, tt_move: Option<G::Move>, ply: u32) -> Vec<G::Move> {
+        let killers = self.killers_at(ply);
+        moves.sort_by_cached_key(|m| {
+            if tt_move == Some(*m) {
+                (0, 0)
+            } else if let Some(score) = game.mvv_lva(*m) {
+                (1, -score)
+            } else if killers[0] == Some(*m) {
+                (2, 0)
+            } else if killers[1] == Some(*m) {
+                (3, 0)
+            } else {
+                (4, -(self.history_score(game, *m) as i64))
+            }
+        });
+        moves
+    }
+
+    fn killers_at(&self, ply: u32) -> [Option<G::Move>; 2] {
+        if !self.use_killers {
+            return [None, None];
+        }
+
+        self.killers.get(ply as usize).copied().unwrap_or([None, None])
+    }
+
+    fn store_killer(&mut self, ply: u32, mv: G::Move) {
+        let ply = ply as usize;
+        if self.killers.len() <= ply {
+            self.killers.resize(ply + 1, [None, None]);
+        }
+
+        let killers = &mut self.killers[ply];
+        if killers[0] != Some(mv) {
+            killers[1] = killers[0];
+            killers[0] = Some(mv);
+        }
+    }
+
+    fn history_score(&self, game: &G, mv: G::Move) -> u64 {
+        if !self.use_history {
+            return 0;
+        }
+
+        self.history.get(game.history_index(mv)).copied().unwrap_or(0)
+    }
+
+    fn store_history(&mut self, game: &G, mv: G::Move, depth: u32) {
+        let ix = game.history_index(mv);
+        if self.history.len() <= ix {
+            self.history.resize(ix + 1, 0);
+        }
+
+        self.history[ix] += (depth * depth) as u64;
+    }
+
+    // Keep searching captures until the position is quiet, so we don't stop in the middle of an
+    // exchange.
+    fn quiesce(&mut self, game: &G, mut alpha: i64, beta: i64) -> i64 {
+        self.nodes += 1;
+
+        // Assume we can always choose not to capture.
+        let stand_pat = (self.eval)(game);
+        if stand_pat >= beta {
+            return beta;
+        }
+
+        if stand_pat > alpha {
+            alpha = stand_pat;
+        }
+
+        let mut captures = game.legal_captures();
+        captures.sort_by_cached_key(|m| -game.mvv_lva(*m).unwrap_or(0));
+
+        for m in captures {
+            // Captures which lose material can't raise alpha above the stand pat score.
+            if game.static_exchange_eval(m) < 0 {
+                continue;
+            }
+
+            let mut new_state = game.clone();
+            new_state.make_move(m);
+
+            let eval = -self.quiesce(&new_state, -beta, -alpha);
+
+            if eval >= beta {
+                return beta;
+            }
+
+            if eval > alpha {
+                alpha = eval;
+            }
+        }
+
+        alpha
+    }
+}
+
+
+// Lazy SMP: several threads search the same root, sharing only the transposition table. Helper
+// threads alternate searching one ply deeper than the main thread, which diversifies the work and
+// fills the table with results the main thread can reuse.
+pub struct AlphaBetaParallel<G : Game> {
+    eval: Evaluator<G>,
+    tt: Arc<tt::SharedTranspositionTable<CacheData<G::Move>>>,
+    threads: usize,
+    nodes: u64,
+    pub contempt: i64,
+}
+
+impl <G: Game + Send> AlphaBetaParallel<G> where G::Move: Send {
+    pub fn new(eval: Evaluator<G>, tt_size: usize, threads: usize) -> AlphaBetaParallel<G> {
+        AlphaBetaParallel{
+            eval,
+            tt: Arc::new(tt::SharedTranspositionTable::two_bucket(tt_size, 64, |data| data.depth)),
+            threads: threads.max(1),
+            nodes: 0,
+            contempt: 0,
+        }
+    }
+
+    // Total nodes visited across all threads during the last search.
+    pub fn nodes(&self) -> u64 {
+        self.nodes
+    }
+
+    pub fn evaluate(&mut self, game: &G, depth: u32) -> (Option<G::Move>, i64) {
+        self.tt.new_search();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let this = &*self;
+        let (result, nodes) = std::thread::scope(|scope| {
+            let helpers: Vec<_> = (1..this.threads).map(|id| {
+                let stop = stop.clone();
+                let game = game.clone();
+                // Callbacks aren't Send, so each helper has to be built on its own thread.
+                scope.spawn(move || {
+                    let mut helper = this.worker(Some(stop));
+                    helper.new_search();
+                    for d in 1..=depth + (id as u32 % 2) {
+                        helper.eval_recursive(&game, d, 0, i64::MIN + 1, i64::MAX - 1);
+                        if helper.aborted {
+                            break;
+                        }
+                    }
+                    helper.nodes
+                })
+            }).collect();
+
+            let mut main = this.worker(None);
+            let result = main.evaluate(game, depth);
+
+            stop.store(true, Ordering::Relaxed);
+            let helper_nodes: u64 = helpers.into_iter().map(|h| h.join().unwrap()).sum();
+            (result, main.nodes + helper_nodes)
+        });
+
+        self.nodes = nodes;
+        result
+    }
+
+    fn worker(&self, stop: Option<Arc<AtomicBool>>) -> AlphaBeta<G> {
+        AlphaBeta{
+            eval: self.eval,
+            tt: Table::Shared(self.tt.clone()),
+            deadline: None,
+            stop,
+            aborted: false,
+            nodes: 0,
+            killers: vec![],
+            use_killers: true,
+            history: vec![],
+            use_history: true,
+            use_aspiration: true,
+            use_lmr: true,
+            use_check_extensions: true,
+            contempt: self.contempt,
+            on_info: None,
+            started: Instant::now(),
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use chess_lib::fen::{load_fen, STARTING_POSITION};
+    use chess_lib::fmt::{parse_coord, parse_uci_move};
+    use chess_lib::magic::MagicBitBoards;
+    use chess_lib::moves::legal_moves;
+    use chess_lib::types::{Colour, Move, Piece};
+
+    use crate::chess::Chess;
+    use chess_lib::material::material as side_material;
+
+    use crate::eval::evaluate;
+    use crate::minimax::*;
+
+    // Keeps the expected scores below independent of the positional terms in the real evaluator.
+    fn material(chess: &Chess) -> i64 {
+        match chess.state.active_colour {
+            Colour::White => side_material(&chess.state.white.pieces) - side_material(&chess.state.black.pieces),
+            Colour::Black => side_material(&chess.state.black.pieces) - side_material(&chess.state.white.pieces),
+        }
+    }
+
+    #[test]
+    fn quiescence_sees_recapture() {
+        let mbb = MagicBitBoards::default();
+
+        // Qxe5+ wins a pawn, but loses the queen to dxe5.
+        let chess = Chess::new(load_fen("4k3/8/3p4/4p3/8/8/4Q3/4K3 w - - 0 1"), &mbb);
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+
+        let (mv, score) = alphabeta.evaluate(&chess, 1);
+
+        assert_ne!(mv, Some(Move::Normal(Piece::Queen, parse_coord("e2"), parse_coord("e5"))));
+        assert_eq!(score, 700);
+    }
+
+    #[test]
+    fn repetition_scores_as_draw() {
+        let mbb = MagicBitBoards::default();
+
+        // White is a queen up, but Black can repeat the position for the third time with Ng8.
+        let mut chess = Chess::new(load_fen("4k1n1/8/8/8/8/8/8/3QK1N1 w - - 0 1"), &mbb);
+        for mv in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1"].iter() {
+            let mv = parse_uci_move(&chess.state, mv, &mbb).unwrap();
+            chess.make_move(mv);
+        }
+
+        let ng8 = parse_uci_move(&chess.state, "f6g8", &mbb).unwrap();
+        let mut repeated = chess.clone();
+        repeated.make_move(ng8);
+        assert!(repeated.is_draw());
+
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        assert_eq!(alphabeta.eval_recursive(&repeated, 2, 1, -1000, 1000), DRAW);
+
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        assert_eq!(alphabeta.evaluate(&chess, 2), (Some(ng8), DRAW));
+    }
+
+    #[test]
+    fn contempt_avoids_repetition() {
+        let mbb = MagicBitBoards::default();
+
+        // Black is a pawn down, and Ng8 repeats the position for the third time.
+        let mut chess = Chess::new(load_fen("4k1n1/8/8/8/8/8/P7/4K1N1 w - - 0 1"), &mbb);
+        for mv in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1"].iter() {
+            let mv = parse_uci_move(&chess.state, mv, &mbb).unwrap();
+            chess.make_move(mv);
+        }
+        let ng8 = parse_uci_move(&chess.state, "f6g8", &mbb).unwrap();
+
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        assert_eq!(alphabeta.evaluate(&chess, 1), (Some(ng8), DRAW));
+
+        // Once a draw is worse than being a pawn down, Black plays on.
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        alphabeta.contempt = 200;
+        let (mv, score) = alphabeta.evaluate(&chess, 1);
+        assert_ne!(mv, Some(ng8));
+        assert_eq!(score, -100);
+    }
+
+    #[test]
+    fn finished_games() {
+        let mbb = MagicBitBoards::default();
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+
+        // Fool's mate, and a king with nowhere to go.
+        let checkmated = Chess::new(load_fen("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3"), &mbb);
+        assert_eq!(alphabeta.evaluate(&checkmated, 3), (None, -MATE));
+        assert_eq!(alphabeta.evaluate_timed(&checkmated, Duration::from_millis(10)), (None, -MATE, 0));
+
+        let stalemated = Chess::new(load_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), &mbb);
+        assert_eq!(alphabeta.evaluate(&stalemated, 3), (None, DRAW));
+    }
+
+    #[test]
+    fn depth_zero_searches_one_ply() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+
+        let one = alphabeta.evaluate(&chess, 1);
+        assert!(one.0.is_some());
+        assert_eq!(alphabeta.evaluate(&chess, 0), one);
+    }
+
+    #[test]
+    fn terminal_children_are_cached() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("6k1/5ppp/8/8/8/8/8/3R2K1 w - - 0 1"), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+
+        let rd8 = parse_uci_move(&chess.state, "d1d8", &mbb).unwrap();
+        assert_eq!(alphabeta.evaluate(&chess, 2), (Some(rd8), MATE - 1));
+
+        let mut mated = chess.clone();
+        mated.make_move(rd8);
+        let cached = alphabeta.tt.peek(mated.zobrist_hash()).expect("Mated position not cached");
+        assert_eq!(cached.score, -MATE);
+        assert_eq!(cached.bound, Bound::Exact);
+        assert_eq!(cached.best_move, None);
+
+        // Stalemates aren't, since their score depends on contempt.
+        let chess = Chess::new(load_fen("7k/8/5K2/8/8/8/8/6Q1 w - - 0 1"), &mbb);
+        let qg6 = parse_uci_move(&chess.state, "g1g6", &mbb).unwrap();
+        alphabeta.evaluate(&chess, 2);
+        let mut stalemated = chess.clone();
+        stalemated.make_move(qg6);
+        assert!(stalemated.legal_moves().is_empty());
+        assert!(alphabeta.tt.peek(stalemated.zobrist_hash()).is_none());
+    }
+
+    #[test]
+    fn ranked_moves() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+
+        let (best, score) = alphabeta.evaluate(&chess, 3);
+        let ranked = alphabeta.evaluate_ranked(&chess, 3);
+        assert_eq!(ranked.len(), 20);
+        assert_eq!(Some(ranked[0].0), best);
+        assert_eq!(ranked[0].1, score);
+        assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        let lines = alphabeta.multi_pv(&chess, 3, 3);
+        assert_eq!(lines.len(), 3);
+        for (line, (mv, score)) in lines.iter().zip(ranked.iter()) {
+            assert_eq!((line.0, line.1), (*mv, *score));
+            assert_eq!(line.2[0], line.0);
+
+            let mut game = chess.clone();
+            for m in line.2.iter() {
+                assert!(legal_moves(&game.state, &mbb).contains(m));
+                game.make_move(*m);
+            }
+        }
+
+        // Nothing to rank once the game is over.
+        let stalemated = Chess::new(load_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), &mbb);
+        assert!(alphabeta.evaluate_ranked(&stalemated, 3).is_empty());
+    }
+
+    #[test]
+    fn resize_hash() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+        let (mv, score) = alphabeta.evaluate(&chess, 3);
+        let size = alphabeta.tt_stats().size();
+
+        alphabeta.set_hash_mb(1);
+        assert!(alphabeta.tt_stats().size() > size);
+        assert_eq!(alphabeta.evaluate(&chess, 3), (mv, score));
+        assert!(alphabeta.principal_variation(&chess, 3).len() > 1);
+    }
+
+    #[test]
+    fn principal_variation() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("6k1/5ppp/8/8/8/1n6/1P6/R5K1 w - - 0 1"), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+
+        let (mv, _) = alphabeta.evaluate(&chess, 3);
+        let pv = alphabeta.principal_variation(&chess, 10);
+
+        assert!(!pv.is_empty());
+        assert_eq!(Some(pv[0]), mv);
+
+        let mut game = chess.clone();
+        for m in pv {
+            assert!(legal_moves(&game.state, &mbb).contains(&m));
+            game.make_move(m);
+        }
+    }
+
+    #[test]
+    fn cached_bounds_respect_window() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("4k3/8/3p4/4p3/8/8/4Q3/4K3 w - - 0 1"), &mbb);
+        let zh = Game::zobrist_hash(&chess);
+
+        // An upper bound below the window tells us nothing, so we must search.
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        alphabeta.tt.insert(zh, CacheData{depth: 10, score: 0, bound: Bound::Upper, best_move: None});
+        assert_eq!(alphabeta.eval_recursive(&chess, 1, 0, -1000, 1000), 700);
+
+        // But an upper bound below alpha is a cutoff.
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        alphabeta.tt.insert(zh, CacheData{depth: 10, score: -2000, bound: Bound::Upper, best_move: None});
+        assert_eq!(alphabeta.eval_recursive(&chess, 1, 0, -1000, 1000), -1000);
+
+        // Likewise for lower bounds.
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        alphabeta.tt.insert(zh, CacheData{depth: 10, score: 2000, bound: Bound::Lower, best_move: None});
+        assert_eq!(alphabeta.eval_recursive(&chess, 1, 0, -1000, 1000), 1000);
+
+        let mut alphabeta = AlphaBeta::new(material, 1 << 16);
+        alphabeta.tt.insert(zh, CacheData{depth: 10, score: 0, bound: Bound::Lower, best_move: None});
+        assert_eq!(alphabeta.eval_recursive(&chess, 1, 0, -1000, 1000), 700);
+    }
+
+    #[test]
+    fn mate_in_two() {
+        let mbb = MagicBitBoards::default();
+        // 1. Kf7 Kh7 2. Rh1#
+        let chess = Chess::new(load_fen("7k/8/5K2/8/8/8/8/6R1 w - - 0 1"), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+
+        let (mv, score) = alphabeta.evaluate(&chess, 4);
+        assert_eq!(mv, Some(Move::Normal(Piece::King, parse_coord("f6"), parse_coord("f7"))));
+        assert_eq!(score, MATE - 3);
+        assert!(is_mate_score(score));
+
+        let pv = alphabeta.principal_variation(&chess, 10);
+        assert_eq!(pv.len(), 3);
+
+        let mut game = chess.clone();
+        for m in pv {
+            game.make_move(m);
+        }
+        assert!(game.is_in_check());
+        assert!(legal_moves(&game.state, &mbb).is_empty());
+    }
+
+    #[test]
+    fn killers_reduce_nodes() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4"), &mbb);
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.use_killers = false;
+        alphabeta.evaluate(&chess, 4);
+        let without_killers = alphabeta.nodes();
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.evaluate(&chess, 4);
+        let with_killers = alphabeta.nodes();
+
+        assert!(with_killers < without_killers);
+    }
+
+    #[test]
+    fn history_reduces_nodes() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP2BPPP/R2QKB1R w KQ - 0 8"), &mbb);
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.use_history = false;
+        alphabeta.evaluate(&chess, 5);
+        let without_history = alphabeta.nodes();
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.evaluate(&chess, 5);
+        let with_history = alphabeta.nodes();
+
+        assert!(with_history < without_history);
+    }
+
+    #[test]
+    fn check_extensions_find_mate() {
+        let mbb = MagicBitBoards::default();
+        // 1. Nh6+ Kh8 2. Qg8+ Rxg8 3. Nf7#, five plies deep.
+        let chess = Chess::new(load_fen("r5k1/5Npp/8/8/2Q5/8/8/6K1 w - - 0 1"), &mbb);
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.use_check_extensions = false;
+        let (_, score) = alphabeta.evaluate(&chess, 3);
+        assert!(!is_mate_score(score));
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        let (mv, score) = alphabeta.evaluate(&chess, 3);
+        assert_eq!(mv, Some(Move::Normal(Piece::Knight, parse_coord("f7"), parse_coord("h6"))));
+        assert_eq!(score, MATE - 5);
+    }
+
+    #[test]
+    fn late_move_reductions_reduce_nodes() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP2BPPP/R2QKB1R w KQ - 0 8"), &mbb);
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.use_lmr = false;
+        alphabeta.evaluate(&chess, 5);
+        let without_lmr = alphabeta.nodes();
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.evaluate(&chess, 5);
+        let with_lmr = alphabeta.nodes();
+
+        assert!(with_lmr < without_lmr, "{} vs {}", with_lmr, without_lmr);
+    }
+
+    #[test]
+    fn late_move_reductions_keep_tactics() {
+        let mbb = MagicBitBoards::default();
+        let positions = [
+            // Nc7+ forks king and rook.
+            "r3k3/8/8/1N6/8/8/8/4K3 w - - 0 1",
+            // Back rank mate with Rd8#.
+            "6k1/5ppp/8/8/8/8/8/3R2K1 w - - 0 1",
+            // Qxf7# (Scholar's mate).
+            "r1bqkb1r/pppp1ppp/2n2n2/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 4 4",
+        ];
+
+        for fen in positions.iter() {
+            let chess = Chess::new(load_fen(fen), &mbb);
+
+            let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+            alphabeta.use_lmr = false;
+            let without_lmr = alphabeta.evaluate(&chess, 4);
+
+            let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+            let with_lmr = alphabeta.evaluate(&chess, 4);
+
+            assert_eq!(with_lmr.0, without_lmr.0, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn aspiration_windows_reduce_nodes() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4"), &mbb);
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        alphabeta.use_aspiration = false;
+        let (full_mv, full_score) = alphabeta.evaluate(&chess, 5);
+        let full_window = alphabeta.nodes();
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        let (mv, score) = alphabeta.evaluate(&chess, 5);
+        let aspiration = alphabeta.nodes();
+
+        assert_eq!(mv, full_mv);
+        assert_eq!(score, full_score);
+        assert!(aspiration < full_window, "{} >= {}", aspiration, full_window);
+    }
+
+    #[test]
+    fn search_info() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let infos = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+        let sink = infos.clone();
+        alphabeta.on_info = Some(Box::new(move |info| sink.borrow_mut().push(info)));
+        let (mv, score) = alphabeta.evaluate(&chess, 4);
+
+        let infos = infos.borrow();
+        assert_eq!(infos.iter().map(|info| info.depth).collect::<Vec<u32>>(), vec![1, 2, 3, 4]);
+        assert!(infos.iter().all(|info| info.nodes > 0 && !info.pv.is_empty()));
+        assert!(infos.windows(2).all(|w| w[0].nodes < w[1].nodes));
+
+        let last = infos.last().unwrap();
+        assert_eq!(last.score, score);
+        assert_eq!(Some(last.pv[0]), mv);
+        assert_eq!(last.nodes, alphabeta.nodes());
+    }
+
+    #[test]
+    fn two_bucket_tt_reduces_nodes() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4"), &mbb);
+
+        // Use a table small enough that there are plenty of collisions.
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 18);
+        alphabeta.tt = Table::Local(tt::TranspositionTable::new(1 << 18, prefer_higher));
+        alphabeta.evaluate(&chess, 5);
+        let single_bucket = alphabeta.nodes();
+
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 18);
+        alphabeta.evaluate(&chess, 5);
+        let two_bucket = alphabeta.nodes();
+
+        assert!(two_bucket < single_bucket);
+    }
+
+    #[test]
+    fn parallel_mate_in_two() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen("7k/8/5K2/8/8/8/8/6R1 w - - 0 1"), &mbb);
+
+        let (serial_mv, serial_score) = AlphaBeta::new(evaluate, 1 << 20).evaluate(&chess, 4);
+        let (mv, score) = AlphaBetaParallel::new(evaluate, 1 << 20, 4).evaluate(&chess, 4);
+
+        assert_eq!(mv, serial_mv);
+        assert_eq!(score, serial_score);
+        assert_eq!(score, MATE - 3);
+    }
+
+    // Timing dependent, so only run on demand (ideally with --release).
+    #[test]
+    #[ignore]
+    fn parallel_speedup() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+
+        let before = Instant::now();
+        AlphaBetaParallel::new(evaluate, 1 << 24, 1).evaluate(&chess, 8);
+        let serial = before.elapsed();
+
+        let before = Instant::now();
+        AlphaBetaParallel::new(evaluate, 1 << 24, 4).evaluate(&chess, 8);
+        let parallel = before.elapsed();
+
+        println!("1 thread: {:?}, 4 threads: {:?}", serial, parallel);
+        assert!(parallel < serial);
+    }
+
+    #[test]
+    fn mvv_lva_ordering() {
+        let mbb = MagicBitBoards::default();
+        // Both the pawn and the queen can take the queen on d5; PxQ should come first.
+        let chess = Chess::new(load_fen("4k3/8/8/3q4/4P3/8/3Q4/4K3 w - - 0 1"), &mbb);
+        let alphabeta = AlphaBeta::new(evaluate, 1 << 16);
+
+        let pxq = Move::Normal(Piece::Pawn, parse_coord("e4"), parse_coord("d5"));
+        let ordered = alphabeta.order_moves(&chess, Game::legal_moves(&chess), None, 0);
+        assert_eq!(ordered[0], pxq);
+
+        // Captures still come before quiet moves, but QxP is ranked after PxP.
+        let chess = Chess::new(load_fen("4k3/8/2p5/3p4/4P3/8/8/3QK3 w - - 0 1"), &mbb);
+        let pxp = Move::Normal(Piece::Pawn, parse_coord("e4"), parse_coord("d5"));
+        let qxp = Move::Normal(Piece::Queen, parse_coord("d1"), parse_coord("d5"));
+        let ordered = alphabeta.order_moves(&chess, Game::legal_moves(&chess), None, 0);
+        assert_eq!(&ordered[0..2], &[pxp, qxp]);
+    }
+
+    #[test]
+    fn timed_search() {
+        let mbb = MagicBitBoards::default();
+        let chess = Chess::new(load_fen(STARTING_POSITION), &mbb);
+        let mut alphabeta = AlphaBeta::new(evaluate, 1 << 20);
+
+        let (mv, _, depth) = alphabeta.evaluate_timed(&chess, Duration::from_millis(50));
+
+        assert!(legal_moves(&chess.state, &mbb).contains(&mv.unwrap()));
+        assert!(depth >= 2, "Only reached depth {}", depth);
+    }
+}
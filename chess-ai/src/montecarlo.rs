@@ -1,15 +1,26 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::hash::{BuildHasherDefault, Hash};
 use std::rc::Rc;
+use std::time::Instant;
 
+use rand::SeedableRng;
 use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
+
+// Rollouts longer than this are scored as draws, in case the game's own draw rules don't end them.
+const MAX_ROLLOUT_PLIES: usize = 400;
+
+// Unseeded hasher so that children are always iterated in the same order, which keeps searches
+// with the same seed reproducible.
+type Children<Move> = HashMap<Move, Rc<RefCell<Node<Move>>>, BuildHasherDefault<DefaultHasher>>;
 
 pub struct Node<Move> {
     wins: f32,
     simulations: f32,
-    children: HashMap<Move, Rc<RefCell<Node<Move>>>>,
+    children: Children<Move>,
     parent: Option<Rc<RefCell<Node<Move>>>>,
     mv: Option<Move>,
 }
@@ -19,7 +30,7 @@ impl <Move : Hash + Eq> Node<Move> {
         Node{
             wins: 0.0,
             simulations: 0.0,
-            children: HashMap::new(),
+            children: HashMap::default(),
             parent: None,
             mv: None,
         }
@@ -29,7 +40,7 @@ impl <Move : Hash + Eq> Node<Move> {
         Node{
             wins: 0.0,
             simulations: 0.0,
-            children: HashMap::new(),
+            children: HashMap::default(),
             parent: Some(parent),
             mv: Some(mv),
         }
@@ -77,13 +88,20 @@ impl GameResult {
 pub struct MCTS<M, G> {
     root: Rc<RefCell<Node<M>>>,
     initial_state: G,
+    rng: ChaCha8Rng,
 }
 
 impl <M : Hash + Eq + Copy + Debug, G : Game<Move = M>> MCTS<M, G> {
     pub fn new(initial_state: G) -> MCTS<M, G> {
+        MCTS::with_rng(initial_state, ChaCha8Rng::from_entropy())
+    }
+
+    // Searches with identically seeded rngs will give identical results.
+    pub fn with_rng(initial_state: G, rng: ChaCha8Rng) -> MCTS<M, G> {
         MCTS{
             root: Rc::new(RefCell::new(Node::root())),
             initial_state,
+            rng,
         }
     }
 
@@ -107,23 +125,63 @@ impl <M : Hash + Eq + Copy + Debug, G : Game<Move = M>> MCTS<M, G> {
         *best.unwrap()
     }
 
+    // The most visited move, along with its share of all the visits to the root's children.
+    pub fn best_move_with_confidence(&self) -> (M, f32) {
+        let best = self.best_move();
+        let root = self.root.borrow();
+        let total: f32 = root.children.values().map(|nd| nd.borrow().simulations).sum();
+        let best_simulations = root.children[&best].borrow().simulations;
+        (best, best_simulations / total)
+    }
+
     pub fn move_scores(&self) -> Vec<(M, f32, f32)> {
         self.root.borrow().children.iter().map(|(mv, nd)| (*mv, nd.borrow().wins, nd.borrow().simulations)).collect()
     }
 
     pub fn simulate_once(&mut self) {
-        let (leaf, state) = MCTS::traverse(self.root.clone(), self.initial_state.clone());
-        let result = MCTS::rollout(state);
+        let (leaf, state) = MCTS::traverse(self.root.clone(), self.initial_state.clone(), &mut self.rng);
+        let result = MCTS::rollout(state, &mut self.rng);
         Self::back_propagate(leaf, result);
     }
 
-    pub fn traverse(node: Rc<RefCell<Node<M>>>, state: G) -> (Rc<RefCell<Node<M>>>, G) {
+    // Play a move, keeping the statistics already gathered for the resulting position.
+    pub fn advance(&mut self, mv: M) {
+        let child = self.root.borrow_mut().children.remove(&mv);
+        self.root = match child {
+            Some(child) => {
+                {
+                    let mut nd = child.borrow_mut();
+                    nd.parent = None;
+                    nd.mv = None;
+                }
+                child
+            },
+            None => Rc::new(RefCell::new(Node::root())),
+        };
+        self.initial_state.make_move(mv);
+    }
+
+    pub fn run_for(&mut self, iterations: usize) {
+        for _ in 0..iterations {
+            self.simulate_once();
+        }
+    }
+
+    pub fn run_until(&mut self, deadline: Instant) {
+        // Only check the clock every so often, it's relatively expensive.
+        const CHECK_INTERVAL: usize = 256;
+        while Instant::now() < deadline {
+            self.run_for(CHECK_INTERVAL);
+        }
+    }
+
+    pub fn traverse(node: Rc<RefCell<Node<M>>>, state: G, rng: &mut ChaCha8Rng) -> (Rc<RefCell<Node<M>>>, G) {
         let legal_moves = state.legal_moves();
         let unexplored_moves: Vec<M> = legal_moves.into_iter().filter(|m| !node.borrow().children.contains_key(&m)).collect();
         let mut new_state = state.clone();
 
         if unexplored_moves.len() > 0 {
-            let mv = unexplored_moves.choose(&mut rand::thread_rng()).unwrap();
+            let mv = unexplored_moves.choose(rng).unwrap();
             new_state.make_move(*mv);
             let nd = Rc::new(RefCell::new(Node::child_of(node.clone(), *mv)));
             node.borrow_mut().children.insert(*mv, nd.clone());
@@ -134,7 +192,7 @@ impl <M : Hash + Eq + Copy + Debug, G : Game<Move = M>> MCTS<M, G> {
             match Self::select_child_uct(node.clone()) {
                 Some(child) => {
                     new_state.make_move(child.borrow().mv.unwrap());
-                    Self::traverse(child, new_state)
+                    Self::traverse(child, new_state, rng)
                 },
                 None => (node, state),
             }
@@ -169,14 +227,20 @@ impl <M : Hash + Eq + Copy + Debug, G : Game<Move = M>> MCTS<M, G> {
         exploitation + exploration
     }
 
-    fn rollout(state: G) -> GameResult {
+    fn rollout(state: G, rng: &mut ChaCha8Rng) -> GameResult {
         let mut rollout_state = state.clone();
         let this_player = state.active_player();
 
         let mut game_state = state.game_state();
+        let mut plies = 0;
         while game_state == GameState::Ongoing {
+            if plies == MAX_ROLLOUT_PLIES {
+                return GameResult::Draw;
+            }
+            plies += 1;
+
             let legal_moves = rollout_state.legal_moves();
-            let mv = legal_moves.choose(&mut rand::thread_rng()).unwrap();
+            let mv = legal_moves.choose(rng).unwrap();
             rollout_state.make_move(*mv);
             game_state = rollout_state.game_state();
         }
@@ -210,3 +274,130 @@ impl <M : Hash + Eq + Copy + Debug, G : Game<Move = M>> MCTS<M, G> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chess_lib::fen::{load_fen, STARTING_POSITION};
+    use chess_lib::magic::MagicBitBoards;
+    use chess_lib::moves::legal_moves;
+
+    use crate::chess::Chess;
+    use crate::montecarlo::*;
+
+    #[test]
+    fn run_for() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let mut mcts = MCTS::new(Chess::new(state.clone(), &mbb));
+
+        mcts.run_for(10_000);
+
+        let (mv, confidence) = mcts.best_move_with_confidence();
+        assert!(legal_moves(&state, &mbb).contains(&mv));
+        assert!(confidence > 0.0 && confidence <= 1.0);
+    }
+
+    #[test]
+    fn advance_keeps_subtree() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let mut mcts = MCTS::new(Chess::new(state.clone(), &mbb));
+
+        mcts.run_for(500);
+        let mv = mcts.best_move();
+        let simulations = mcts.root.borrow().children[&mv].borrow().simulations;
+
+        mcts.advance(mv);
+        assert_eq!(mcts.root.borrow().simulations, simulations);
+        assert!(mcts.root.borrow().parent.is_none());
+
+        // Further simulations build on the retained tree.
+        mcts.run_for(100);
+        assert_eq!(mcts.root.borrow().simulations, simulations + 100.0);
+    }
+
+    #[test]
+    fn advance_unexplored_move() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let mut mcts = MCTS::new(Chess::new(state.clone(), &mbb));
+
+        let mv = legal_moves(&state, &mbb)[0];
+        mcts.advance(mv);
+        assert_eq!(mcts.root.borrow().simulations, 0.0);
+
+        mcts.run_for(10);
+        let mut next = state.clone();
+        next.make_move(mv);
+        assert!(legal_moves(&next, &mbb).contains(&mcts.best_move()));
+    }
+
+    #[test]
+    fn run_until() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let mut mcts = MCTS::new(Chess::new(state.clone(), &mbb));
+
+        mcts.run_until(Instant::now() + Duration::from_millis(50));
+
+        assert!(legal_moves(&state, &mbb).contains(&mcts.best_move()));
+    }
+
+    #[test]
+    fn seeded_searches_are_reproducible() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+
+        let search = |seed: u64| {
+            let mut mcts = MCTS::with_rng(Chess::new(state.clone(), &mbb), ChaCha8Rng::seed_from_u64(seed));
+            mcts.run_for(2_000);
+            let mut scores = mcts.move_scores();
+            scores.sort_by_key(|(mv, _, _)| format!("{:?}", mv));
+            (mcts.best_move(), scores)
+        };
+
+        assert_eq!(search(42), search(42));
+    }
+
+    // A game that never ends by itself.
+    #[derive(Clone)]
+    struct Endless {
+        plies: usize,
+    }
+
+    impl Game for Endless {
+        type Move = u8;
+
+        fn make_move(&mut self, _mv: u8) {
+            self.plies += 1;
+        }
+
+        fn legal_moves(&self) -> Vec<u8> {
+            vec![0, 1]
+        }
+
+        fn game_state(&self) -> GameState {
+            GameState::Ongoing
+        }
+
+        fn active_player(&self) -> Player {
+            if self.plies.is_multiple_of(2) { Player::One } else { Player::Two }
+        }
+    }
+
+    #[test]
+    fn rollouts_are_capped() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert_eq!(MCTS::rollout(Endless{plies: 0}, &mut rng), GameResult::Draw);
+
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("8/8/3k4/8/8/4K3/8/8 w - - 0 1");
+        let start = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(MCTS::rollout(Chess::new(state.clone(), &mbb), &mut rng), GameResult::Draw);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
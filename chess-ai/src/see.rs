@@ -0,0 +1,148 @@
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::moves::attackers_to;
+use chess_lib::types::{BitBoard, BitCoord, Colour, GameState, Move, Piece, Pieces};
+
+use crate::eval::piece_value;
+
+// The king is worth nothing to the evaluation, but here it must never be traded.
+const KING_VALUE: i64 = 10_000;
+
+// Order in which pieces are thrown into an exchange.
+const LEAST_VALUABLE_FIRST: [Piece; 6] = [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen, Piece::King];
+
+// Net material gain for the side to move from capturing on `tgt` with their least valuable
+// attacker, assuming both sides keep recapturing for as long as it pays. 0 if nothing attacks it.
+pub fn static_exchange_eval(state: &GameState, tgt: BitCoord, mbb: &MagicBitBoards) -> i64 {
+    let occupancy = state.white.pieces.all() | state.black.pieces.all();
+    let pieces = side_pieces(state, state.active_colour);
+
+    match least_valuable_attacker(pieces, occupancy, tgt, state.active_colour, mbb) {
+        Some((src, _)) => exchange(state, src, tgt, victim_value(state, tgt), mbb),
+        None => 0,
+    }
+}
+
+// The same, but starting with the given capture rather than the least valuable attacker.
+// Quiet moves and promotions score 0.
+pub fn static_exchange_eval_move(state: &GameState, mv: Move, mbb: &MagicBitBoards) -> i64 {
+    match mv {
+        Move::Normal(_, src, tgt) if state.find_piece(tgt).is_some() => {
+            exchange(state, src, tgt, victim_value(state, tgt), mbb)
+        },
+        Move::EnPassant(src, tgt) => exchange(state, src, tgt, piece_value(Piece::Pawn), mbb),
+        _ => 0,
+    }
+}
+
+fn exchange(state: &GameState, src: BitCoord, tgt: BitCoord, victim: i64, mbb: &MagicBitBoards) -> i64 {
+    let mut occupancy = state.white.pieces.all() | state.black.pieces.all();
+    let mut colour = state.active_colour;
+    let mut attacker = (src, state.find_piece(src).expect("No piece on square").1);
+
+    // gains[d] is what the side making capture d stands to win, if it's then recaptured.
+    let mut gains: Vec<i64> = vec![victim];
+    loop {
+        let (from, piece) = attacker;
+        gains.push(value(piece) - gains[gains.len() - 1]);
+
+        // Removing the piece uncovers any sliders behind it.
+        occupancy = occupancy & !BitBoard(from.0);
+        colour = Colour::other(colour);
+        match least_valuable_attacker(side_pieces(state, colour), occupancy, tgt, colour, mbb) {
+            Some(next) => attacker = next,
+            None => break,
+        }
+    }
+
+    // Each side may stop capturing if that's better for them.
+    for d in (1..gains.len() - 1).rev() {
+        gains[d - 1] = -(-gains[d - 1]).max(gains[d]);
+    }
+
+    gains[0]
+}
+
+fn least_valuable_attacker(pieces: &Pieces, occupancy: BitBoard, tgt: BitCoord, colour: Colour, mbb: &MagicBitBoards) -> Option<(BitCoord, Piece)> {
+    // Pieces already used in the exchange have been removed from the occupancy.
+    let attackers = attackers_to(pieces, colour, occupancy, tgt, mbb) & occupancy;
+
+    for piece in LEAST_VALUABLE_FIRST.iter() {
+        let bb = match piece {
+            Piece::Pawn => pieces.pawns,
+            Piece::Knight => pieces.knights,
+            Piece::Bishop => pieces.bishops,
+            Piece::Rook => pieces.rooks,
+            Piece::Queen => pieces.queens,
+            Piece::King => pieces.king,
+        };
+
+        if let Some(src) = (attackers & bb).iter().next() {
+            return Some((src, *piece));
+        }
+    }
+
+    None
+}
+
+fn side_pieces(state: &GameState, colour: Colour) -> &Pieces {
+    match colour {
+        Colour::White => &state.white.pieces,
+        Colour::Black => &state.black.pieces,
+    }
+}
+
+fn victim_value(state: &GameState, tgt: BitCoord) -> i64 {
+    state.find_piece(tgt).map(|(_, pc)| value(pc)).unwrap_or(0)
+}
+
+fn value(piece: Piece) -> i64 {
+    match piece {
+        Piece::King => KING_VALUE,
+        _ => piece_value(piece),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chess_lib::fen::load_fen;
+    use chess_lib::fmt::{parse_coord, parse_uci_move};
+    use chess_lib::magic::MagicBitBoards;
+    use crate::see::*;
+
+    #[test]
+    fn losing_capture() {
+        let mbb = MagicBitBoards::default();
+
+        // The pawn on d5 is defended by the pawn on e6, so RxP loses the rook for a pawn.
+        let state = load_fen("4k3/8/4p3/3p4/8/8/8/3RK3 w - - 0 1");
+        assert_eq!(static_exchange_eval(&state, parse_coord("d5"), &mbb), 100 - 500);
+    }
+
+    #[test]
+    fn winning_capture() {
+        let mbb = MagicBitBoards::default();
+
+        // PxN wins a knight for a pawn, even though the knight is defended.
+        let state = load_fen("4k3/8/4p3/3n4/4P3/8/8/4K3 w - - 0 1");
+        assert_eq!(static_exchange_eval(&state, parse_coord("d5"), &mbb), 300 - 100);
+
+        // Nothing attacks e6.
+        assert_eq!(static_exchange_eval(&state, parse_coord("e6"), &mbb), 0);
+    }
+
+    #[test]
+    fn x_rays_and_specific_moves() {
+        let mbb = MagicBitBoards::default();
+
+        // The queen behind the rook only joins in once the rook has gone: RxP, RxR, QxR.
+        let state = load_fen("3rk3/8/8/3p4/8/8/3R4/3QK3 w - - 0 1");
+        assert_eq!(static_exchange_eval(&state, parse_coord("d5"), &mbb), 100);
+
+        // exd6, cxd6, Qxd6 wins a pawn, but starting with the queen gives it up for two pawns.
+        let state = load_fen("4k3/2p5/3p4/4P3/8/8/8/3QK3 w - - 0 1");
+        let exd6 = parse_uci_move(&state, "e5d6", &mbb).unwrap();
+        let qxd6 = parse_uci_move(&state, "d1d6", &mbb).unwrap();
+        assert_eq!(static_exchange_eval_move(&state, exd6, &mbb), 100);
+        assert_eq!(static_exchange_eval_move(&state, qxd6, &mbb), 100 - 900 + 100);
+    }
+}
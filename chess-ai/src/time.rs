@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+// Assumed number of moves left in the game when the time control doesn't say.
+const DEFAULT_MOVES_TO_GO: u32 = 30;
+// Kept back for the time it takes to get the move to the GUI, so we don't lose on time.
+const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
+
+// Turns the clock into a time budget for the next move.
+pub struct TimeManager;
+
+impl TimeManager {
+    // An even share of the time left between the remaining moves, keeping one share in reserve,
+    // plus most of the increment. Never more than is left on the clock. With almost nothing left
+    // the budget is zero, which still gets a move from a depth 1 search.
+    pub fn allocate(remaining: Duration, increment: Duration, moves_to_go: Option<u32>) -> Duration {
+        let usable = remaining.checked_sub(MOVE_OVERHEAD).unwrap_or_default();
+        let moves = match moves_to_go {
+            Some(n) => n + 1,
+            None => DEFAULT_MOVES_TO_GO,
+        };
+
+        let budget = usable / moves + increment * 3 / 4;
+        budget.min(usable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::time::*;
+
+    fn allocate(remaining_ms: u64, increment_ms: u64, moves_to_go: Option<u32>) -> u128 {
+        TimeManager::allocate(Duration::from_millis(remaining_ms), Duration::from_millis(increment_ms), moves_to_go).as_millis()
+    }
+
+    #[test]
+    fn sudden_death() {
+        // 5 minutes left, no increment.
+        assert_eq!(allocate(300_050, 0, None), 10_000);
+        // Plus most of the increment.
+        assert_eq!(allocate(300_050, 2_000, None), 11_500);
+    }
+
+    #[test]
+    fn moves_to_go() {
+        // 40 moves in 10 minutes, with one move's share kept back.
+        assert_eq!(allocate(410_050, 0, Some(40)), 10_000);
+        // The last move before the time control still leaves some time on the clock.
+        assert_eq!(allocate(10_050, 0, Some(1)), 5_000);
+    }
+
+    #[test]
+    fn low_time() {
+        // The increment can't be spent before it's been added to the clock.
+        assert_eq!(allocate(1_050, 5_000, None), 1_000);
+        assert_eq!(allocate(30, 0, None), 0);
+        assert_eq!(allocate(0, 0, Some(10)), 0);
+    }
+}
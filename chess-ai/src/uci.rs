@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use chess_lib::fen::{load_fen, parse_fen, STARTING_POSITION};
+use chess_lib::fmt::format_uci_move;
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::types::{Colour, GameState, Move};
+
+use crate::chess::Chess;
+use crate::eval::evaluate;
+use crate::minimax::{is_mate_score, AlphaBeta, SearchInfo, MATE};
+use crate::time::TimeManager;
+
+// Transposition table size in MB, as set by the Hash option.
+pub(crate) const HASH_DEFAULT_MB: usize = 16;
+pub(crate) const HASH_MAX_MB: usize = 4096;
+// Used for a bare "go", or "go infinite", since we can't be interrupted mid-search.
+pub(crate) const DEFAULT_DEPTH: u32 = 6;
+
+pub struct Uci<'a> {
+    mbb: &'a MagicBitBoards,
+    state: GameState,
+    search: AlphaBeta<Chess<'a>>,
+    // Shared with the search, which writes info lines as each depth completes.
+    output: Rc<RefCell<dyn Write>>,
+    hash_mb: usize,
+}
+
+impl <'a> Uci<'a> {
+    pub fn new<W: Write + 'static>(mbb: &'a MagicBitBoards, output: W) -> Uci<'a> {
+        Uci{
+            mbb,
+            state: load_fen(STARTING_POSITION),
+            search: new_search(HASH_DEFAULT_MB),
+            output: Rc::new(RefCell::new(output)),
+            hash_mb: HASH_DEFAULT_MB,
+        }
+    }
+
+    // Reads commands until "quit" or the end of the input.
+    pub fn run<R: BufRead>(&mut self, input: R) -> io::Result<()> {
+        for line in input.lines() {
+            if !self.handle_command(&line?)? {
+                break;
+            }
+            self.output.borrow_mut().flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Returns false once we've been told to quit.
+    fn handle_command(&mut self, line: &str) -> io::Result<bool> {
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("uci") => {
+                self.send("id name chess")?;
+                self.send(&format!("id author {}", env!("CARGO_PKG_AUTHORS")))?;
+                self.send(&format!("option name Hash type spin default {} min 1 max {}", HASH_DEFAULT_MB, HASH_MAX_MB))?;
+                self.send("uciok")?;
+            },
+            Some("isready") => self.send("readyok")?,
+            Some("ucinewgame") => {
+                self.state = load_fen(STARTING_POSITION);
+                self.search = new_search(self.hash_mb);
+            },
+            Some("setoption") => self.set_option(&tokens.collect::<Vec<&str>>()),
+            Some("position") => self.position(&tokens.collect::<Vec<&str>>())?,
+            Some("go") => self.go(&tokens.collect::<Vec<&str>>())?,
+            Some("quit") => return Ok(false),
+            // Unknown commands should be ignored, according to the spec.
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    fn send(&self, line: &str) -> io::Result<()> {
+        writeln!(self.output.borrow_mut(), "{}", line)
+    }
+
+    // setoption name <name> [value <value>]
+    fn set_option(&mut self, args: &[&str]) {
+        let value_ix = args.iter().position(|t| *t == "value").unwrap_or(args.len());
+        let name = args.get(1..value_ix).unwrap_or(&[]).join(" ");
+        let value = args.get(value_ix + 1..).unwrap_or(&[]).join(" ");
+
+        // Option names are case insensitive.
+        if name.eq_ignore_ascii_case("hash") {
+            if let Ok(mb) = value.parse::<usize>() {
+                self.hash_mb = mb.clamp(1, HASH_MAX_MB);
+                self.search.set_hash_mb(self.hash_mb);
+            }
+        }
+    }
+
+    // position [startpos | fen <fen>] [moves <move>...]
+    // An invalid FEN is reported, and leaves the previous position in place.
+    fn position(&mut self, args: &[&str]) -> io::Result<()> {
+        let moves_ix = args.iter().position(|t| *t == "moves").unwrap_or(args.len());
+
+        self.state = match args.first() {
+            Some(&"fen") => match parse_fen(&args[1..moves_ix].join(" ")) {
+                Ok(state) => state,
+                Err(e) => return self.send(&format!("info string {}", e)),
+            },
+            _ => load_fen(STARTING_POSITION),
+        };
+
+        // Anything after an illegal move is ignored.
+        let moves = args.get(moves_ix + 1..).unwrap_or(&[]);
+        let _ = self.state.apply_uci_moves(moves, self.mbb);
+        Ok(())
+    }
+
+    fn go(&mut self, args: &[&str]) -> io::Result<()> {
+        let arg = |name: &str| -> Option<u64> {
+            let ix = args.iter().position(|t| *t == name)?;
+            args.get(ix + 1)?.parse().ok()
+        };
+
+        let (time, inc) = match self.state.active_colour {
+            Colour::White => (arg("wtime"), arg("winc")),
+            Colour::Black => (arg("btime"), arg("binc")),
+        };
+
+        let game = Chess::new(self.state.clone(), self.mbb);
+
+        // Written as soon as each depth completes, so GUIs can show progress during long searches.
+        // A failed write can't be reported from inside the search; the next command will hit it.
+        let root = self.state.clone();
+        let info_output = self.output.clone();
+        self.search.on_info = Some(Box::new(move |info| {
+            let mut output = info_output.borrow_mut();
+            let _ = writeln!(output, "{}", format_info(&root, &info));
+            let _ = output.flush();
+        }));
+
+        let mv = if let Some(depth) = arg("depth") {
+            self.search.evaluate(&game, depth as u32).0
+        } else if let Some(movetime) = arg("movetime") {
+            self.search.evaluate_timed(&game, Duration::from_millis(movetime)).0
+        } else if let Some(time) = time {
+            let budget = TimeManager::allocate(
+                Duration::from_millis(time),
+                Duration::from_millis(inc.unwrap_or(0)),
+                arg("movestogo").map(|n| n as u32),
+            );
+            self.search.evaluate_timed(&game, budget).0
+        } else {
+            self.search.evaluate(&game, DEFAULT_DEPTH).0
+        };
+
+        match mv {
+            Some(mv) => self.send(&format!("bestmove {}", format_uci_move(&self.state, mv))),
+            None => self.send("bestmove 0000"),
+        }
+    }
+}
+
+fn new_search<'a>(hash_mb: usize) -> AlphaBeta<Chess<'a>> {
+    AlphaBeta::new(evaluate, hash_mb << 20)
+}
+
+fn format_info(state: &GameState, info: &SearchInfo<Move>) -> String {
+    let mut pv_state = state.clone();
+    let pv: Vec<String> = info.pv.iter()
+        .map(|mv| {
+            let s = format_uci_move(&pv_state, *mv);
+            pv_state.make_move(*mv);
+            s
+        })
+        .collect();
+
+    format!(
+        "info depth {} score {} nodes {} nps {} time {} pv {}",
+        info.depth,
+        format_score(info.score),
+        info.nodes,
+        info.nps,
+        info.time.as_millis(),
+        pv.join(" "),
+    )
+}
+
+// Scores are from the point of view of the side to move, and mates are given in moves, not plies.
+fn format_score(score: i64) -> String {
+    if is_mate_score(score) {
+        let plies = MATE - score.abs();
+        let moves = (plies + 1) / 2;
+        format!("mate {}", if score > 0 { moves } else { -moves })
+    } else {
+        format!("cp {}", score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use chess_lib::fen::{load_fen, STARTING_POSITION};
+    use chess_lib::fmt::parse_uci_move;
+    use chess_lib::magic::MagicBitBoards;
+    use crate::uci::*;
+
+    // Keeps what's been written where the test can still see it, along with a snapshot of the
+    // output at each flush.
+    #[derive(Clone, Default)]
+    struct SharedOutput {
+        written: Rc<RefCell<Vec<u8>>>,
+        flushes: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let snapshot = String::from_utf8(self.written.borrow().clone()).unwrap();
+            self.flushes.borrow_mut().push(snapshot);
+            Ok(())
+        }
+    }
+
+    fn lines(bytes: &[u8]) -> Vec<String> {
+        String::from_utf8(bytes.to_vec()).unwrap().lines().map(|l| l.to_owned()).collect()
+    }
+
+    fn run_script(mbb: &'static MagicBitBoards, script: &str) -> Vec<String> {
+        let output = SharedOutput::default();
+        Uci::new(mbb, output.clone()).run(Cursor::new(script)).unwrap();
+        let written = output.written.borrow();
+        lines(&written)
+    }
+
+    #[test]
+    fn plays_legal_move() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "uci\nisready\nucinewgame\nposition startpos moves e2e4 e7e5\ngo depth 3\nquit\n");
+
+        assert!(output.contains(&"uciok".to_owned()));
+        assert!(output.contains(&"readyok".to_owned()));
+        let depths: Vec<&str> = output.iter()
+            .filter(|l| l.starts_with("info depth "))
+            .map(|l| l.split_whitespace().nth(2).unwrap())
+            .collect();
+        assert_eq!(depths, vec!["1", "2", "3"]);
+
+        let bestmove = output.last().unwrap().strip_prefix("bestmove ").unwrap();
+        let mut state = load_fen(STARTING_POSITION);
+        for s in ["e2e4", "e7e5"].iter() {
+            let mv = parse_uci_move(&state, s, mbb).unwrap();
+            state.make_move(mv);
+        }
+        assert!(parse_uci_move(&state, bestmove, mbb).is_some(), "{}", bestmove);
+    }
+
+    #[test]
+    fn hash_option() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "uci\nsetoption name Hash value 1\nucinewgame\nposition startpos\ngo depth 2\nquit\n");
+
+        assert!(output.iter().any(|l| l.starts_with("option name Hash type spin")), "{:?}", output);
+        assert!(output.last().unwrap().starts_with("bestmove "));
+
+        let mut uci = Uci::new(mbb, SharedOutput::default());
+        uci.set_option(&["name", "Hash", "value", "32"]);
+        assert_eq!(uci.hash_mb, 32);
+        uci.set_option(&["name", "Hash", "value", "lots"]);
+        assert_eq!(uci.hash_mb, 32);
+    }
+
+    #[test]
+    fn finds_mate() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "position fen 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1\ngo movetime 200\n");
+
+        assert!(output.iter().any(|l| l.starts_with("info ") && l.contains("score mate 1 ")), "{:?}", output);
+        assert_eq!(output.last().unwrap(), "bestmove a1a8");
+    }
+
+    #[test]
+    fn no_moves() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "position fen 7k/5Q2/6K1/8/8/8/8/8 b - - 0 1\ngo wtime 1000 btime 1000\n");
+        assert_eq!(output, vec!["bestmove 0000".to_owned()]);
+    }
+
+    #[test]
+    fn invalid_fen_keeps_position() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "position fen 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1\nposition fen 6k1/5ppp/8/8/8/8/8/R5K1 x\ngo depth 2\n");
+
+        assert_eq!(output[0], "info string Invalid active colour field: x");
+        assert_eq!(output.last().unwrap(), "bestmove a1a8");
+    }
+
+    #[test]
+    fn go_depth_zero() {
+        let mbb = MagicBitBoards::shared();
+        let output = run_script(mbb, "position startpos\ngo depth 0\n");
+        assert!(output.last().unwrap().starts_with("bestmove "), "{:?}", output);
+        assert_ne!(output.last().unwrap(), "bestmove 0000");
+    }
+
+    #[test]
+    fn streams_info_lines() {
+        let mbb = MagicBitBoards::shared();
+        let output = SharedOutput::default();
+        Uci::new(mbb, output.clone()).run(Cursor::new("position startpos\ngo depth 3\n")).unwrap();
+
+        // Each depth was flushed on its own, before the search finished.
+        let flushes = output.flushes.borrow();
+        let first = flushes.iter().find(|f| !f.is_empty()).unwrap();
+        assert_eq!(lines(first.as_bytes()).len(), 1);
+        assert!(first.starts_with("info depth 1 "), "{}", first);
+        assert!(flushes.iter().filter(|f| !f.contains("bestmove")).count() >= 3);
+    }
+}
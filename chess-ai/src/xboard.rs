@@ -0,0 +1,268 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+use chess_lib::fen::{load_fen, parse_fen, STARTING_POSITION};
+use chess_lib::fmt::{format_uci_move, parse_uci_move};
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::types::{Colour, GameState, Move, Status};
+
+use crate::chess::Chess;
+use crate::eval::evaluate;
+use crate::minimax::AlphaBeta;
+use crate::time::TimeManager;
+use crate::uci::{DEFAULT_DEPTH, HASH_DEFAULT_MB, HASH_MAX_MB};
+
+// The Xboard (CECP) protocol. Unlike UCI the engine keeps track of the game itself, and moves as
+// soon as it's its turn unless in force mode.
+pub struct Xboard<'a> {
+    mbb: &'a MagicBitBoards,
+    state: GameState,
+    search: AlphaBeta<Chess<'a>>,
+    // The side the engine plays, or None in force mode.
+    engine: Option<Colour>,
+    limits: Limits,
+    hash_mb: usize,
+}
+
+// Set by "level", "st", "sd", and kept up to date by "time".
+#[derive(Clone, Copy, Debug, Default)]
+struct Limits {
+    depth: Option<u32>,
+    move_time: Option<Duration>,
+    // Moves per time control, or None if the base time is for the whole game.
+    moves_per_session: Option<u64>,
+    // Milliseconds.
+    increment: u64,
+    time_left: Option<u64>,
+}
+
+impl <'a> Xboard<'a> {
+    pub fn new(mbb: &'a MagicBitBoards) -> Xboard<'a> {
+        Xboard{
+            mbb,
+            state: load_fen(STARTING_POSITION),
+            search: AlphaBeta::new(evaluate, HASH_DEFAULT_MB << 20),
+            engine: Some(Colour::Black),
+            limits: Limits::default(),
+            hash_mb: HASH_DEFAULT_MB,
+        }
+    }
+
+    // Reads commands until "quit" or the end of the input.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        for line in input.lines() {
+            if !self.handle_command(&line?, output)? {
+                break;
+            }
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Returns false once we've been told to quit.
+    fn handle_command<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<bool> {
+        let mut tokens = line.split_whitespace();
+        let command = tokens.next();
+        let args: Vec<&str> = tokens.collect();
+
+        match command {
+            Some("protover") => {
+                writeln!(output, "feature myname=\"chess\" setboard=1 usermove=1 ping=1 memory=1 sigint=0 sigterm=0 done=1")?;
+            },
+            Some("ping") => writeln!(output, "pong {}", args.join(" "))?,
+            Some("new") => {
+                self.state = load_fen(STARTING_POSITION);
+                self.search = AlphaBeta::new(evaluate, self.hash_mb << 20);
+                self.engine = Some(Colour::Black);
+                self.limits.depth = None;
+            },
+            Some("force") => self.engine = None,
+            Some("go") => {
+                self.engine = Some(self.state.active_colour);
+                self.think(output)?;
+            },
+            Some("setboard") => match parse_fen(&args.join(" ")) {
+                Ok(state) => self.state = state,
+                Err(_) => writeln!(output, "tellusererror Illegal position")?,
+            },
+            Some("usermove") => self.user_move(args.first().copied().unwrap_or(""), output)?,
+            Some("level") => self.level(&args),
+            Some("st") => {
+                // Negative, NaN or absurdly large times are ignored.
+                self.limits.move_time = args.first()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+            },
+            // Megabytes.
+            Some("memory") => {
+                if let Some(mb) = args.first().and_then(|s| s.parse::<usize>().ok()) {
+                    self.hash_mb = mb.clamp(1, HASH_MAX_MB);
+                    self.search.set_hash_mb(self.hash_mb);
+                }
+            },
+            Some("sd") => self.limits.depth = args.first().and_then(|s| s.parse().ok()),
+            // Centiseconds.
+            Some("time") => self.limits.time_left = args.first().and_then(|s| s.parse::<u64>().ok()).map(|cs| cs * 10),
+            Some("quit") => return Ok(false),
+            // Everything else, including "xboard", "otim", "post" and "result", needs no reply.
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    fn user_move<W: Write>(&mut self, mv: &str, output: &mut W) -> io::Result<()> {
+        match parse_uci_move(&self.state, mv, self.mbb) {
+            Some(mv) => self.state.make_move(mv),
+            None => return writeln!(output, "Illegal move: {}", mv),
+        };
+
+        if self.report_result(output)? {
+            return Ok(());
+        }
+
+        if self.engine == Some(self.state.active_colour) {
+            self.think(output)?;
+        }
+
+        Ok(())
+    }
+
+    // level <moves per session> <base time> <increment>
+    // Base time is in minutes, or minutes:seconds, and the increment is in seconds.
+    fn level(&mut self, args: &[&str]) {
+        let mps = args.first().and_then(|s| s.parse::<u64>().ok());
+        let base = args.get(1).and_then(|s| {
+            let mut parts = s.split(':');
+            let minutes: u64 = parts.next()?.parse().ok()?;
+            let seconds: u64 = parts.next().map(|s| s.parse().ok()).unwrap_or(Some(0))?;
+            Some((minutes * 60 + seconds) * 1000)
+        });
+        let inc = args.get(2).and_then(|s| s.parse::<f64>().ok());
+
+        self.limits.moves_per_session = mps.filter(|n| *n > 0);
+        self.limits.time_left = base;
+        self.limits.increment = inc.map(|s| (s * 1000.0) as u64).unwrap_or(0);
+        self.limits.move_time = None;
+    }
+
+    fn think<W: Write>(&mut self, output: &mut W) -> io::Result<()> {
+        if self.report_result(output)? {
+            return Ok(());
+        }
+
+        let game = Chess::new(self.state.clone(), self.mbb);
+        let limits = self.limits;
+        let mv = if let Some(depth) = limits.depth {
+            self.search.evaluate(&game, depth).0
+        } else if let Some(move_time) = limits.move_time {
+            self.search.evaluate_timed(&game, move_time).0
+        } else if let Some(time) = limits.time_left {
+            // Moves left before the next time control, counting this one.
+            let moves_to_go = limits.moves_per_session
+                .map(|mps| (mps - (self.state.fullmove_number() as u64 - 1) % mps) as u32);
+            let budget = TimeManager::allocate(
+                Duration::from_millis(time),
+                Duration::from_millis(limits.increment),
+                moves_to_go,
+            );
+            self.search.evaluate_timed(&game, budget).0
+        } else {
+            self.search.evaluate(&game, DEFAULT_DEPTH).0
+        };
+
+        if let Some(mv) = mv {
+            self.play(mv, output)?;
+        }
+
+        Ok(())
+    }
+
+    fn play<W: Write>(&mut self, mv: Move, output: &mut W) -> io::Result<()> {
+        writeln!(output, "move {}", format_uci_move(&self.state, mv))?;
+        self.state.make_move(mv);
+        self.report_result(output)?;
+        Ok(())
+    }
+
+    // Writes out the result if the game has ended, and returns whether it has.
+    fn report_result<W: Write>(&self, output: &mut W) -> io::Result<bool> {
+        let result = match self.state.status(self.mbb) {
+            Status::Ongoing => return Ok(false),
+            Status::Checkmate => match self.state.active_colour {
+                Colour::White => "0-1 {Black mates}",
+                Colour::Black => "1-0 {White mates}",
+            },
+            Status::Stalemate => "1/2-1/2 {Stalemate}",
+            Status::FiftyMoveDraw => "1/2-1/2 {Fifty move rule}",
+            Status::InsufficientMaterial => "1/2-1/2 {Insufficient material}",
+            Status::ThreefoldRepetition => "1/2-1/2 {Threefold repetition}",
+        };
+
+        writeln!(output, "{}", result)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use chess_lib::fen::load_fen;
+    use chess_lib::fmt::parse_uci_move;
+    use chess_lib::magic::MagicBitBoards;
+    use crate::xboard::*;
+
+    fn run_script(mbb: &MagicBitBoards, script: &str) -> Vec<String> {
+        let mut output: Vec<u8> = vec![];
+        Xboard::new(mbb).run(Cursor::new(script), &mut output).unwrap();
+        String::from_utf8(output).unwrap().lines().map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn plays_legal_move() {
+        let mbb = MagicBitBoards::default();
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        let output = run_script(&mbb, &format!("xboard\nprotover 2\nmemory 1\nnew\nsd 3\nsetboard {}\ngo\nquit\n", fen));
+
+        assert!(output[0].starts_with("feature "));
+        assert_eq!(output.len(), 2, "{:?}", output);
+        let mv = output[1].strip_prefix("move ").unwrap();
+        assert!(parse_uci_move(&load_fen(fen), mv, &mbb).is_some(), "{}", mv);
+    }
+
+    #[test]
+    fn replies_to_user_moves() {
+        let mbb = MagicBitBoards::default();
+        let output = run_script(&mbb, "new\nst 0.1\nusermove e2e5\nusermove e2e4\nforce\nusermove d2d4\nquit\n");
+
+        // No reply to the move made in force mode.
+        assert_eq!(output.len(), 2, "{:?}", output);
+        assert_eq!(output[0], "Illegal move: e2e5");
+        assert!(output[1].starts_with("move "));
+    }
+
+    #[test]
+    fn results() {
+        let mbb = MagicBitBoards::default();
+
+        // Mate in one for White, and the engine is asked to move in a finished game.
+        let output = run_script(&mbb, "setboard 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1\nsd 2\ngo\ngo\n");
+        assert_eq!(output, vec!["move a1a8", "1-0 {White mates}", "1-0 {White mates}"]);
+
+        // The user delivers stalemate.
+        let output = run_script(&mbb, "setboard 7k/8/5QK1/8/8/8/8/8 w - - 0 1\nusermove f6f7\n");
+        assert_eq!(output, vec!["1/2-1/2 {Stalemate}"]);
+    }
+
+    #[test]
+    fn bad_input() {
+        let mbb = MagicBitBoards::default();
+
+        // The position is kept after a bad setboard, and bad times are ignored.
+        let script = "setboard 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1\nsetboard 6k1/5ppp/8/8/8/8/8/R5K1 x\nst -1\nst NaN\nsd 0\ngo\n";
+        let output = run_script(&mbb, script);
+        assert_eq!(output, vec!["tellusererror Illegal position", "move a1a8", "1-0 {White mates}"]);
+    }
+}
@@ -1,4 +1,5 @@
-use chess_lib::types::{Colour, GameState, Move, Piece};
+use chess_lib::fmt::{format_file, format_rank};
+use chess_lib::types::{BitCoord, Colour, GameState, Move, Piece};
 use tui::buffer::Buffer;
 use tui::layout::Rect;
 use tui::style;
@@ -8,11 +9,39 @@ use tui::widgets::{Block, Borders, Widget};
 pub struct ChessBoard {
     state: GameState,
     highlight_move: Option<Move>,
+    // Draw from Black's point of view, with a8 in the bottom right.
+    flipped: bool,
 }
 
 impl ChessBoard {
-    pub fn _with_highlight(state: GameState, highlight: Move) -> ChessBoard {
-        ChessBoard{ state, highlight_move: Some(highlight) }
+    pub fn new(state: GameState) -> ChessBoard {
+        ChessBoard{ state, highlight_move: None, flipped: false }
+    }
+
+    // The move should be the one that led to the state.
+    pub fn with_highlight(state: GameState, highlight: Move) -> ChessBoard {
+        ChessBoard{ state, highlight_move: Some(highlight), flipped: false }
+    }
+
+    pub fn flipped(mut self, flipped: bool) -> ChessBoard {
+        self.flipped = flipped;
+        self
+    }
+
+    // Squares to highlight for the last move, as (from, to).
+    fn highlight_squares(&self) -> Option<(BitCoord, BitCoord)> {
+        // The move has already been made, so the mover is the side not to move.
+        let (side, home_rank) = match self.state.active_colour {
+            Colour::White => (&self.state.black, 7u32),
+            Colour::Black => (&self.state.white, 0u32),
+        };
+
+        match self.highlight_move? {
+            Move::Normal(_, src, tgt) | Move::EnPassant(src, tgt) => Some((src, tgt)),
+            Move::Promotion(src, tgt, _) => Some((src, tgt)),
+            Move::Castle => Some(((side.king_file, home_rank).into(), (6u32, home_rank).into())),
+            Move::LongCastle => Some(((side.king_file, home_rank).into(), (2u32, home_rank).into())),
+        }
     }
 }
 
@@ -44,19 +73,31 @@ fn symbol_for_piece(piece: Piece) -> &'static str {
 impl Widget for ChessBoard {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let block = Block::default().title("Board").borders(Borders::ALL);
+        let inner = block.inner(area);
         block.render(area, buf);
 
+        // Leave a column on the left for rank labels and a row underneath for file labels.
         // Subtract 1 because there is one more border than squares.
-        let square_width = ((area.width - 1) / 8) - 1;
-        let square_height = ((area.height - 1) / 8) - 1;
+        let square_width = ((inner.width - 2) / 8) - 1;
+        let square_height = ((inner.height - 2) / 8) - 1;
 
         // 8 squares plus 9 borders.
         let board_width = square_width * 8 + 9;
         let board_height = square_height * 8 + 9;
 
-        // Center the board.
-        let board_x = area.x + (area.width - board_width) / 2;
-        let board_y = area.y + (area.height - board_height) / 2;
+        // Center the board and its labels.
+        let board_x = inner.x + 1 + (inner.width - 1 - board_width) / 2;
+        let board_y = inner.y + (inner.height - 1 - board_height) / 2;
+
+        // Screen column and row of a square, counted from the top left.
+        let flipped = self.flipped;
+        let to_screen = move |file: u32, rank: u32| -> (u16, u16) {
+            if flipped {
+                ((7 - file) as u16, rank as u16)
+            } else {
+                (file as u16, (7 - rank) as u16)
+            }
+        };
 
         // Draw borders row by row.
         for x in 0..board_width {
@@ -109,45 +150,38 @@ impl Widget for ChessBoard {
             }
         }
 
-        // Draw pieces in the center of the squares.
-        for file in 0..8u32 {
-            for rank in 0..8u32 {
-                match self.state.find_piece((file, rank).into()) {
-                    Some((colour, piece)) => {
-                        let symbol = symbol_for_piece(piece);
-                        let colour = match colour {
-                            Colour::White => style::Color::White,
-                            Colour::Black => style::Color::Blue,
-                        };
-                        let x = (file as u16 * (square_width + 1)) + (square_width / 2) + 1;
-                        let y = ((7 - rank) as u16 * (square_height + 1)) + (square_height / 2) + 1;
-                        buf.get_mut(board_x + x, board_y + y).set_symbol(symbol).set_fg(colour);
-                    },
-                    None => (),
-                }
-            }
+        // Label files along the bottom and ranks down the left.
+        for i in 0..8u32 {
+            let (col, row) = to_screen(i, i);
+            let x = (col * (square_width + 1)) + (square_width / 2) + 1;
+            let y = (row * (square_height + 1)) + (square_height / 2) + 1;
+            buf.get_mut(board_x + x, board_y + board_height).set_symbol(&format_file(i).to_string());
+            buf.get_mut(board_x - 1, board_y + y).set_symbol(&format_rank(i).to_string());
         }
 
-        // Highlight the given move.
-        match self.highlight_move {
-            Some(Move::Normal(_, src, tgt)) => {
-                let mut f = src.file() as u16;
-                let mut r = src.rank() as u16;
-                for x in (f * (square_width + 1) + 1)..((f + 1) * (square_width + 1)) {
-                    for y in ((7 - r) * (square_height + 1) + 1)..((7 - r + 1) * (square_height + 1)) {
-                        buf.get_mut(board_x + x, board_y + y).set_bg(style::Color::Yellow);
+        // Highlight the last move, with different colours for where it came from and went to.
+        if let Some((src, tgt)) = self.highlight_squares() {
+            for (coord, colour) in [(src, style::Color::Yellow), (tgt, style::Color::Green)].iter() {
+                let (col, row) = to_screen(coord.file(), coord.rank());
+                for x in (col * (square_width + 1) + 1)..((col + 1) * (square_width + 1)) {
+                    for y in (row * (square_height + 1) + 1)..((row + 1) * (square_height + 1)) {
+                        buf.get_mut(board_x + x, board_y + y).set_bg(*colour);
                     }
                 }
+            }
+        }
 
-                f = tgt.file() as u16;
-                r = tgt.rank() as u16;
-                for x in (f * (square_width + 1) + 1)..((f + 1) * (square_width + 1)) {
-                    for y in ((7 - r) * (square_height + 1) + 1)..((7 - r + 1) * (square_height + 1)) {
-                        buf.get_mut(board_x + x, board_y + y).set_bg(style::Color::Yellow);
-                    }
-                }
-            },
-            _ => (),
-        };
+        // Draw pieces in the center of the squares.
+        for (coord, colour, piece) in self.state.iter_pieces() {
+            let symbol = symbol_for_piece(piece);
+            let colour = match colour {
+                Colour::White => style::Color::White,
+                Colour::Black => style::Color::Blue,
+            };
+            let (col, row) = to_screen(coord.file(), coord.rank());
+            let x = (col * (square_width + 1)) + (square_width / 2) + 1;
+            let y = (row * (square_height + 1)) + (square_height / 2) + 1;
+            buf.get_mut(board_x + x, board_y + y).set_symbol(symbol).set_fg(colour);
+        }
     }
 }
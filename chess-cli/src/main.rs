@@ -1,11 +1,14 @@
 use std::io;
+use std::io::{BufRead, Write};
 use std::fs;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use clap::{AppSettings, Clap};
 use crossbeam::channel;
 use threadpool::ThreadPool;
+use tui::Terminal;
+use tui::backend::TermionBackend;
+use tui::layout::Rect;
 
 mod board;
 
@@ -20,8 +23,13 @@ struct Opts {
 #[derive(Clap)]
 enum SubCommand {
     Analyze(Analyze),
+    Bench(Bench),
     Divide(Divide),
     Magic(Magic),
+    PerftSuite(PerftSuite),
+    Play(Play),
+    Uci,
+    Xboard,
 }
 
 #[derive(Clap)]
@@ -31,6 +39,13 @@ struct Divide {
 
     #[clap(short, long)]
     depth: u8,
+
+    #[clap(short, long)]
+    workers: Option<usize>,
+
+    // Print moves in UCI notation, as Stockfish's "go perft" does.
+    #[clap(long)]
+    uci: bool,
 }
 
 #[derive(Clap)]
@@ -45,6 +60,43 @@ struct Analyze {
     tt_bits: Option<u8>,
 }
 
+#[derive(Clap)]
+struct Bench {
+    #[clap(short, long, default_value = "6")]
+    depth: u32,
+
+    #[clap(short, long, default_value = "20")]
+    tt_bits: u8,
+}
+
+#[derive(Clap)]
+struct PerftSuite {
+    // EPD lines like "<fen> ;D1 20 ;D2 400".
+    #[clap(short, long)]
+    file: String,
+
+    // Skip entries deeper than this.
+    #[clap(short, long, default_value = "255")]
+    depth: u8,
+}
+
+#[derive(Clap)]
+struct Play {
+    #[clap(short, long)]
+    fen: Option<String>,
+
+    #[clap(short, long, default_value = "5")]
+    depth: u32,
+
+    // Milliseconds per engine move. Overrides --depth.
+    #[clap(short, long)]
+    movetime: Option<u64>,
+
+    // The colour the human plays.
+    #[clap(short, long, default_value = "white")]
+    colour: String,
+}
+
 #[derive(Clap)]
 struct Magic {
     #[clap(short, long)]
@@ -58,23 +110,35 @@ struct Magic {
 
     #[clap(short, long)]
     sourcefile: Option<String>,
+
+    #[clap(short, long)]
+    out: Option<String>,
 }
 
 fn main() -> Result<(), io::Error> {
     let opts: Opts = Opts::parse();
     match opts.subcmd {
         SubCommand::Divide(div) => {
+            if div.depth > chess_lib::perft::MAX_PERFT_DEPTH {
+                let msg = format!("Depth must be at most {}", chess_lib::perft::MAX_PERFT_DEPTH);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+
             let state = chess_lib::fen::load_fen(&div.fen);
-            let mbb = chess_lib::magic::MagicBitBoards::default();
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
 
             let before = Instant::now();
-            let counts = chess_lib::perft::divide(&state, div.depth, &mbb);
+            let counts = match div.workers {
+                Some(workers) => chess_lib::perft::divide_parallel_moves(&state, div.depth, mbb, workers),
+                None => chess_lib::perft::divide_moves(&state, div.depth, mbb),
+            };
             let after = Instant::now();
 
-            let mut lines: Vec<String> = counts.iter().map(|(k, v)| {
-                return format!("{}: {}", k, v);
-            }).collect();
-            lines.sort();
+            let lines: Vec<String> = if div.uci {
+                chess_lib::perft::format_divide_uci(&state, &counts)
+            } else {
+                counts.iter().map(|(m, c)| format!("{}: {}", chess_lib::fmt::format_move(*m), c)).collect()
+            };
             lines.iter().for_each(|l| println!("{}", l));
             println!("Total nodes: {}", counts.iter().map(|(_, v)| *v).sum::<u64>());
 
@@ -84,8 +148,8 @@ fn main() -> Result<(), io::Error> {
         },
         SubCommand::Analyze(cmd) => {
             let state = chess_lib::fen::load_fen(&cmd.fen);
-            let mbb = chess_lib::magic::MagicBitBoards::default();
-            let chess = chess_ai::chess::Chess::new(state, &mbb);
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
+            let chess = chess_ai::chess::Chess::new(state, mbb);
             let tt_size = 1 << cmd.tt_bits.unwrap_or(28);
             let mut alphabeta = chess_ai::minimax::AlphaBeta::new(chess_ai::eval::evaluate, tt_size);
 
@@ -93,7 +157,10 @@ fn main() -> Result<(), io::Error> {
             let (mv, score) = alphabeta.evaluate(&chess, cmd.depth);
             let after = Instant::now();
 
-            println!("{}: {:.2}", chess_lib::fmt::format_move(mv), (score as f64) / 100f64);
+            match mv {
+                Some(mv) => println!("{}: {:.2}", chess_lib::fmt::format_move(mv), (score as f64) / 100f64),
+                None => println!("No legal moves: {:.2}", (score as f64) / 100f64),
+            }
 
             let duration = after - before;
             println!("Took: {}s", duration.as_secs_f32());
@@ -104,8 +171,49 @@ fn main() -> Result<(), io::Error> {
 
             Ok(())
         },
+        SubCommand::Bench(cmd) => {
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
+            let result = chess_ai::bench::run_bench(cmd.depth, 1 << cmd.tt_bits, mbb);
+
+            println!("Total nodes: {}", result.nodes);
+            println!("Took: {}s", result.time.as_secs_f32());
+            println!("Nodes/second: {}", result.nps);
+            Ok(())
+        },
+        SubCommand::PerftSuite(cmd) => {
+            let text = fs::read_to_string(&cmd.file)?;
+            let cases = chess_lib::perft::parse_perft_suite(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
+
+            let before = Instant::now();
+            let mismatches = chess_lib::perft::run_perft_suite(&cases, cmd.depth, mbb);
+            let after = Instant::now();
+
+            mismatches.iter().for_each(|m| println!("MISMATCH: {}", m));
+            println!("{} positions, {} mismatches", cases.len(), mismatches.len());
+
+            let duration = after - before;
+            println!("Took: {}s", duration.as_secs_f32());
+
+            if !mismatches.is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        SubCommand::Play(cmd) => play(cmd),
+        SubCommand::Uci => {
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
+            let stdin = io::stdin();
+            chess_ai::uci::Uci::new(mbb, io::stdout()).run(stdin.lock())
+        },
+        SubCommand::Xboard => {
+            let mbb = chess_lib::magic::MagicBitBoards::shared();
+            let stdin = io::stdin();
+            chess_ai::xboard::Xboard::new(mbb).run(stdin.lock(), &mut io::stdout())
+        },
         SubCommand::Magic(cmd) => {
-            let default_bbs = chess_lib::magic::MagicBitBoards::default();
+            let default_bbs = chess_lib::magic::MagicBitBoards::shared();
             let (maskgen, movegen): (
                 fn (chess_lib::types::BitCoord) -> chess_lib::types::BitBoard,
                 fn (chess_lib::types::BitCoord, chess_lib::types::BitBoard) -> chess_lib::types::BitBoard,
@@ -131,6 +239,9 @@ fn main() -> Result<(), io::Error> {
             let pool = ThreadPool::new(cmd.workers);
 
             let sourcefile = cmd.sourcefile;
+            let out = cmd.out;
+            let is_rook = cmd.piece == "rook";
+            let (mut rook_magics, mut bishop_magics) = default_bbs.magics();
 
             println!("=== {} (iterations={}, workers={}) ===", cmd.piece, cmd.iterations.unwrap_or(0), cmd.workers);
             let mut iteration = 0;
@@ -146,27 +257,14 @@ fn main() -> Result<(), io::Error> {
                     let tx = result_tx.clone();
                     pool.execute(move|| {
                         let coord = chess_lib::types::BitCoord(1 << c);
-                        let mask = maskgen(coord);
-                        let moves = chess_lib::magic::generate_moves(coord, mask, movegen);
-                        let mut boards_cache: HashMap<chess_lib::types::BitBoard, Vec<chess_lib::types::BitBoard>> = HashMap::new();
-
-                        for _ in 0..batch_size {
-                            let magic = rand::random::<u64>();
-                            match chess_lib::magic::Magic::generate(magic, mask, &moves, &mut boards_cache, best.size() - 1) {
-                                Some(m) => {
-                                    let size = m.size();
-                                    if size < best.size() {
-                                        tx.send((c, m)).expect("able to report results");
-                                        return;
-                                    }
-                                },
-                                None => (),
-                            }
-                        }
-                        tx.send((c, best)).expect("able to report results");
+                        match chess_lib::magic::find_magic(coord, maskgen(coord), movegen, batch_size as u32) {
+                            Some(m) if m.size() < best.size() => tx.send((c, m)),
+                            _ => tx.send((c, best)),
+                        }.expect("able to report results");
                     });
                 }
 
+                let mut improved = false;
                 result_rx.iter().take(64).for_each(|(c, m)| {
                     if m.size() < bests[c].size() {
                         println!("(0x{:016x}, {}),  // {}[{}] !! (was {})", m.magic(), 64 - m.shift(), c, m.size(), bests[c].size());
@@ -174,10 +272,22 @@ fn main() -> Result<(), io::Error> {
                         if sourcefile.is_some() {
                             update_magic(sourcefile.clone().unwrap(), c, &bests[c], &m);
                         }
+                        if is_rook {
+                            rook_magics[c] = (m.magic(), 64 - m.shift());
+                        } else {
+                            bishop_magics[c] = (m.magic(), 64 - m.shift());
+                        }
                         bests[c] = m;
+                        improved = true;
                     }
                 });
 
+                if improved {
+                    if let Some(out) = &out {
+                        chess_lib::magic::MagicBitBoards::generate(rook_magics, bishop_magics).save(out)?;
+                    }
+                }
+
                 let duration = iteration_start.elapsed();
                 let per_second = (64 * batch_size * 1000) / duration.as_millis();
 
@@ -195,6 +305,97 @@ fn main() -> Result<(), io::Error> {
     }
 }
 
+// Board size which gives 5x2 character squares, with room for the labels.
+const BOARD_WIDTH: u16 = 52;
+const BOARD_HEIGHT: u16 = 28;
+
+fn play(cmd: Play) -> Result<(), io::Error> {
+    let mbb = chess_lib::magic::MagicBitBoards::shared();
+    let mut state = chess_lib::fen::load_fen(cmd.fen.as_deref().unwrap_or(chess_lib::fen::STARTING_POSITION));
+    let human = match cmd.colour.as_str() {
+        "white" | "w" => chess_lib::types::Colour::White,
+        "black" | "b" => chess_lib::types::Colour::Black,
+        _ => panic!("Unknown colour: {}", cmd.colour),
+    };
+    let mut alphabeta = chess_ai::minimax::AlphaBeta::new(chess_ai::eval::evaluate, 1 << 24);
+
+    let mut terminal = Terminal::new(TermionBackend::new(io::stdout()))?;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut last_move: Option<chess_lib::types::Move> = None;
+    let mut message = String::new();
+
+    loop {
+        terminal.clear()?;
+        terminal.draw(|f| {
+            let size = f.size();
+            let area = Rect::new(0, 0, BOARD_WIDTH.min(size.width), BOARD_HEIGHT.min(size.height));
+            let board = match last_move {
+                Some(mv) => board::ChessBoard::with_highlight(state.clone(), mv),
+                None => board::ChessBoard::new(state.clone()),
+            };
+            f.render_widget(board.flipped(human == chess_lib::types::Colour::Black), area);
+        })?;
+        terminal.set_cursor(0, BOARD_HEIGHT)?;
+        terminal.show_cursor()?;
+
+        if !message.is_empty() {
+            println!("{}", message);
+        }
+
+        match state.status(mbb) {
+            chess_lib::types::Status::Ongoing => (),
+            chess_lib::types::Status::Checkmate => {
+                println!("Checkmate, {:?} wins.", chess_lib::types::Colour::other(state.active_colour));
+                return Ok(());
+            },
+            status => {
+                println!("Draw: {:?}.", status);
+                return Ok(());
+            },
+        }
+
+        if state.active_colour == human {
+            print!("Your move: ");
+            io::stdout().flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let input = line.trim();
+            if input == "quit" {
+                return Ok(());
+            }
+
+            let mv = chess_lib::pgn::parse_san(&state, input, mbb)
+                .or_else(|| chess_lib::fmt::parse_uci_move(&state, input, mbb))
+                .filter(|mv| state.is_legal_move(*mv, mbb));
+            match mv {
+                Some(mv) => {
+                    message = format!("You played {}", chess_lib::pgn::PGNMove::from_internal(&state, mv, mbb));
+                    state.make_move(mv);
+                    last_move = Some(mv);
+                },
+                None => message = format!("Illegal move: {}", input),
+            }
+        } else {
+            let chess = chess_ai::chess::Chess::new(state.clone(), mbb);
+            let (mv, score) = match cmd.movetime {
+                Some(movetime) => {
+                    let (mv, score, _) = alphabeta.evaluate_timed(&chess, Duration::from_millis(movetime));
+                    (mv, score)
+                },
+                None => alphabeta.evaluate(&chess, cmd.depth),
+            };
+            // The game ends before the engine is asked to move in a finished position.
+            let mv = mv.expect("Engine has no legal moves");
+            message = format!("Engine played {} ({:.2})", chess_lib::pgn::PGNMove::from_internal(&state, mv, mbb), (score as f64) / 100f64);
+            state.make_move(mv);
+            last_move = Some(mv);
+        }
+    }
+}
+
 fn update_magic(sourcefile: String, c: usize, prev: &chess_lib::magic::Magic, new: &chess_lib::magic::Magic) {
     let text = fs::read_to_string(&sourcefile).expect("Failed to read magic source file");
 
@@ -1,12 +1,47 @@
-use crate::fmt::parse_coord;
-use crate::types::{BitCoord, GameState, Colour, Piece, Pieces, SideState};
+use std::fmt;
+use std::fmt::Display;
+use crate::fmt::{format_coord, format_file, format_piece, parse_file};
+use crate::types::{BitBoard, BitCoord, GameState, Colour, MaterialScore, Piece, Pieces, SideState};
 
 pub const STARTING_POSITION: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FenParseError {
+    MissingPieces,
+    InvalidPiece(char),
+    InvalidBoard(String),
+    WrongKingCount(Colour),
+    InvalidActiveColour(String),
+    InvalidCastling(String),
+    InvalidEnPassant(String),
+}
+
+impl Display for FenParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FenParseError::MissingPieces => write!(f, "FEN string didn't contain piece positions"),
+            FenParseError::InvalidPiece(c) => write!(f, "Unexpected character in piece positions: {}", c),
+            FenParseError::InvalidBoard(board) => write!(f, "Piece positions should be 8 ranks of 8 squares: {}", board),
+            FenParseError::WrongKingCount(colour) => write!(f, "{:?} should have exactly one king", colour),
+            FenParseError::InvalidActiveColour(field) => write!(f, "Invalid active colour field: {}", field),
+            FenParseError::InvalidCastling(field) => write!(f, "Invalid castling field: {}", field),
+            FenParseError::InvalidEnPassant(field) => write!(f, "Invalid en-passant field: {}", field),
+        }
+    }
+}
+
+impl std::error::Error for FenParseError {}
+
+// Panics if the FEN is invalid. Use parse_fen for input which might be.
 pub fn load_fen(fen: &str) -> GameState {
+    parse_fen(fen).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn parse_fen(fen: &str) -> Result<GameState, FenParseError> {
     let mut fields = fen.split_whitespace();
 
-    let positions = fields.next().expect("FEN string didn't contain piece positions");
+    let positions = fields.next().ok_or(FenParseError::MissingPieces)?;
+    check_board_shape(positions)?;
 
     let mut w_pieces = Pieces::empty();
     let mut b_pieces = Pieces::empty();
@@ -36,63 +71,222 @@ pub fn load_fen(fen: &str) -> GameState {
                 coord = coord >> num_empty;
                 continue
             },
-            _ => panic!("Unexpected character in piece positions: {}", c),
+            _ => return Err(FenParseError::InvalidPiece(c)),
         }
 
         coord = coord >> 1;
     }
 
+    if w_pieces.king.count() != 1 {
+        return Err(FenParseError::WrongKingCount(Colour::White));
+    }
+    if b_pieces.king.count() != 1 {
+        return Err(FenParseError::WrongKingCount(Colour::Black));
+    }
+
     // Initialize side states from board.
     let mut white = SideState{
         pieces: w_pieces,
+        score: MaterialScore::default(),
         can_castle_kingside: false,
         can_castle_queenside: false,
+        king_file: 4,
+        kingside_rook_file: 7,
+        queenside_rook_file: 0,
     };
 
     let mut black = SideState{
         pieces: b_pieces,
+        score: MaterialScore::default(),
         can_castle_kingside: false,
         can_castle_queenside: false,
+        king_file: 4,
+        kingside_rook_file: 7,
+        queenside_rook_file: 0,
     };
 
-    let active_colour_field = fields.next().expect("FEN string didn't contain active colour");
+    white.king_file = king_file(&white);
+    black.king_file = king_file(&black);
+
+    // Everything after the piece positions may be left off, as some puzzle tools do.
+    // White moves if not otherwise stated, and there's no castling or en-passant.
+    let active_colour_field = fields.next().unwrap_or("w");
     let active_colour = match active_colour_field {
         "w" => Colour::White,
         "b" => Colour::Black,
-        _ => panic!("Invalid active colour field: {}", active_colour_field),
+        _ => return Err(FenParseError::InvalidActiveColour(active_colour_field.to_owned())),
     };
 
-    let castling_field = fields.next().expect("FEN string didn't contain castling");
+    // Accepts standard KQkq, as well as Shredder-FEN/X-FEN file letters for Chess960.
+    // e.g. HAha, or GEge.
+    let castling_field = fields.next().unwrap_or("-");
     for c in castling_field.chars() {
         match c {
-            'K' => white.can_castle_kingside = true,
-            'Q' => white.can_castle_queenside = true,
-            'k' => black.can_castle_kingside = true,
-            'q' => black.can_castle_queenside = true,
+            'K' => set_castling_right(&mut white, 0, None, true),
+            'Q' => set_castling_right(&mut white, 0, None, false),
+            'k' => set_castling_right(&mut black, 7, None, true),
+            'q' => set_castling_right(&mut black, 7, None, false),
+            'A'..='H' => {
+                let file = parse_file(c.to_ascii_lowercase());
+                let king_file = king_file(&white);
+                set_castling_right(&mut white, 0, Some(file), file > king_file);
+            },
+            'a'..='h' => {
+                let file = parse_file(c);
+                let king_file = king_file(&black);
+                set_castling_right(&mut black, 7, Some(file), file > king_file);
+            },
             '-' => (),
-            _ => panic!("Invalid castling field: {}", castling_field),
+            _ => return Err(FenParseError::InvalidCastling(castling_field.to_owned())),
         }
     }
 
-    let en_passant_field = fields.next().expect("FEN string didn't contain en-passant");
+    let en_passant_field = fields.next().unwrap_or("-");
     let en_passant = match en_passant_field {
         "-" => None,
-        _ => Some(parse_coord(en_passant_field)),
+        _ => Some(en_passant_field.parse().map_err(|_| FenParseError::InvalidEnPassant(en_passant_field.to_owned()))?),
     };
 
-    GameState::new(
+    let fifty_move_clock = fields.next().and_then(|f| f.parse().ok()).unwrap_or(0);
+    let fullmove_number = fields.next().and_then(|f| f.parse().ok()).unwrap_or(1);
+
+    let mut state = GameState::new(
         active_colour,
         white,
         black,
         en_passant,
-        0,
-    )
+        fifty_move_clock,
+    );
+    state.set_fullmove_number(fullmove_number);
+    Ok(state)
+}
+
+// Checked up front, since the board is filled in by shifting along a single bit and running off
+// the end would silently drop pieces.
+fn check_board_shape(positions: &str) -> Result<(), FenParseError> {
+    let ranks: Vec<&str> = positions.split('/').collect();
+    let rank_ok = |rank: &&str| {
+        let squares: u32 = rank.chars().map(|c| c.to_digit(10).unwrap_or(1)).sum();
+        squares == 8
+    };
+
+    if ranks.len() == 8 && ranks.iter().all(rank_ok) {
+        Ok(())
+    } else {
+        Err(FenParseError::InvalidBoard(positions.to_owned()))
+    }
+}
+
+pub fn to_fen(state: &GameState) -> String {
+    let mut positions = String::new();
+    for rank in (0..8u32).rev() {
+        let mut num_empty = 0;
+        for file in 0..8u32 {
+            match state.find_piece((file, rank).into()) {
+                Some((colour, pc)) => {
+                    if num_empty > 0 {
+                        positions.push_str(&num_empty.to_string());
+                        num_empty = 0;
+                    }
+                    match colour {
+                        Colour::White => positions.push(format_piece(pc)),
+                        Colour::Black => positions.push(format_piece(pc).to_ascii_lowercase()),
+                    }
+                },
+                None => num_empty += 1,
+            }
+        }
+
+        if num_empty > 0 {
+            positions.push_str(&num_empty.to_string());
+        }
+
+        if rank > 0 {
+            positions.push('/');
+        }
+    }
+
+    let active_colour = match state.active_colour {
+        Colour::White => "w",
+        Colour::Black => "b",
+    };
+
+    let mut castling = String::new();
+    castling.push_str(&castling_rights(&state.white, 'K', 'Q'));
+    castling.push_str(&castling_rights(&state.black, 'k', 'q'));
+    if castling.is_empty() {
+        castling.push('-');
+    }
+
+    let en_passant = match state.en_passant {
+        Some(ep) => format_coord(ep),
+        None => "-".to_owned(),
+    };
+
+    format!("{} {} {} {} {} {}", positions, active_colour, castling, en_passant, state.fifty_move_clock, state.fullmove_number())
+}
+
+// Uses KQkq where possible, otherwise falls back to Shredder-FEN file letters.
+fn castling_rights(side: &SideState, kingside: char, queenside: char) -> String {
+    let is_white = kingside.is_ascii_uppercase();
+    let format = |file: u32, standard: char| -> char {
+        if file == if standard == kingside { 7 } else { 0 } {
+            standard
+        } else if is_white {
+            format_file(file).to_ascii_uppercase()
+        } else {
+            format_file(file)
+        }
+    };
+
+    let mut rights = String::new();
+    if side.can_castle_kingside {
+        rights.push(format(side.kingside_rook_file, kingside));
+    }
+    if side.can_castle_queenside {
+        rights.push(format(side.queenside_rook_file, queenside));
+    }
+    rights
+}
+
+fn set_castling_right(side: &mut SideState, home_rank: u32, file: Option<u32>, kingside: bool) {
+    // For plain K/Q, the right refers to the outermost rook on that side of the king.
+    let rook_file = file.unwrap_or_else(|| outermost_rook_file(side, home_rank, kingside));
+
+    if kingside {
+        side.can_castle_kingside = true;
+        side.kingside_rook_file = rook_file;
+    } else {
+        side.can_castle_queenside = true;
+        side.queenside_rook_file = rook_file;
+    }
+}
+
+fn outermost_rook_file(side: &SideState, home_rank: u32, kingside: bool) -> u32 {
+    let king_file = king_file(side);
+    let files: Vec<u32> = if kingside {
+        ((king_file + 1)..8).rev().collect()
+    } else {
+        (0..king_file).collect()
+    };
+
+    files.into_iter()
+        .find(|f| side.pieces.rooks & BitCoord::from((*f, home_rank)) != BitBoard::EMPTY)
+        .unwrap_or(if kingside { 7 } else { 0 })
+}
+
+fn king_file(side: &SideState) -> u32 {
+    if side.pieces.king == BitBoard::EMPTY {
+        4
+    } else {
+        BitCoord(side.pieces.king.0).file()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::fen::*;
-    use crate::types::BitBoard;
+    use crate::types::{BitBoard, Colour};
 
     #[test]
     fn starting_position() {
@@ -117,5 +311,95 @@ mod tests {
         assert_eq!(state.white.can_castle_queenside, true);
         assert_eq!(state.black.can_castle_kingside, true);
         assert_eq!(state.black.can_castle_queenside, true);
+
+        assert_eq!(state.white.kingside_rook_file, 7);
+        assert_eq!(state.white.queenside_rook_file, 0);
+        assert_eq!(state.black.kingside_rook_file, 7);
+        assert_eq!(state.black.queenside_rook_file, 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR w KQkq c6 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "bqnbrkrn/pppppppp/8/8/8/8/PPPPPPPP/BQNBRKRN b GEg - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 b - - 12 10",
+        ];
+
+        for fen in fens.iter() {
+            assert_eq!(to_fen(&load_fen(fen)), *fen);
+        }
+    }
+
+    #[test]
+    fn truncated_fen() {
+        let state = load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b");
+        assert_eq!(state, load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b - - 0 1"));
+        assert!(!state.white.can_castle_kingside && !state.white.can_castle_queenside);
+        assert!(!state.black.can_castle_kingside && !state.black.can_castle_queenside);
+
+        // With just the pieces, white is to move.
+        assert_eq!(load_fen("4k3/8/8/8/8/8/8/4K3"), load_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1"));
+    }
+
+    #[test]
+    fn invalid_fens_are_errors() {
+        assert_eq!(parse_fen(""), Err(FenParseError::MissingPieces));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/8/4K2X w - - 0 1"), Err(FenParseError::InvalidPiece('X')));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/4K3 w - - 0 1"), Err(FenParseError::InvalidBoard("4k3/8/8/8/8/8/4K3".to_owned())));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/8/4K4 w - - 0 1"), Err(FenParseError::InvalidBoard("4k3/8/8/8/8/8/8/4K4".to_owned())));
+        assert_eq!(parse_fen("8/8/8/8/8/8/8/4K3 w - - 0 1"), Err(FenParseError::WrongKingCount(Colour::Black)));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/8/4K3 x - - 0 1"), Err(FenParseError::InvalidActiveColour("x".to_owned())));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/8/4K3 w KX - 0 1"), Err(FenParseError::InvalidCastling("KX".to_owned())));
+        assert_eq!(parse_fen("4k3/8/8/8/8/8/8/4K3 w - e9 0 1"), Err(FenParseError::InvalidEnPassant("e9".to_owned())));
+
+        assert_eq!(parse_fen(STARTING_POSITION), Ok(load_fen(STARTING_POSITION)));
+    }
+
+    #[test]
+    fn shredder_castling_standard() {
+        let state = load_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w HAha - 0 1");
+
+        assert_eq!(state.white.can_castle_kingside, true);
+        assert_eq!(state.white.can_castle_queenside, true);
+        assert_eq!(state.black.can_castle_kingside, true);
+        assert_eq!(state.black.can_castle_queenside, true);
+
+        assert_eq!(state.white.kingside_rook_file, 7);
+        assert_eq!(state.white.queenside_rook_file, 0);
+        assert_eq!(state.black.kingside_rook_file, 7);
+        assert_eq!(state.black.queenside_rook_file, 0);
+
+        // Same position as the standard notation.
+        assert_eq!(state, load_fen(STARTING_POSITION));
+    }
+
+    #[test]
+    fn shredder_castling_960() {
+        let state = load_fen("bqnbrkrn/pppppppp/8/8/8/8/PPPPPPPP/BQNBRKRN w GEg - 0 1");
+
+        assert_eq!(state.white.can_castle_kingside, true);
+        assert_eq!(state.white.can_castle_queenside, true);
+        assert_eq!(state.black.can_castle_kingside, true);
+        assert_eq!(state.black.can_castle_queenside, false);
+
+        assert_eq!(state.white.kingside_rook_file, 6);
+        assert_eq!(state.white.queenside_rook_file, 4);
+        assert_eq!(state.black.kingside_rook_file, 6);
+    }
+
+    #[test]
+    fn xfen_castling_960() {
+        // X-FEN uses KQkq to refer to the outermost rooks.
+        let state = load_fen("bqnbrkrn/pppppppp/8/8/8/8/PPPPPPPP/BQNBRKRN w KQkq - 0 1");
+
+        assert_eq!(state.white.kingside_rook_file, 6);
+        assert_eq!(state.white.queenside_rook_file, 4);
+        assert_eq!(state.black.kingside_rook_file, 6);
+        assert_eq!(state.black.queenside_rook_file, 4);
     }
 }
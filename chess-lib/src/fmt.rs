@@ -1,3 +1,5 @@
+use crate::magic::MagicBitBoards;
+use crate::moves::legal_moves;
 use crate::types::{BitCoord, Colour, GameState, Move, Piece};
 
 impl std::fmt::Display for GameState {
@@ -8,27 +10,29 @@ impl std::fmt::Display for GameState {
 }
 
 pub fn format_board(state: &GameState, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-    writeln!(formatter, " ------------------------------- ")?;
     for rank in (0..8u32).rev() {
-        write!(formatter, "|")?;
+        write!(formatter, "{}", format_rank(rank))?;
         for file in 0..8u32 {
             let coord: BitCoord = (file, rank).into();
-            write!(formatter, " ")?;
             match state.find_piece(coord) {
-                Some((c, pc)) => {
-                    match c {
-                        Colour::White => write!(formatter, "{}", format_piece(pc))?,
-                        Colour::Black => write!(formatter, "{}", format_piece(pc).to_lowercase())?,
-                    }
-                },
-                None => write!(formatter, " ")?,
+                Some((Colour::White, pc)) => write!(formatter, " {}", format_piece(pc))?,
+                Some((Colour::Black, pc)) => write!(formatter, " {}", format_piece(pc).to_ascii_lowercase())?,
+                None => write!(formatter, " .")?,
             }
-            write!(formatter, " |")?;
         }
         writeln!(formatter)?;
-        writeln!(formatter, " ------------------------------- ")?;
     }
-    Ok(())
+
+    write!(formatter, " ")?;
+    for file in 0..8u32 {
+        write!(formatter, " {}", format_file(file))?;
+    }
+    writeln!(formatter)?;
+
+    match state.active_colour {
+        Colour::White => writeln!(formatter, "White to move"),
+        Colour::Black => writeln!(formatter, "Black to move"),
+    }
 }
 
 pub fn format_piece(piece: Piece) -> char {
@@ -49,6 +53,33 @@ impl std::fmt::Debug for Move {
     }
 }
 
+// e.g. "e2e4", "e7e8Q", or "O-O".
+impl std::fmt::Display for Move {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "{}", format_move(*self))
+    }
+}
+
+impl Move {
+    // Long algebraic notation as used by UCI, e.g. "e7e8q". Castles need to know whose move it
+    // is, since they're written as the king's move.
+    pub fn to_uci(self, colour: Colour) -> String {
+        let home_rank = match colour {
+            Colour::White => 0,
+            Colour::Black => 7,
+        };
+
+        match self {
+            Move::Normal(_, src, tgt) | Move::EnPassant(src, tgt) => format!("{}{}", format_coord(src), format_coord(tgt)),
+            Move::Promotion(src, tgt, pc) => {
+                format!("{}{}{}", format_coord(src), format_coord(tgt), format_piece(pc).to_ascii_lowercase())
+            },
+            Move::Castle => format!("{}{}", format_coord((4u32, home_rank).into()), format_coord((6u32, home_rank).into())),
+            Move::LongCastle => format!("{}{}", format_coord((4u32, home_rank).into()), format_coord((2u32, home_rank).into())),
+        }
+    }
+}
+
 impl std::fmt::Debug for BitCoord {
     fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(formatter, "{}", format_coord(*self))?;
@@ -56,9 +87,56 @@ impl std::fmt::Debug for BitCoord {
     }
 }
 
+impl std::fmt::Display for BitCoord {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "{}", format_coord(*self))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoordParseError {
+    WrongLength(String),
+    InvalidFile(char),
+    InvalidRank(char),
+}
+
+impl std::fmt::Display for CoordParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoordParseError::WrongLength(s) => write!(f, "Square should be two characters: {}", s),
+            CoordParseError::InvalidFile(c) => write!(f, "Invalid file: {}", c),
+            CoordParseError::InvalidRank(c) => write!(f, "Invalid rank: {}", c),
+        }
+    }
+}
+
+impl std::error::Error for CoordParseError {}
+
+impl std::str::FromStr for BitCoord {
+    type Err = CoordParseError;
+
+    fn from_str(s: &str) -> Result<BitCoord, CoordParseError> {
+        let cs: Vec<char> = s.chars().collect();
+        if cs.len() != 2 {
+            return Err(CoordParseError::WrongLength(s.to_owned()));
+        }
+
+        let file = match cs[0] {
+            c @ 'a'..='h' => parse_file(c),
+            c => return Err(CoordParseError::InvalidFile(c)),
+        };
+        let rank = match cs[1] {
+            c @ '1'..='8' => parse_rank(c),
+            c => return Err(CoordParseError::InvalidRank(c)),
+        };
+
+        Ok((file, rank).into())
+    }
+}
+
 pub fn format_move(m: Move) -> String {
     match m {
-        Move::Normal(_, src, tgt) => {
+        Move::Normal(_, src, tgt) | Move::EnPassant(src, tgt) => {
             let s = format!("{}{}", format_coord(src), format_coord(tgt));
             s
         },
@@ -71,6 +149,49 @@ pub fn format_move(m: Move) -> String {
     }
 }
 
+// Long algebraic notation as used by UCI, e.g. "e2e4", "e7e8q", "e1g1".
+pub fn format_uci_move(state: &GameState, mv: Move) -> String {
+    mv.to_uci(state.active_colour)
+}
+
+pub fn parse_uci_move(state: &GameState, s: &str, mbb: &MagicBitBoards) -> Option<Move> {
+    if !s.is_ascii() || (s.len() != 4 && s.len() != 5) {
+        return None;
+    }
+
+    let src = try_parse_coord(&s[0..2])?;
+    let tgt = try_parse_coord(&s[2..4])?;
+
+    let side = match state.active_colour {
+        Colour::White => &state.white,
+        Colour::Black => &state.black,
+    };
+    let piece = side.pieces.get_piece(src)?;
+
+    let mv = match s[4..].chars().next() {
+        Some(c) => {
+            let promote_to = match c {
+                'q' => Piece::Queen,
+                'r' => Piece::Rook,
+                'b' => Piece::Bishop,
+                'n' => Piece::Knight,
+                _ => return None,
+            };
+            Move::Promotion(src, tgt, promote_to)
+        },
+        None if piece == Piece::King && src.rank() == tgt.rank() && tgt.file() == src.file() + 2 => Move::Castle,
+        None if piece == Piece::King && src.rank() == tgt.rank() && src.file() == tgt.file() + 2 => Move::LongCastle,
+        None if piece == Piece::Pawn && state.en_passant == Some(tgt) => Move::EnPassant(src, tgt),
+        None => Move::Normal(piece, src, tgt),
+    };
+
+    if legal_moves(state, mbb).contains(&mv) {
+        Some(mv)
+    } else {
+        None
+    }
+}
+
 pub fn format_coord(coord: BitCoord) -> String {
      let mut s = String::new();
      s.push(format_file(coord.file()));
@@ -108,6 +229,10 @@ pub fn parse_coord(coord: &str) -> BitCoord {
     (file, rank).into()
 }
 
+fn try_parse_coord(coord: &str) -> Option<BitCoord> {
+    coord.parse().ok()
+}
+
 pub fn parse_file(c: char) -> u32 {
     match c {
         'a' => 0,
@@ -126,3 +251,116 @@ pub fn parse_rank(c: char) -> u32 {
     c.to_digit(10).expect(format!("Invalid rank: {}", c).as_str()) - 1
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::fen::{load_fen, STARTING_POSITION};
+    use crate::fmt::*;
+
+    #[test]
+    fn display_starting_position() {
+        let state = load_fen(STARTING_POSITION);
+        let expected = [
+            "8 r n b q k b n r",
+            "7 p p p p p p p p",
+            "6 . . . . . . . .",
+            "5 . . . . . . . .",
+            "4 . . . . . . . .",
+            "3 . . . . . . . .",
+            "2 P P P P P P P P",
+            "1 R N B Q K B N R",
+            "  a b c d e f g h",
+            "White to move",
+            "",
+        ].join("\n");
+
+        assert_eq!(format!("{}", state), expected);
+    }
+
+    #[test]
+    fn coord_round_trip() {
+        for ix in 0..64 {
+            let coord = BitCoord(1 << ix);
+            assert_eq!(coord.to_string().parse::<BitCoord>(), Ok(coord));
+        }
+
+        assert_eq!("e4".parse::<BitCoord>(), Ok(parse_coord("e4")));
+        assert_eq!(parse_coord("a1").to_string(), "a1");
+        assert_eq!(parse_coord("h8").to_string(), "h8");
+    }
+
+    #[test]
+    fn coord_parse_errors() {
+        assert_eq!("e".parse::<BitCoord>(), Err(CoordParseError::WrongLength("e".to_owned())));
+        assert_eq!("e44".parse::<BitCoord>(), Err(CoordParseError::WrongLength("e44".to_owned())));
+        assert_eq!("i4".parse::<BitCoord>(), Err(CoordParseError::InvalidFile('i')));
+        assert_eq!("E4".parse::<BitCoord>(), Err(CoordParseError::InvalidFile('E')));
+        assert_eq!("e9".parse::<BitCoord>(), Err(CoordParseError::InvalidRank('9')));
+        assert_eq!("e0".parse::<BitCoord>(), Err(CoordParseError::InvalidRank('0')));
+    }
+
+    #[test]
+    fn uci_round_trip() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/Pp2P3/2N2Q1p/1PPBBPPP/R3K2R b KQkq a3 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N w - - 0 1",
+        ];
+
+        for fen in fens.iter() {
+            let state = load_fen(fen);
+            for mv in legal_moves(&state, &mbb) {
+                let s = format_uci_move(&state, mv);
+                assert_eq!(parse_uci_move(&state, &s, &mbb), Some(mv), "{} in {}", s, fen);
+            }
+        }
+    }
+
+    #[test]
+    fn display_move() {
+        assert_eq!(format!("{}", Move::Castle), "O-O");
+        assert_eq!(format!("{}", Move::LongCastle), "O-O-O");
+        assert_eq!(format!("{}", Move::Normal(Piece::Knight, parse_coord("g1"), parse_coord("f3"))), "g1f3");
+
+        let promotion = Move::Promotion(parse_coord("e7"), parse_coord("e8"), Piece::Queen);
+        assert_eq!(format!("{}", promotion), "e7e8Q");
+        assert_eq!(promotion.to_uci(Colour::White), "e7e8q");
+
+        assert_eq!(Move::Castle.to_uci(Colour::White), "e1g1");
+        assert_eq!(Move::LongCastle.to_uci(Colour::Black), "e8c8");
+    }
+
+    #[test]
+    fn parse_uci() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/Pp2P3/2N2Q1p/1PPBBPPP/R3K2R b KQkq a3 0 1");
+
+        assert_eq!(parse_uci_move(&state, "e8g8", &mbb), Some(Move::Castle));
+        assert_eq!(parse_uci_move(&state, "e8c8", &mbb), Some(Move::LongCastle));
+        assert_eq!(
+            parse_uci_move(&state, "b4a3", &mbb),
+            Some(Move::EnPassant(parse_coord("b4"), parse_coord("a3"))),
+        );
+        assert_eq!(
+            parse_uci_move(&state, "b4c3", &mbb),
+            Some(Move::Normal(Piece::Pawn, parse_coord("b4"), parse_coord("c3"))),
+        );
+
+        let state = load_fen("n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1");
+        assert_eq!(
+            parse_uci_move(&state, "g2h1n", &mbb),
+            Some(Move::Promotion(parse_coord("g2"), parse_coord("h1"), Piece::Knight)),
+        );
+
+        // Invalid squares and illegal moves.
+        assert_eq!(parse_uci_move(&state, "i2h1", &mbb), None);
+        assert_eq!(parse_uci_move(&state, "g2g9", &mbb), None);
+        assert_eq!(parse_uci_move(&state, "g2h1", &mbb), None);
+        assert_eq!(parse_uci_move(&state, "g2h1k", &mbb), None);
+        assert_eq!(parse_uci_move(&state, "d7d5", &mbb), None);
+        assert_eq!(parse_uci_move(&state, "e2", &mbb), None);
+    }
+}
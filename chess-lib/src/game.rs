@@ -1,8 +1,31 @@
+use std::fmt;
+use std::fmt::Display;
+use crate::fmt::parse_uci_move;
 use crate::magic::MagicBitBoards;
-use crate::moves::square_under_attack;
-use crate::types::{BitBoard, BitCoord, Colour, GameState, Move, Piece, SideState, ZobristHash};
+use crate::material::{material_score, piece_score};
+use crate::moves::{attackers_to, has_legal_move, legal_moves};
+use crate::types::{BitBoard, BitCoord, Colour, GameState, MaterialScore, Move, MoveInfo, Piece, Pieces, SideState, Status, Undo, ZobristHash};
 use crate::zobrist::ZobristHasher;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IllegalMove(pub Move);
+
+impl Display for IllegalMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Illegal move: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for IllegalMove {}
+
+// The square of the pawn taken by an en-passant capture landing on `tgt`, made by `colour`.
+pub fn en_passant_victim(tgt: BitCoord, colour: Colour) -> BitCoord {
+    match colour {
+        Colour::White => tgt >> 8,
+        Colour::Black => tgt << 8,
+    }
+}
+
 impl GameState {
     pub fn new(
         active_colour: Colour, 
@@ -17,79 +40,267 @@ impl GameState {
             black,
             en_passant,
             fifty_move_clock,
+            fullmove_number: 1,
+            ply: 0,
             zh: ZobristHash(0),
+            history: vec![],
+            last_irreversible: 0,
         };
+        state.set_fullmove_number(1);
 
         state.zh = ZobristHasher::default().hash(&state);
+        state.white.score = material_score(&state.white.pieces, Colour::White);
+        state.black.score = material_score(&state.black.pieces, Colour::Black);
 
         state
     }
 
-    pub fn make_move(&mut self, mv: Move) {
+    // Builds a position from a list of pieces, for hand-written tests.
+    // Castling rights are (kingside, queenside) and refer to the standard rook files.
+    pub fn from_squares(
+        squares: &[(BitCoord, Colour, Piece)],
+        active_colour: Colour,
+        white_castling: (bool, bool),
+        black_castling: (bool, bool),
+        en_passant: Option<BitCoord>,
+    ) -> GameState {
+        let mut white = Pieces::empty();
+        let mut black = Pieces::empty();
+        for (coord, colour, piece) in squares.iter() {
+            match colour {
+                Colour::White => white.put_piece(*piece, *coord),
+                Colour::Black => black.put_piece(*piece, *coord),
+            }
+        }
+
+        let side = |pieces: Pieces, (kingside, queenside): (bool, bool)| {
+            let king_file = if pieces.king.is_empty() { 4 } else { BitCoord(pieces.king.0).file() };
+            SideState{
+                pieces,
+                score: MaterialScore::default(),
+                can_castle_kingside: kingside,
+                can_castle_queenside: queenside,
+                king_file,
+                kingside_rook_file: 7,
+                queenside_rook_file: 0,
+            }
+        };
+
+        GameState::new(active_colour, side(white, white_castling), side(black, black_castling), en_passant, 0)
+    }
+
+    pub fn make_move(&mut self, mv: Move) -> Undo {
         let hasher = ZobristHasher::default();
 
+        let mut undo = Undo{
+            captured: None,
+            white_castling: (self.white.can_castle_kingside, self.white.can_castle_queenside),
+            black_castling: (self.black.can_castle_kingside, self.black.can_castle_queenside),
+            en_passant: self.en_passant,
+            fifty_move_clock: self.fifty_move_clock,
+            last_irreversible: self.last_irreversible,
+            white_score: self.white.score,
+            black_score: self.black.score,
+            zh_delta: self.zh.0,
+        };
+
+        self.history.push(self.zh);
         self.fifty_move_clock = self.fifty_move_clock.saturating_add(1);
+        self.ply += 1;
+        if self.active_colour == Colour::Black {
+            self.fullmove_number += 1;
+        }
 
         match mv {
             Move::Normal(piece, src, tgt) => {
-                self.move_piece(piece, src, tgt, hasher);
+                undo.captured = self.move_piece(piece, src, tgt, hasher);
             },
             Move::Promotion(src, tgt, pc) => {
-                self.move_piece(Piece::Pawn, src, tgt, hasher);
+                undo.captured = self.move_piece(Piece::Pawn, src, tgt, hasher);
                 self.remove_active_piece(Piece::Pawn, tgt, hasher);
                 self.put_active_piece(pc, tgt, hasher);
             },
-            Move::Castle => {
-                match self.active_colour {
-                    Colour::White => {
-                        self.remove_active_piece(Piece::King, BitCoord(0x00_00_00_00_00_00_00_08), hasher);
-                        self.remove_active_piece(Piece::Rook, BitCoord(0x00_00_00_00_00_00_00_01), hasher);
-                        self.put_active_piece(Piece::King, BitCoord(0x00_00_00_00_00_00_00_02), hasher);
-                        self.put_active_piece(Piece::Rook, BitCoord(0x00_00_00_00_00_00_00_04), hasher);
-                        self.disable_active_kingside_castle(hasher);
-                        self.disable_active_queenside_castle(hasher);
-                        self.clear_en_passant(hasher);
-                    },
-                    Colour::Black => {
-                        self.remove_active_piece(Piece::King, BitCoord(0x08_00_00_00_00_00_00_00), hasher);
-                        self.remove_active_piece(Piece::Rook, BitCoord(0x01_00_00_00_00_00_00_00), hasher);
-                        self.put_active_piece(Piece::King, BitCoord(0x02_00_00_00_00_00_00_00), hasher);
-                        self.put_active_piece(Piece::Rook, BitCoord(0x04_00_00_00_00_00_00_00), hasher);
-                        self.disable_active_kingside_castle(hasher);
-                        self.disable_active_queenside_castle(hasher);
-                        self.clear_en_passant(hasher);
-                    },
+            Move::EnPassant(src, tgt) => {
+                self.move_piece(Piece::Pawn, src, tgt, hasher);
+                self.remove_other_piece(Piece::Pawn, en_passant_victim(tgt, self.active_colour), hasher);
+                undo.captured = Some(Piece::Pawn);
+            },
+            Move::Castle => self.castle(true, hasher),
+            Move::LongCastle => self.castle(false, hasher),
+        }
+
+        if self.fifty_move_clock == 0 {
+            self.last_irreversible = self.history.len();
+        }
+
+        self.active_colour = Colour::other(self.active_colour);
+        self.zh = hasher.toggle_active_colour(self.zh);
+
+        undo.zh_delta ^= self.zh.0;
+        undo
+    }
+
+    // Same as make_move, but also describes the move for callers that need to know about captures
+    // or checks without working them out again.
+    pub fn make_move_info(&mut self, mv: Move, mbb: &MagicBitBoards) -> (Undo, MoveInfo) {
+        let is_pawn_move = match mv {
+            Move::Normal(piece, _, _) => piece == Piece::Pawn,
+            Move::Promotion(_, _, _) | Move::EnPassant(_, _) => true,
+            Move::Castle | Move::LongCastle => false,
+        };
+
+        let undo = self.make_move(mv);
+        let info = MoveInfo{
+            captured: undo.captured,
+            gives_check: self.is_in_check(mbb),
+            is_castle: mv == Move::Castle || mv == Move::LongCastle,
+            is_pawn_move,
+        };
+
+        (undo, info)
+    }
+
+    pub fn ply(&self) -> u32 {
+        self.ply
+    }
+
+    pub fn fullmove_number(&self) -> u16 {
+        self.fullmove_number
+    }
+
+    // Also sets the ply to match, counting from the start of the game.
+    pub fn set_fullmove_number(&mut self, fullmove_number: u16) {
+        self.fullmove_number = fullmove_number.max(1);
+        self.ply = (self.fullmove_number as u32 - 1) * 2;
+        if self.active_colour == Colour::Black {
+            self.ply += 1;
+        }
+    }
+
+    pub fn is_legal_move(&self, mv: Move, mbb: &MagicBitBoards) -> bool {
+        legal_moves(self, mbb).contains(&mv)
+    }
+
+    // For moves from untrusted sources. make_move assumes the move is legal and will corrupt the
+    // state otherwise, whereas this leaves the state untouched.
+    pub fn make_move_checked(&mut self, mv: Move, mbb: &MagicBitBoards) -> Result<(), IllegalMove> {
+        if self.is_legal_move(mv, mbb) {
+            self.make_move(mv);
+            Ok(())
+        } else {
+            Err(IllegalMove(mv))
+        }
+    }
+
+    // Plays a list of moves like "e2e4", as given by UCI's position command.
+    // On failure, returns the index of the first move which couldn't be played. The moves before
+    // it are left applied.
+    pub fn apply_uci_moves(&mut self, moves: &[&str], mbb: &MagicBitBoards) -> Result<(), usize> {
+        for (ix, s) in moves.iter().enumerate() {
+            let mv = parse_uci_move(self, s, mbb).ok_or(ix)?;
+            self.make_move(mv);
+        }
+
+        Ok(())
+    }
+
+    // Reverses a move made with make_move, restoring the state exactly.
+    pub fn unmake_move(&mut self, mv: Move, undo: Undo) {
+        self.active_colour = Colour::other(self.active_colour);
+        let colour = self.active_colour;
+
+        let home_rank = match colour {
+            Colour::White => 0u32,
+            Colour::Black => 7u32,
+        };
+
+        let (side, other_side) = match colour {
+            Colour::White => (&mut self.white, &mut self.black),
+            Colour::Black => (&mut self.black, &mut self.white),
+        };
+
+        match mv {
+            Move::Normal(piece, src, tgt) => {
+                side.pieces.remove_piece(piece, tgt);
+                side.pieces.put_piece(piece, src);
+
+                if let Some(captured) = undo.captured {
+                    other_side.pieces.put_piece(captured, tgt);
                 }
             },
-            Move::LongCastle => {
-                match self.active_colour {
-                    Colour::White => {
-                        self.remove_active_piece(Piece::King, BitCoord(0x00_00_00_00_00_00_00_08), hasher);
-                        self.remove_active_piece(Piece::Rook, BitCoord(0x00_00_00_00_00_00_00_80), hasher);
-                        self.put_active_piece(Piece::King, BitCoord(0x00_00_00_00_00_00_00_20), hasher);
-                        self.put_active_piece(Piece::Rook, BitCoord(0x00_00_00_00_00_00_00_10), hasher);
-                        self.disable_active_kingside_castle(hasher);
-                        self.disable_active_queenside_castle(hasher);
-                        self.clear_en_passant(hasher);
-                    },
-                    Colour::Black => {
-                        self.remove_active_piece(Piece::King, BitCoord(0x08_00_00_00_00_00_00_00), hasher);
-                        self.remove_active_piece(Piece::Rook, BitCoord(0x80_00_00_00_00_00_00_00), hasher);
-                        self.put_active_piece(Piece::King, BitCoord(0x20_00_00_00_00_00_00_00), hasher);
-                        self.put_active_piece(Piece::Rook, BitCoord(0x10_00_00_00_00_00_00_00), hasher);
-                        self.disable_active_kingside_castle(hasher);
-                        self.disable_active_queenside_castle(hasher);
-                        self.clear_en_passant(hasher);
-                    },
+            Move::EnPassant(src, tgt) => {
+                side.pieces.remove_piece(Piece::Pawn, tgt);
+                side.pieces.put_piece(Piece::Pawn, src);
+                other_side.pieces.put_piece(Piece::Pawn, en_passant_victim(tgt, colour));
+            },
+            Move::Promotion(src, tgt, pc) => {
+                side.pieces.remove_piece(pc, tgt);
+                side.pieces.put_piece(Piece::Pawn, src);
+
+                if let Some(captured) = undo.captured {
+                    other_side.pieces.put_piece(captured, tgt);
                 }
             },
+            Move::Castle => {
+                side.pieces.remove_piece(Piece::King, (6u32, home_rank).into());
+                side.pieces.remove_piece(Piece::Rook, (5u32, home_rank).into());
+                side.pieces.put_piece(Piece::King, (side.king_file, home_rank).into());
+                side.pieces.put_piece(Piece::Rook, (side.kingside_rook_file, home_rank).into());
+            },
+            Move::LongCastle => {
+                side.pieces.remove_piece(Piece::King, (2u32, home_rank).into());
+                side.pieces.remove_piece(Piece::Rook, (3u32, home_rank).into());
+                side.pieces.put_piece(Piece::King, (side.king_file, home_rank).into());
+                side.pieces.put_piece(Piece::Rook, (side.queenside_rook_file, home_rank).into());
+            },
         }
 
-        self.active_colour = Colour::other(self.active_colour);
-        self.zh = hasher.toggle_active_colour(self.zh);
+        let (white_kingside, white_queenside) = undo.white_castling;
+        self.white.can_castle_kingside = white_kingside;
+        self.white.can_castle_queenside = white_queenside;
+
+        let (black_kingside, black_queenside) = undo.black_castling;
+        self.black.can_castle_kingside = black_kingside;
+        self.black.can_castle_queenside = black_queenside;
+
+        self.en_passant = undo.en_passant;
+        self.fifty_move_clock = undo.fifty_move_clock;
+        self.last_irreversible = undo.last_irreversible;
+        self.white.score = undo.white_score;
+        self.black.score = undo.black_score;
+        self.ply -= 1;
+        if colour == Colour::Black {
+            self.fullmove_number -= 1;
+        }
+        self.zh = ZobristHash(self.zh.0 ^ undo.zh_delta);
+        self.history.pop();
+    }
+
+    // Identifies the position for repetition purposes: pieces, side to move, castling rights and
+    // en-passant, but not the move clocks.
+    pub fn repetition_key(&self) -> ZobristHash {
+        self.zh
+    }
+
+    // How many times the current position occurred before, since the last capture or pawn move.
+    pub fn repetition_count(&self) -> usize {
+        let key = self.repetition_key();
+        self.history[self.last_irreversible..]
+            .iter()
+            .filter(|zh| **zh == key)
+            .count()
+    }
+
+    pub fn is_threefold_repetition(&self) -> bool {
+        self.repetition_count() >= 2
     }
 
     pub fn is_in_check(&self, mbb: &MagicBitBoards) -> bool {
+        self.checkers(mbb) != BitBoard::EMPTY
+    }
+
+    // The enemy pieces attacking the side to move's king. Two of them means double check.
+    pub fn checkers(&self, mbb: &MagicBitBoards) -> BitBoard {
         let occupancy = self.white.pieces.all() | self.black.pieces.all();
         let (side, other_side) = match self.active_colour {
             Colour::White => (&self.white, &self.black),
@@ -97,7 +308,48 @@ impl GameState {
         };
         let king = BitCoord(side.pieces.king.0);
 
-        square_under_attack(occupancy, &other_side.pieces, king, self.active_colour, mbb)
+        attackers_to(&other_side.pieces, Colour::other(self.active_colour), occupancy, king, mbb)
+    }
+
+    // Mate and stalemate take priority over the draw rules, since the game ended on the board.
+    pub fn status(&self, mbb: &MagicBitBoards) -> Status {
+        if !has_legal_move(self, mbb) {
+            if self.is_in_check(mbb) {
+                Status::Checkmate
+            } else {
+                Status::Stalemate
+            }
+        } else if self.is_threefold_repetition() {
+            Status::ThreefoldRepetition
+        } else if self.is_insufficient_material() {
+            Status::InsufficientMaterial
+        } else if self.fifty_move_clock >= 100 {
+            // The clock counts half-moves.
+            Status::FiftyMoveDraw
+        } else {
+            Status::Ongoing
+        }
+    }
+
+    // Positions where neither side can possibly deliver mate.
+    // K v K, K+minor v K, and K+B v K+B with all bishops on the same colour.
+    pub fn is_insufficient_material(&self) -> bool {
+        let light_squares = BitBoard(0xAA_55_AA_55_AA_55_AA_55);
+        let (w, b) = (&self.white.pieces, &self.black.pieces);
+
+        let mating_material = w.queens | w.rooks | w.pawns | b.queens | b.rooks | b.pawns;
+        if !mating_material.is_empty() {
+            return false;
+        }
+
+        let knights = w.knights.count() + b.knights.count();
+        let bishops = w.bishops | b.bishops;
+
+        if knights == 0 {
+            (bishops & light_squares).is_empty() || (bishops & !light_squares).is_empty()
+        } else {
+            knights == 1 && bishops.is_empty()
+        }
     }
 
     pub fn find_piece(&self, coord: BitCoord) -> Option<(Colour, Piece)> {
@@ -114,43 +366,55 @@ impl GameState {
         None
     }
 
-    fn move_piece(&mut self, piece: Piece, src: BitCoord, tgt: BitCoord, hasher: &ZobristHasher) {
+    pub fn occupied(&self) -> BitBoard {
+        self.white.pieces.all() | self.black.pieces.all()
+    }
+
+    pub fn piece_bitboard(&self, colour: Colour, piece: Piece) -> BitBoard {
+        match colour {
+            Colour::White => self.white.pieces.of(piece),
+            Colour::Black => self.black.pieces.of(piece),
+        }
+    }
+
+    // Every piece on the board, white's first.
+    pub fn iter_pieces(&self) -> impl Iterator<Item=(BitCoord, Colour, Piece)> + '_ {
+        const COLOURS: [Colour; 2] = [Colour::White, Colour::Black];
+        const PIECES: [Piece; 6] = [Piece::King, Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight, Piece::Pawn];
+
+        COLOURS.iter().flat_map(move |&colour| {
+            PIECES.iter().flat_map(move |&piece| {
+                self.piece_bitboard(colour, piece).iter().map(move |c| (c, colour, piece))
+            })
+        })
+    }
+
+    // Returns the captured piece, if any.
+    fn move_piece(&mut self, piece: Piece, src: BitCoord, tgt: BitCoord, hasher: &ZobristHasher) -> Option<Piece> {
         let colour = self.active_colour;
 
-        let home_rank = match colour {
-            Colour::White => BitBoard(0x00_00_00_00_00_00_00_FF),
-            Colour::Black => BitBoard(0xFF_00_00_00_00_00_00_00),
+        let (home_rank, other_home_rank) = match colour {
+            Colour::White => (0u32, 7u32),
+            Colour::Black => (7u32, 0u32),
         };
 
-        let other_home_rank = match colour {
-            Colour::White => BitBoard(0xFF_00_00_00_00_00_00_00),
-            Colour::Black => BitBoard(0x00_00_00_00_00_00_00_FF),
+        let (side, other_side) = match colour {
+            Colour::White => (&self.white, &self.black),
+            Colour::Black => (&self.black, &self.white),
         };
 
-        let queenside_rook: BitCoord = BitCoord(home_rank.0 & 0x80_00_00_00_00_00_00_80);
-        let kingside_rook: BitCoord = BitCoord(home_rank.0 & 0x01_00_00_00_00_00_00_01);
-        let other_queenside_rook: BitCoord = BitCoord(other_home_rank.0 & 0x80_00_00_00_00_00_00_80);
-        let other_kingside_rook: BitCoord = BitCoord(other_home_rank.0 & 0x01_00_00_00_00_00_00_01);
+        let queenside_rook: BitCoord = (side.queenside_rook_file, home_rank).into();
+        let kingside_rook: BitCoord = (side.kingside_rook_file, home_rank).into();
+        let other_queenside_rook: BitCoord = (other_side.queenside_rook_file, other_home_rank).into();
+        let other_kingside_rook: BitCoord = (other_side.kingside_rook_file, other_home_rank).into();
 
         let is_pawn = piece == Piece::Pawn;
 
         self.remove_active_piece(piece, src, hasher);
         self.put_active_piece(piece, tgt, hasher);
-        let mut is_capture = match self.other_side_mut().pieces.get_piece(tgt) {
-            Some(pc) => {
-                self.remove_other_piece(pc, tgt, hasher);
-                true
-            },
-            None => false,
-        };
-
-        if is_pawn && self.en_passant.map(|ep| ep == tgt).unwrap_or(false) {
-            let taken_coord = match colour {
-                Colour::White => tgt >> 8,
-                Colour::Black => tgt << 8,
-            };
-            self.remove_other_piece(Piece::Pawn, taken_coord, hasher);
-            is_capture = true;
+        let captured = self.other_side_mut().pieces.get_piece(tgt);
+        if let Some(pc) = captured {
+            self.remove_other_piece(pc, tgt, hasher);
         }
 
         // King moves.
@@ -183,23 +447,59 @@ impl GameState {
         }
 
         // Adjust clocks.
-        if is_pawn || is_capture {
+        if is_pawn || captured.is_some() {
             self.fifty_move_clock = 0;
         }
+
+        captured
+    }
+
+    // The king and rook always end up on the g and f files (c and d for queenside), wherever they
+    // started, which covers both standard chess and Chess960.
+    fn castle(&mut self, kingside: bool, hasher: &ZobristHasher) {
+        let (side, home_rank) = match self.active_colour {
+            Colour::White => (&self.white, 0u32),
+            Colour::Black => (&self.black, 7u32),
+        };
+
+        let king_file = side.king_file;
+        let (rook_file, king_target, rook_target) = if kingside {
+            (side.kingside_rook_file, 6u32, 5u32)
+        } else {
+            (side.queenside_rook_file, 2u32, 3u32)
+        };
+
+        // Remove both first, since in Chess960 the targets may overlap the starting squares.
+        self.remove_active_piece(Piece::King, (king_file, home_rank).into(), hasher);
+        self.remove_active_piece(Piece::Rook, (rook_file, home_rank).into(), hasher);
+        self.put_active_piece(Piece::King, (king_target, home_rank).into(), hasher);
+        self.put_active_piece(Piece::Rook, (rook_target, home_rank).into(), hasher);
+        self.disable_active_kingside_castle(hasher);
+        self.disable_active_queenside_castle(hasher);
+        self.clear_en_passant(hasher);
     }
 
     fn put_active_piece(&mut self, piece: Piece, coord: BitCoord, hasher: &ZobristHasher) {
-        self.active_side_mut().pieces.put_piece(piece, coord);
+        let colour = self.active_colour;
+        let side = self.active_side_mut();
+        side.pieces.put_piece(piece, coord);
+        side.score += piece_score(piece, coord, colour);
         self.zh = hasher.toggle_piece(self.zh, self.active_colour, piece, coord);
     }
 
     fn remove_active_piece(&mut self, piece: Piece, coord: BitCoord, hasher: &ZobristHasher) {
-        self.active_side_mut().pieces.remove_piece(piece, coord);
+        let colour = self.active_colour;
+        let side = self.active_side_mut();
+        side.pieces.remove_piece(piece, coord);
+        side.score -= piece_score(piece, coord, colour);
         self.zh = hasher.toggle_piece(self.zh, self.active_colour, piece, coord);
     }
 
     fn remove_other_piece(&mut self, piece: Piece, coord: BitCoord, hasher: &ZobristHasher) {
-        self.other_side_mut().pieces.remove_piece(piece, coord);
+        let colour = Colour::other(self.active_colour);
+        let side = self.other_side_mut();
+        side.pieces.remove_piece(piece, coord);
+        side.score -= piece_score(piece, coord, colour);
         self.zh = hasher.toggle_piece(self.zh, Colour::other(self.active_colour), piece, coord);
     }
 
@@ -276,3 +576,385 @@ impl GameState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::fen::{load_fen, to_fen, STARTING_POSITION};
+    use crate::magic::MagicBitBoards;
+    use crate::material::material_score;
+    use crate::moves::legal_moves;
+    use crate::pgn::parse_san;
+    use crate::fmt::{parse_coord, parse_uci_move};
+    use crate::game::IllegalMove;
+    use crate::types::{BitBoard, BitCoord, Colour, GameState, Move, MoveInfo, Piece, Status};
+    use rand::prelude::*;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn in_check() {
+        let mbb = MagicBitBoards::default();
+        let cases = [
+            (STARTING_POSITION, false),
+            // Rook, bishop, knight and pawn checks.
+            ("4k3/8/8/8/8/8/8/4RK2 b - - 0 1", true),
+            ("4k3/8/8/8/B7/8/8/5K2 b - - 0 1", true),
+            ("4k3/8/3N4/8/8/8/8/5K2 b - - 0 1", true),
+            ("4k3/3P4/8/8/8/8/8/5K2 b - - 0 1", true),
+            // Blocked, and a pawn attacking the wrong way.
+            ("4k3/4p3/8/8/8/8/8/4RK2 b - - 0 1", false),
+            ("4k3/8/8/8/8/8/8/4K3 w - - 0 1", false),
+            ("4k3/8/8/8/8/8/4p3/5K2 w - - 0 1", true),
+            ("4k3/8/8/8/8/8/5p2/5K2 w - - 0 1", false),
+        ];
+
+        for (fen, expected) in cases.iter() {
+            let state = load_fen(fen);
+            assert_eq!(state.is_in_check(&mbb), *expected, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn discovered_double_check() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen("4k3/8/8/8/4N3/8/8/4RK2 w - - 0 1");
+        assert_eq!(state.checkers(&mbb), BitBoard::EMPTY);
+
+        // Moving the knight checks with it and uncovers the rook.
+        let mv = parse_uci_move(&state, "e4d6", &mbb).unwrap();
+        state.make_move(mv);
+        let checkers = state.checkers(&mbb);
+        assert_eq!(checkers.count(), 2);
+        assert!(checkers.contains(parse_coord("d6")));
+        assert!(checkers.contains(parse_coord("e1")));
+        assert!(state.is_in_check(&mbb));
+    }
+
+    #[test]
+    fn apply_uci_moves() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen(STARTING_POSITION);
+
+        // The closed Ruy Lopez, Breyer variation.
+        let moves = [
+            "e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "a7a6", "b5a4", "g8f6", "e1g1", "f8e7",
+            "f1e1", "b7b5", "a4b3", "d7d6", "c2c3", "e8g8", "h2h3", "c6b8", "d2d4", "b8d7",
+        ];
+        assert_eq!(state.apply_uci_moves(&moves, &mbb), Ok(()));
+        assert_eq!(to_fen(&state), "r1bq1rk1/2pnbppp/p2p1n2/1p2p3/3PP3/1BP2N1P/PP3PP1/RNBQR1K1 w - - 1 11");
+
+        // Stops at the first move which isn't legal, keeping the ones before it.
+        let mut state = load_fen(STARTING_POSITION);
+        assert_eq!(state.apply_uci_moves(&["e2e4", "e7e5", "e4e5", "g1f3"], &mbb), Err(2));
+        assert_eq!(state.ply(), 2);
+
+        let mut state = load_fen(STARTING_POSITION);
+        assert_eq!(state.apply_uci_moves(&["e2e4", "nonsense"], &mbb), Err(1));
+    }
+
+    #[test]
+    fn checked_moves() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen("4k3/8/8/8/8/8/3r4/4K3 w - - 0 1");
+
+        // Stepping along the rook's rank would leave the king in check.
+        let illegal = Move::Normal(Piece::King, parse_coord("e1"), parse_coord("f2"));
+        assert!(!state.is_legal_move(illegal, &mbb));
+        let before = state.clone();
+        assert_eq!(state.make_move_checked(illegal, &mbb), Err(IllegalMove(illegal)));
+        assert_eq!(state, before);
+
+        // There's no piece on a1 at all.
+        let bogus = Move::Normal(Piece::Rook, parse_coord("a1"), parse_coord("a8"));
+        assert!(!state.is_legal_move(bogus, &mbb));
+        assert!(state.make_move_checked(bogus, &mbb).is_err());
+        assert_eq!(state, before);
+
+        let legal = Move::Normal(Piece::King, parse_coord("e1"), parse_coord("d2"));
+        assert!(state.is_legal_move(legal, &mbb));
+        assert!(state.make_move_checked(legal, &mbb).is_ok());
+        assert_ne!(state, before);
+    }
+
+    #[test]
+    fn status() {
+        let mbb = MagicBitBoards::default();
+        assert_eq!(load_fen(STARTING_POSITION).status(&mbb), Status::Ongoing);
+        // Fool's mate.
+        assert_eq!(load_fen("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3").status(&mbb), Status::Checkmate);
+        assert_eq!(load_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").status(&mbb), Status::Stalemate);
+        assert_eq!(load_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1").status(&mbb), Status::InsufficientMaterial);
+
+        let mut state = load_fen("4k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+        state.fifty_move_clock = 100;
+        assert_eq!(state.status(&mbb), Status::FiftyMoveDraw);
+    }
+
+    #[test]
+    fn insufficient_material() {
+        // K v K
+        assert!(load_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1").is_insufficient_material());
+        // K+B v K
+        assert!(load_fen("4k3/8/8/8/8/8/8/2B1K3 w - - 0 1").is_insufficient_material());
+        // K v K+N
+        assert!(load_fen("4k1n1/8/8/8/8/8/8/4K3 w - - 0 1").is_insufficient_material());
+        // K+B v K+B, same colour bishops (c1 and f8 are both dark).
+        assert!(load_fen("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1").is_insufficient_material());
+    }
+
+    #[test]
+    fn sufficient_material() {
+        assert!(!load_fen(STARTING_POSITION).is_insufficient_material());
+        // K+B v K+N can still be mated.
+        assert!(!load_fen("4k1n1/8/8/8/8/8/8/2B1K3 w - - 0 1").is_insufficient_material());
+        // K+B v K+B, opposite colour bishops.
+        assert!(!load_fen("2b1k3/8/8/8/8/8/8/2B1K3 w - - 0 1").is_insufficient_material());
+        // K+N+N v K
+        assert!(!load_fen("4k3/8/8/8/8/8/8/1N2K1N1 w - - 0 1").is_insufficient_material());
+        // A single pawn.
+        assert!(!load_fen("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").is_insufficient_material());
+        // K+R v K
+        assert!(!load_fen("4k3/8/8/8/8/8/8/R3K3 w - - 0 1").is_insufficient_material());
+    }
+
+    #[test]
+    fn threefold_repetition() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen(STARTING_POSITION);
+        let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+
+        for _ in 0..2 {
+            for san in shuffle.iter() {
+                assert!(!state.is_threefold_repetition());
+                let mv = parse_san(&state, san, &mbb).unwrap();
+                state.make_move(mv);
+            }
+        }
+
+        assert!(state.is_threefold_repetition());
+    }
+
+    #[test]
+    fn repetition_key_ignores_clocks() {
+        let mbb = MagicBitBoards::default();
+        let play = |moves: &[&str]| {
+            let mut state = load_fen(STARTING_POSITION);
+            for san in moves.iter() {
+                let mv = parse_san(&state, san, &mbb).unwrap();
+                state.make_move(mv);
+            }
+            state
+        };
+
+        // Transposed move orders reach exactly the same state.
+        let a = play(&["Nf3", "Nf6", "Nc3", "Nc6"]);
+        let b = play(&["Nc3", "Nc6", "Nf3", "Nf6"]);
+        assert_eq!(a.repetition_key(), b.repetition_key());
+
+        // Same board, rights and en-passant square, but a move later.
+        let a = play(&["e4", "e5", "Nf3", "Nf6"]);
+        let b = play(&["e3", "e6", "e4", "e5", "Nf3", "Nf6"]);
+        assert_eq!(a.repetition_key(), b.repetition_key());
+        assert_ne!(a.fullmove_number(), b.fullmove_number());
+        assert_ne!(a, b);
+
+        // Same board, but a different side to move.
+        let white = load_fen("rnbqkb1r/pppp1ppp/5n2/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3");
+        let black = load_fen("rnbqkb1r/pppp1ppp/5n2/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 2 3");
+        assert_eq!(white.repetition_key(), a.repetition_key());
+        assert_ne!(white.repetition_key(), black.repetition_key());
+    }
+
+    #[test]
+    fn irreversible_move_resets_repetition() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen(STARTING_POSITION);
+        let moves = ["Nf3", "Nf6", "Ng1", "Ng8", "e3", "e6", "Nf3", "Nf6", "Ng1", "Ng8", "Nf3", "Nf6", "Ng1"];
+
+        for san in moves.iter() {
+            let mv = parse_san(&state, san, &mbb).unwrap();
+            state.make_move(mv);
+            assert!(!state.is_threefold_repetition());
+        }
+    }
+
+    #[test]
+    fn move_info() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("4k3/8/8/3p4/4P3/8/8/R3K3 w Q - 0 1");
+        let info = |uci: &str| {
+            let mut state = state.clone();
+            let mv = parse_uci_move(&state, uci, &mbb).unwrap();
+            let (undo, info) = state.make_move_info(mv, &mbb);
+
+            // Undoes just like make_move.
+            state.unmake_move(mv, undo);
+            assert_eq!(state, load_fen("4k3/8/8/3p4/4P3/8/8/R3K3 w Q - 0 1"));
+            info
+        };
+
+        assert_eq!(info("e4d5"), MoveInfo{captured: Some(Piece::Pawn), gives_check: false, is_castle: false, is_pawn_move: true});
+        assert_eq!(info("a1a8"), MoveInfo{captured: None, gives_check: true, is_castle: false, is_pawn_move: false});
+        assert_eq!(info("e1c1"), MoveInfo{captured: None, gives_check: false, is_castle: true, is_pawn_move: false});
+    }
+
+    #[test]
+    fn capture_prevents_false_repetition() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen("r3k1n1/8/8/8/8/8/8/R3K1N1 w - - 0 1");
+        let play = |state: &mut GameState, moves: &[&str]| {
+            for uci in moves.iter() {
+                let mv = parse_uci_move(state, uci, &mbb).unwrap();
+                state.make_move(mv);
+            }
+        };
+
+        play(&mut state, &["g1f3", "g8f6", "f3g1", "f6g8"]);
+        assert_eq!(state.repetition_count(), 1);
+
+        let before_capture = state.clone();
+        let rxa8 = parse_uci_move(&state, "a1a8", &mbb).unwrap();
+        state.make_move(rxa8);
+        assert_eq!(state.last_irreversible, 5);
+        assert_eq!(state.repetition_count(), 0);
+
+        // Pretend the positions before the capture matched this one, as a hash collision could.
+        // They still can't count towards a repetition.
+        let key = state.repetition_key();
+        for zh in state.history[..5].iter_mut() {
+            *zh = key;
+        }
+        assert_eq!(state.repetition_count(), 0);
+
+        // Whereas repeats since the capture do.
+        play(&mut state, &["e8e7", "a8a1", "e7e8", "a1a8"]);
+        assert_eq!(state.repetition_count(), 1);
+        assert!(!state.is_threefold_repetition());
+        play(&mut state, &["e8e7", "a8a1", "e7e8", "a1a8"]);
+        assert!(state.is_threefold_repetition());
+
+        // Unmaking the capture brings the earlier positions back into view.
+        let mut state = before_capture;
+        let undo = state.make_move(rxa8);
+        state.unmake_move(rxa8, undo);
+        assert_eq!(state.last_irreversible, 0);
+        assert_eq!(state.repetition_count(), 1);
+    }
+
+    #[test]
+    fn from_squares() {
+        let mbb = MagicBitBoards::default();
+        let state = GameState::from_squares(
+            &[
+                (parse_coord("e1"), Colour::White, Piece::King),
+                (parse_coord("a1"), Colour::White, Piece::Rook),
+                (parse_coord("e8"), Colour::Black, Piece::King),
+            ],
+            Colour::White,
+            (false, true),
+            (false, false),
+            None,
+        );
+
+        assert_eq!(state, load_fen("4k3/8/8/8/8/8/8/R3K3 w Q - 0 1"));
+        // Five king moves, ten rook moves and O-O-O.
+        assert_eq!(legal_moves(&state, &mbb).len(), 16);
+        assert!(legal_moves(&state, &mbb).contains(&Move::LongCastle));
+    }
+
+    #[test]
+    fn iter_pieces() {
+        let state = load_fen(STARTING_POSITION);
+        let pieces: Vec<(BitCoord, Colour, Piece)> = state.iter_pieces().collect();
+        assert_eq!(pieces.len(), 32);
+
+        let count = |colour, piece| pieces.iter().filter(|(_, c, p)| *c == colour && *p == piece).count();
+        for colour in [Colour::White, Colour::Black].iter() {
+            assert_eq!(count(*colour, Piece::King), 1);
+            assert_eq!(count(*colour, Piece::Queen), 1);
+            assert_eq!(count(*colour, Piece::Rook), 2);
+            assert_eq!(count(*colour, Piece::Bishop), 2);
+            assert_eq!(count(*colour, Piece::Knight), 2);
+            assert_eq!(count(*colour, Piece::Pawn), 8);
+        }
+
+        for (coord, colour, piece) in pieces.iter() {
+            assert_eq!(state.find_piece(*coord), Some((*colour, *piece)));
+            assert!(state.piece_bitboard(*colour, *piece).contains(*coord));
+        }
+        assert_eq!(state.occupied().count(), 32);
+        assert_eq!(state.piece_bitboard(Colour::Black, Piece::Knight), BitBoard(0x42_00_00_00_00_00_00_00));
+    }
+
+    #[test]
+    fn move_counters() {
+        let mbb = MagicBitBoards::default();
+        let mut state = load_fen(STARTING_POSITION);
+        assert_eq!(state.fullmove_number(), 1);
+        assert_eq!(state.ply(), 0);
+
+        for san in ["e4", "e5", "Nf3"].iter() {
+            let mv = parse_san(&state, san, &mbb).unwrap();
+            state.make_move(mv);
+        }
+
+        assert_eq!(state.fullmove_number(), 2);
+        assert_eq!(state.ply(), 3);
+        assert_eq!(state.fifty_move_clock, 1);
+
+        // Picked up from FEN, counting ply from the start of the game.
+        let state = load_fen("rnbqkb1r/pppppppp/5n2/8/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 1 2");
+        assert_eq!(state.fullmove_number(), 2);
+        assert_eq!(state.ply(), 2);
+    }
+
+    #[test]
+    fn make_unmake_restores_state() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/Pp2P3/2N2Q1p/1PPBBPPP/R3K2R b KQkq a3 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+        ];
+
+        for fen in fens.iter() {
+            let original = load_fen(fen);
+            let mut state = original.clone();
+            for mv in legal_moves(&original, &mbb) {
+                let undo = state.make_move(mv);
+                assert_ne!(state, original);
+                state.unmake_move(mv, undo);
+                assert_eq!(state, original, "{:?} in {}", mv, fen);
+                assert_eq!(state.zh, original.zh);
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_material_score() {
+        let mbb = MagicBitBoards::default();
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let mut state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        let from_scratch = |state: &GameState| {
+            (material_score(&state.white.pieces, Colour::White), material_score(&state.black.pieces, Colour::Black))
+        };
+
+        for _ in 0..100 {
+            let moves = legal_moves(&state, &mbb);
+            let mv = match moves.choose(&mut rng) {
+                Some(mv) => *mv,
+                None => break,
+            };
+
+            let before = state.clone();
+            let undo = state.make_move(mv);
+            assert_eq!((state.white.score, state.black.score), from_scratch(&state), "{:?}", mv);
+
+            state.unmake_move(mv, undo);
+            assert_eq!(state, before);
+            state.make_move(mv);
+        }
+    }
+}
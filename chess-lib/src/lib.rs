@@ -3,8 +3,11 @@ pub mod fen;
 pub mod fmt;
 pub mod game;
 pub mod magic;
+pub mod material;
 pub mod moves;
+pub mod opening_book;
 pub mod perft;
+pub mod pext;
 pub mod pgn;
 pub mod tt;
 pub mod types;
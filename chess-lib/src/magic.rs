@@ -1,6 +1,29 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
 use crate::types::{BitBoard, BitCoord};
 
+static SHARED: OnceLock<MagicBitBoards> = OnceLock::new();
+
+// A (magic, bits) pair for each square.
+pub type MagicTable = [(u64, u32); 64];
+
+// Counts how many times the shared tables have been built, which should be at most once.
+#[cfg(test)]
+static SHARED_BUILDS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// Precomputed attack sets for each piece type, independent of how the sliding piece lookups are
+// implemented.
+pub trait AttackTables {
+    fn rook_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard;
+    fn bishop_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard;
+    fn king_attacks(&self, coord: BitCoord) -> BitBoard;
+    fn knight_attacks(&self, coord: BitCoord) -> BitBoard;
+}
+
+#[derive(Clone)]
 pub struct MagicBitBoards {
     rooks: Vec<Magic>,
     bishops: Vec<Magic>,
@@ -14,7 +37,22 @@ impl MagicBitBoards {
         Self::generate(generated::ROOK_MAGIC, generated::BISHOP_MAGIC)
     }
 
-    pub fn generate(rook_magics: [(u64, u32); 64], bishop_magics: [(u64, u32); 64]) -> MagicBitBoards {
+    // The default tables, built the first time they're asked for and then shared by the whole
+    // process.
+    pub fn shared() -> &'static MagicBitBoards {
+        SHARED.get_or_init(|| {
+            #[cfg(test)]
+            SHARED_BUILDS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Self::default()
+        })
+    }
+
+    pub fn generate(rook_magics: MagicTable, bishop_magics: MagicTable) -> MagicBitBoards {
+        Self::try_generate(rook_magics, bishop_magics).expect("Magics are valid")
+    }
+
+    // Returns None if any of the magics fail to produce a collision-free table of the given size.
+    pub fn try_generate(rook_magics: MagicTable, bishop_magics: MagicTable) -> Option<MagicBitBoards> {
         let mut rooks: Vec<Magic> = Vec::with_capacity(64);
         let mut bishops: Vec<Magic> = Vec::with_capacity(64);
         let mut kings: Vec<BitBoard> = Vec::with_capacity(64);
@@ -26,48 +64,123 @@ impl MagicBitBoards {
             let rook_moves_map = generate_moves(coord, rook_mask(coord), rook_moves);
             let bishop_moves_map = generate_moves(coord, bishop_mask(coord), bishop_moves);
 
-            let (rook_magic, rook_bits) = rook_magics[coord.0.trailing_zeros() as usize];
+            let (rook_magic, rook_bits) = rook_magics[coord.index() as usize];
             rooks.push(Magic::generate(
                     rook_magic,
                     rook_mask(coord),
                     &rook_moves_map,
                     &mut boards_cache,
                     1 << rook_bits,
-            ).expect(&format!("Rook magic {} is valid", coord.0.trailing_zeros())));
+            )?);
 
-            let (bishop_magic, bishop_bits) = bishop_magics[coord.0.trailing_zeros() as usize];
+            let (bishop_magic, bishop_bits) = bishop_magics[coord.index() as usize];
             bishops.push(Magic::generate(
                     bishop_magic,
                     bishop_mask(coord),
                     &bishop_moves_map,
                     &mut boards_cache,
                     1 << bishop_bits,
-            ).expect(&format!("Bishop magic {} is valid", coord.0.trailing_zeros())));
+            )?);
 
             kings.push(king_moves(coord));
             knights.push(knight_moves(coord));
         }
 
-        MagicBitBoards{rooks, bishops, kings, knights}
+        Some(MagicBitBoards{rooks, bishops, kings, knights})
+    }
+
+    // The rook and bishop magics, in the same format accepted by generate.
+    pub fn magics(&self) -> (MagicTable, MagicTable) {
+        let mut rook_magics = [(0, 0); 64];
+        let mut bishop_magics = [(0, 0); 64];
+        for c in 0..64 {
+            rook_magics[c] = (self.rooks[c].magic(), 64 - self.rooks[c].shift());
+            bishop_magics[c] = (self.bishops[c].magic(), 64 - self.bishops[c].shift());
+        }
+        (rook_magics, bishop_magics)
+    }
+
+    // Serialized as 64 rook then 64 bishop entries, each a little-endian u64 magic followed by a
+    // single byte for the table width in bits.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (rook_magics, bishop_magics) = self.magics();
+        let mut bytes = Vec::with_capacity(128 * MAGIC_ENTRY_BYTES);
+        for (magic, bits) in rook_magics.iter().chain(bishop_magics.iter()) {
+            bytes.extend_from_slice(&magic.to_le_bytes());
+            bytes.push(*bits as u8);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<MagicBitBoards> {
+        if bytes.len() != 128 * MAGIC_ENTRY_BYTES {
+            return None;
+        }
+
+        let mut entries = bytes.chunks(MAGIC_ENTRY_BYTES).map(|chunk| {
+            let mut magic = [0u8; 8];
+            magic.copy_from_slice(&chunk[0..8]);
+            (u64::from_le_bytes(magic), chunk[8] as u32)
+        });
+
+        let mut rook_magics = [(0, 0); 64];
+        let mut bishop_magics = [(0, 0); 64];
+        rook_magics.iter_mut().chain(bishop_magics.iter_mut()).for_each(|m| *m = entries.next().unwrap());
+
+        // Guard against table widths that would overflow the shift.
+        if rook_magics.iter().chain(bishop_magics.iter()).any(|(_, bits)| *bits > 32) {
+            return None;
+        }
+
+        Self::try_generate(rook_magics, bishop_magics)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<MagicBitBoards> {
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid magic bitboards file"))
     }
 
     pub fn rook(&self, coord: BitCoord) -> &Magic {
-        &self.rooks[coord.0.trailing_zeros() as usize]
+        &self.rooks[coord.index() as usize]
     }
 
     pub fn bishop(&self, coord: BitCoord) -> &Magic {
-        &self.bishops[coord.0.trailing_zeros() as usize]
+        &self.bishops[coord.index() as usize]
     }
 
     pub fn king(&self, coord: BitCoord) -> BitBoard {
-        self.kings[coord.0.trailing_zeros() as usize]
+        self.kings[coord.index() as usize]
     }
 
     pub fn knight(&self, coord: BitCoord) -> BitBoard {
-        self.knights[coord.0.trailing_zeros() as usize]
+        self.knights[coord.index() as usize]
     }
 }
 
+impl AttackTables for MagicBitBoards {
+    fn rook_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard {
+        self.rook(coord).lookup(occupancy)
+    }
+
+    fn bishop_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard {
+        self.bishop(coord).lookup(occupancy)
+    }
+
+    fn king_attacks(&self, coord: BitCoord) -> BitBoard {
+        self.king(coord)
+    }
+
+    fn knight_attacks(&self, coord: BitCoord) -> BitBoard {
+        self.knight(coord)
+    }
+}
+
+const MAGIC_ENTRY_BYTES: usize = 9;
+
 #[derive(Clone)]
 pub struct Magic {
     table: Vec<BitBoard>,
@@ -157,6 +270,50 @@ impl Magic {
     }
 }
 
+// Tries random magic candidates for the given square, returning the one with the smallest table.
+pub fn find_magic(
+    coord: BitCoord,
+    mask: BitBoard,
+    movegen: fn (BitCoord, BitBoard) -> BitBoard,
+    attempts: u32,
+) -> Option<Magic> {
+    let moves = generate_moves(coord, mask, movegen);
+    let mut boards_cache: HashMap<BitBoard, Vec<BitBoard>> = HashMap::new();
+    let mut best: Option<Magic> = None;
+
+    for _ in 0..attempts {
+        // Sparse candidates are much more likely to be valid magics.
+        let candidate = rand::random::<u64>() & rand::random::<u64>() & rand::random::<u64>();
+
+        // Only accept strictly smaller tables than the best so far. Perfect-sized tables are rare,
+        // so allow some slack to begin with.
+        let max_size = match &best {
+            Some(m) => m.size() - 1,
+            None => 1 << (mask.count() + 3),
+        };
+
+        if let Some(m) = Magic::generate(candidate, mask, &moves, &mut boards_cache, max_size) {
+            best = Some(m);
+        }
+    }
+
+    best
+}
+
+pub fn find_all_rook_magics(attempts: u32) -> Vec<Option<Magic>> {
+    (0..64).map(|c| {
+        let coord = BitCoord(1 << c);
+        find_magic(coord, rook_mask(coord), rook_moves, attempts)
+    }).collect()
+}
+
+pub fn find_all_bishop_magics(attempts: u32) -> Vec<Option<Magic>> {
+    (0..64).map(|c| {
+        let coord = BitCoord(1 << c);
+        find_magic(coord, bishop_mask(coord), bishop_moves, attempts)
+    }).collect()
+}
+
 pub fn generate_moves(
     coord: BitCoord, 
     mask: BitBoard,
@@ -299,7 +456,7 @@ fn line_mask(start: BitCoord, shift: i32) -> BitBoard {
     mask & (!last_coord)
 }
 
-fn boards_for_mask(mask: BitBoard) -> Vec<BitBoard> {
+pub(crate) fn boards_for_mask(mask: BitBoard) -> Vec<BitBoard> {
     let mut boards = Vec::with_capacity(1024);
     boards.push(BitBoard::EMPTY);
     
@@ -522,6 +679,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_find_magic() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let coord = BitCoord(1 << (rng.gen_range(0..64)));
+        let mask = rook_mask(coord);
+        let magic = find_magic(coord, mask, rook_moves, 1000).expect("Found a magic");
+
+        for board in boards_for_mask(mask) {
+            assert_eq!(magic.lookup(board), rook_moves(coord, board));
+        }
+
+        for _ in 0..100 {
+            let board = BitBoard(rng.gen::<u64>());
+            assert_eq!(magic.lookup(board), rook_moves(coord, board));
+        }
+    }
+
     #[test]
     fn test_generated_rook_magic() {
         let mut rng = ChaCha8Rng::seed_from_u64(12345);
@@ -541,6 +715,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_save_load_round_trip() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let magic_bbs = MagicBitBoards::default();
+
+        let path = std::env::temp_dir().join(format!("chess-magics-{}.bin", std::process::id()));
+        magic_bbs.save(&path).unwrap();
+        let loaded = MagicBitBoards::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let from_bytes = MagicBitBoards::from_bytes(&magic_bbs.to_bytes()).unwrap();
+
+        for _ in 0..10_000 {
+            let coord = BitCoord(1 << (rng.gen_range(0..64)));
+            let board = BitBoard(rng.gen::<u64>());
+            for other in [&loaded, &from_bytes].iter() {
+                assert_eq!(other.rook(coord).lookup(board), magic_bbs.rook(coord).lookup(board));
+                assert_eq!(other.bishop(coord).lookup(board), magic_bbs.bishop(coord).lookup(board));
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_garbage() {
+        assert!(MagicBitBoards::from_bytes(&[0; 10]).is_none());
+        assert!(MagicBitBoards::from_bytes(&[0; 128 * 9]).is_none());
+    }
+
     #[test]
     fn test_generated_bishop_magic() {
         let mut rng = ChaCha8Rng::seed_from_u64(12345);
@@ -554,4 +756,24 @@ mod tests {
             assert_eq!(actual_moves, magic_moves);
         }
     }
+
+    #[test]
+    fn shared_matches_default() {
+        let shared = MagicBitBoards::shared();
+        assert!(std::ptr::eq(shared, MagicBitBoards::shared()));
+        assert_eq!(SHARED_BUILDS.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let default = MagicBitBoards::default();
+        let mut rng = ChaCha8Rng::seed_from_u64(818);
+        for c in 0..64 {
+            let coord = BitCoord(1 << c);
+            assert_eq!(shared.king_attacks(coord), default.king_attacks(coord));
+            assert_eq!(shared.knight_attacks(coord), default.knight_attacks(coord));
+            for _ in 0..16 {
+                let occupancy = BitBoard(rng.gen::<u64>() & rng.gen::<u64>());
+                assert_eq!(shared.rook_attacks(coord, occupancy), default.rook_attacks(coord, occupancy));
+                assert_eq!(shared.bishop_attacks(coord, occupancy), default.bishop_attacks(coord, occupancy));
+            }
+        }
+    }
 }
@@ -0,0 +1,168 @@
+use crate::types::{BitBoard, BitCoord, Colour, MaterialScore, Piece, Pieces};
+
+// Centipawns. The king is never traded, so it's worth nothing here.
+pub fn piece_value(piece: Piece) -> i64 {
+    match piece {
+        Piece::King => 0,
+        Piece::Queen => 900,
+        Piece::Rook => 500,
+        Piece::Bishop => 300,
+        Piece::Knight => 300,
+        Piece::Pawn => 100,
+    }
+}
+
+// Total value of one side's pieces.
+pub fn material(pieces: &Pieces) -> i64 {
+    non_pawn_material(pieces) + pieces.pawns.count() as i64 * piece_value(Piece::Pawn)
+}
+
+// Useful for game phase, and for spotting pawn endings where null moves are unsafe.
+pub fn non_pawn_material(pieces: &Pieces) -> i64 {
+    pieces.queens.count() as i64 * piece_value(Piece::Queen)
+        + pieces.rooks.count() as i64 * piece_value(Piece::Rook)
+        + pieces.bishops.count() as i64 * piece_value(Piece::Bishop)
+        + pieces.knights.count() as i64 * piece_value(Piece::Knight)
+}
+
+// Everything the running totals in SideState track, counted from scratch.
+pub fn material_score(pieces: &Pieces, colour: Colour) -> MaterialScore {
+    let count = |bb: BitBoard, pst: &[i64; 64]| -> i64 {
+        bb.iter().map(|c| pst[pst_index(c, colour)]).sum()
+    };
+    let pst = count(pieces.queens, &QUEEN_PST)
+        + count(pieces.rooks, &ROOK_PST)
+        + count(pieces.bishops, &BISHOP_PST)
+        + count(pieces.knights, &KNIGHT_PST)
+        + count(pieces.pawns, &PAWN_PST);
+
+    MaterialScore{
+        material: material(pieces),
+        pst_mg: pst + count(pieces.king, &KING_PST),
+        pst_eg: pst + count(pieces.king, &KING_ENDGAME_PST),
+    }
+}
+
+// What a single piece on `coord` adds to its side's MaterialScore.
+pub fn piece_score(piece: Piece, coord: BitCoord, colour: Colour) -> MaterialScore {
+    let ix = pst_index(coord, colour);
+    let (pst_mg, pst_eg) = match piece {
+        Piece::King => (KING_PST[ix], KING_ENDGAME_PST[ix]),
+        Piece::Queen => (QUEEN_PST[ix], QUEEN_PST[ix]),
+        Piece::Rook => (ROOK_PST[ix], ROOK_PST[ix]),
+        Piece::Bishop => (BISHOP_PST[ix], BISHOP_PST[ix]),
+        Piece::Knight => (KNIGHT_PST[ix], KNIGHT_PST[ix]),
+        Piece::Pawn => (PAWN_PST[ix], PAWN_PST[ix]),
+    };
+
+    MaterialScore{material: piece_value(piece), pst_mg, pst_eg}
+}
+
+// The tables below are laid out as seen from white's side of the board, so a8 is the first entry
+// and h1 the last. Black's pieces use the same tables, mirrored vertically.
+fn pst_index(coord: BitCoord, colour: Colour) -> usize {
+    let rank = match colour {
+        Colour::White => 7 - coord.rank(),
+        Colour::Black => coord.rank(),
+    };
+    (rank * 8 + coord.file()) as usize
+}
+
+const PAWN_PST: [i64; 64] = [
+      0,   0,   0,   0,   0,   0,   0,   0,
+     50,  50,  50,  50,  50,  50,  50,  50,
+     10,  10,  20,  30,  30,  20,  10,  10,
+      5,   5,  10,  25,  25,  10,   5,   5,
+      0,   0,   0,  20,  20,   0,   0,   0,
+      5,  -5, -10,   0,   0, -10,  -5,   5,
+      5,  10,  10, -20, -20,  10,  10,   5,
+      0,   0,   0,   0,   0,   0,   0,   0,
+];
+
+const KNIGHT_PST: [i64; 64] = [
+    -50, -40, -30, -30, -30, -30, -40, -50,
+    -40, -20,   0,   0,   0,   0, -20, -40,
+    -30,   0,  10,  15,  15,  10,   0, -30,
+    -30,   5,  15,  20,  20,  15,   5, -30,
+    -30,   0,  15,  20,  20,  15,   0, -30,
+    -30,   5,  10,  15,  15,  10,   5, -30,
+    -40, -20,   0,   5,   5,   0, -20, -40,
+    -50, -40, -30, -30, -30, -30, -40, -50,
+];
+
+const BISHOP_PST: [i64; 64] = [
+    -20, -10, -10, -10, -10, -10, -10, -20,
+    -10,   0,   0,   0,   0,   0,   0, -10,
+    -10,   0,   5,  10,  10,   5,   0, -10,
+    -10,   5,   5,  10,  10,   5,   5, -10,
+    -10,   0,  10,  10,  10,  10,   0, -10,
+    -10,  10,  10,  10,  10,  10,  10, -10,
+    -10,   5,   0,   0,   0,   0,   5, -10,
+    -20, -10, -10, -10, -10, -10, -10, -20,
+];
+
+const ROOK_PST: [i64; 64] = [
+      0,   0,   0,   0,   0,   0,   0,   0,
+      5,  10,  10,  10,  10,  10,  10,   5,
+     -5,   0,   0,   0,   0,   0,   0,  -5,
+     -5,   0,   0,   0,   0,   0,   0,  -5,
+     -5,   0,   0,   0,   0,   0,   0,  -5,
+     -5,   0,   0,   0,   0,   0,   0,  -5,
+     -5,   0,   0,   0,   0,   0,   0,  -5,
+      0,   0,   0,   5,   5,   0,   0,   0,
+];
+
+const QUEEN_PST: [i64; 64] = [
+    -20, -10, -10,  -5,  -5, -10, -10, -20,
+    -10,   0,   0,   0,   0,   0,   0, -10,
+    -10,   0,   5,   5,   5,   5,   0, -10,
+     -5,   0,   5,   5,   5,   5,   0,  -5,
+      0,   0,   5,   5,   5,   5,   0,  -5,
+    -10,   5,   5,   5,   5,   5,   0, -10,
+    -10,   0,   5,   0,   0,   0,   0, -10,
+    -20, -10, -10,  -5,  -5, -10, -10, -20,
+];
+
+const KING_PST: [i64; 64] = [
+    -30, -40, -40, -50, -50, -40, -40, -30,
+    -30, -40, -40, -50, -50, -40, -40, -30,
+    -30, -40, -40, -50, -50, -40, -40, -30,
+    -30, -40, -40, -50, -50, -40, -40, -30,
+    -20, -30, -30, -40, -40, -30, -30, -20,
+    -10, -20, -20, -20, -20, -20, -20, -10,
+     20,  20,   0,   0,   0,   0,  20,  20,
+     20,  30,  10,   0,   0,  10,  30,  20,
+];
+
+const KING_ENDGAME_PST: [i64; 64] = [
+    -50, -40, -30, -20, -20, -30, -40, -50,
+    -30, -20, -10,   0,   0, -10, -20, -30,
+    -30, -10,  20,  30,  30,  20, -10, -30,
+    -30, -10,  30,  40,  40,  30, -10, -30,
+    -30, -10,  30,  40,  40,  30, -10, -30,
+    -30, -10,  20,  30,  30,  20, -10, -30,
+    -30, -30,   0,   0,   0,   0, -30, -30,
+    -50, -30, -30, -30, -30, -30, -30, -50,
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::fen::{load_fen, STARTING_POSITION};
+    use crate::material::*;
+
+    #[test]
+    fn start_position() {
+        let state = load_fen(STARTING_POSITION);
+        assert_eq!(material(&state.white.pieces), 8 * 100 + 2 * 300 + 2 * 300 + 2 * 500 + 900);
+        assert_eq!(material(&state.white.pieces), material(&state.black.pieces));
+    }
+
+    #[test]
+    fn non_pawn_material_excludes_pawns() {
+        let state = load_fen("4k3/pppp4/8/8/8/8/PPPPPPPP/3RK3 w - - 0 1");
+        assert_eq!(non_pawn_material(&state.white.pieces), 500);
+        assert_eq!(material(&state.white.pieces), 500 + 800);
+        assert_eq!(non_pawn_material(&state.black.pieces), 0);
+        assert_eq!(material(&state.black.pieces), 400);
+    }
+}
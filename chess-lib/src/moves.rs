@@ -1,7 +1,132 @@
-use crate::magic::{Line, MagicBitBoards};
-use crate::types::{BitBoard, BitCoord, Colour, GameState, Move, Piece, Pieces};
+use crate::game::en_passant_victim;
+use crate::magic::{AttackTables, MagicBitBoards};
+use crate::types::{BitBoard, BitCoord, Colour, GameState, Move, Piece, Pieces, SideState};
 
 pub fn legal_moves(state: &GameState, mbb: &MagicBitBoards) -> Vec<Move> {
+    let mut moves: Vec<Move> = Vec::with_capacity(256);
+    legal_moves_into(state, mbb, &mut moves);
+    moves
+}
+
+// Clears `buf` and fills it with the legal moves, so callers can reuse one buffer across positions.
+pub fn legal_moves_into(state: &GameState, mbb: &MagicBitBoards, buf: &mut Vec<Move>) {
+    buf.clear();
+    generate_legal_moves(state, mbb, false, |m| {
+        buf.push(m);
+        true
+    });
+}
+
+// Only moves which capture an opposing piece, including en-passant and capturing promotions.
+pub fn legal_captures(state: &GameState, mbb: &MagicBitBoards) -> Vec<Move> {
+    let mut moves: Vec<Move> = Vec::with_capacity(64);
+    generate_legal_moves(state, mbb, true, |m| {
+        moves.push(m);
+        true
+    });
+    moves
+}
+
+// Same as legal_moves(...).len(), without building the list.
+pub fn count_legal_moves(state: &GameState, mbb: &MagicBitBoards) -> usize {
+    let mut count = 0;
+    generate_legal_moves(state, mbb, false, |_| {
+        count += 1;
+        true
+    });
+    count
+}
+
+// Stops at the first legal move found, which is all that's needed to spot mate or stalemate.
+pub fn has_legal_move(state: &GameState, mbb: &MagicBitBoards) -> bool {
+    let mut found = false;
+    generate_legal_moves(state, mbb, false, |_| {
+        found = true;
+        false
+    });
+    found
+}
+
+// Every move ignoring checks and pins, i.e. what legal move generation starts from before
+// removing moves which leave the king in check. Castling is only included when legal, since
+// its extra conditions aren't just about where the king ends up.
+pub fn pseudo_legal_moves(state: &GameState, mbb: &MagicBitBoards) -> Vec<Move> {
+    let mut moves: Vec<Move> = Vec::with_capacity(256);
+    let mut push = |m| {
+        moves.push(m);
+        true
+    };
+
+    if generate_pseudo_legal_moves(state, mbb, |_, _, targets| targets, &mut push) && !state.is_in_check(mbb) {
+        generate_castles(state, mbb, &mut push);
+    }
+
+    moves
+}
+
+// Passes each pseudo-legal move, other than castling, to `emit`. `mask` is given the piece, its
+// square and the squares it could move to, and returns the ones to keep.
+// Returns false if `emit` asked to stop early.
+fn generate_pseudo_legal_moves<M, F>(state: &GameState, mbb: &MagicBitBoards, mut mask: M, emit: &mut F) -> bool
+where
+    M: FnMut(Piece, BitCoord, BitBoard) -> BitBoard,
+    F: FnMut(Move) -> bool,
+{
+    let colour = state.active_colour;
+    let (side, other_side) = match colour {
+        Colour::White => (&state.white, &state.black),
+        Colour::Black => (&state.black, &state.white),
+    };
+
+    let active_occupancy = side.pieces.all();
+    let other_occupancy = other_side.pieces.all();
+
+    for src in active_occupancy.iter() {
+        let piece = side.pieces.get_piece(src).expect("No piece on square");
+        let targets = magic_piece_movement(piece, active_occupancy, other_occupancy, src, colour, state.en_passant, mbb);
+        for tgt in mask(piece, src, targets).iter() {
+            let keep_going = if piece == Piece::Pawn && state.en_passant == Some(tgt) {
+                emit(Move::EnPassant(src, tgt))
+            } else {
+                emit_piece_move(piece, src, tgt, emit)
+            };
+            if !keep_going {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// Assumes the side to move isn't in check. Returns false if `emit` asked to stop early.
+fn generate_castles<F: FnMut(Move) -> bool>(state: &GameState, mbb: &MagicBitBoards, emit: &mut F) -> bool {
+    let colour = state.active_colour;
+    let (side, other_side) = match colour {
+        Colour::White => (&state.white, &state.black),
+        Colour::Black => (&state.black, &state.white),
+    };
+    let occupancy = side.pieces.all() | other_side.pieces.all();
+
+    if side.can_castle_queenside && can_castle(side, &other_side.pieces, occupancy, colour, false, mbb) && !emit(Move::LongCastle) {
+        return false;
+    }
+
+    if side.can_castle_kingside && can_castle(side, &other_side.pieces, occupancy, colour, true, mbb) {
+        return emit(Move::Castle);
+    }
+
+    true
+}
+
+// Passes each legal move to `emit`, stopping early if it returns false.
+fn generate_legal_moves<F: FnMut(Move) -> bool>(state: &GameState, mbb: &MagicBitBoards, captures_only: bool, emit: F) {
+    generate_moves(state, mbb, captures_only, true, emit);
+}
+
+// With `use_evasions` off, positions in check go through the general path, filtering every
+// pseudo-legal move. Only the tests want that, as a reference for the evasion generator.
+fn generate_moves<F: FnMut(Move) -> bool>(state: &GameState, mbb: &MagicBitBoards, captures_only: bool, use_evasions: bool, mut emit: F) {
     let colour = state.active_colour;
 
     let (side, other_side) = match colour {
@@ -30,172 +155,302 @@ pub fn legal_moves(state: &GameState, mbb: &MagicBitBoards) -> Vec<Move> {
 
     let is_in_check = allowed_non_king_moves != BitBoard(0xFF_FF_FF_FF_FF_FF_FF_FF);
 
-    // Now get all moves disregarding restrictions.
-    let mostly_legal_moves = side.pieces.all()
-        .iter()
-        .flat_map(|src| {
-            let piece = side.pieces.get_piece(src).expect("No piece on square");
-            let mut pseudo_legals = magic_piece_movement(
-                piece,
-                active_occupancy,
-                other_occupancy,
-                occupancy,
-                src,
-                colour,
-                state.en_passant,
-            mbb);
-            
-            // If there's en-passant, store it off and add it back in later.
-            let ep_mask = if side.pieces.pawns & src != BitBoard::EMPTY {
-                state.en_passant.map(|ep| pseudo_legals & ep).unwrap_or(BitBoard::EMPTY)
-            } else {
-                BitBoard::EMPTY
-            };
+    if is_in_check && use_evasions {
+        generate_evasions(state, state.checkers(mbb), allowed_non_king_moves, &pins, captures_only, mbb, &mut emit);
+        return;
+    }
 
-            if src.0 != side.pieces.king.0 {
-                pseudo_legals = pseudo_legals & allowed_non_king_moves
-            }
+    let allowed_targets = if captures_only {
+        other_occupancy
+    } else {
+        BitBoard(0xFF_FF_FF_FF_FF_FF_FF_FF)
+    };
 
-            match pins[src.0.trailing_zeros() as usize] {
-                Some(allowed) => pseudo_legals = pseudo_legals & allowed,
-                None => ()
-            }
+    // Restrict the pseudo-legal moves to those which deal with any check and respect pins.
+    let mask = |piece: Piece, src: BitCoord, targets: BitBoard| {
+        // En-passant is checked separately below, since it can uncover a check along the rank.
+        let ep_mask = match state.en_passant {
+            Some(ep) if piece == Piece::Pawn => targets & ep,
+            _ => BitBoard::EMPTY,
+        };
+
+        let mut targets = targets & allowed_targets;
+        if piece != Piece::King {
+            targets = targets & allowed_non_king_moves;
+        }
+        if let Some(allowed) = pins[src.index() as usize] {
+            targets = targets & allowed;
+        }
 
-            // Add back in en-passant.
-            pseudo_legals = pseudo_legals | ep_mask;
+        targets | ep_mask
+    };
 
-            pseudo_legals.iter()
-                .map(move |tgt| Move::Normal(piece, src, tgt))
-        });
+    let occupancy_without_king = occupancy & (!side.pieces.king);
+
+    // Remove king moves which would put the king in check, and illegal en-passant.
+    let mut legal_emit = |m| match m {
+        Move::Normal(Piece::King, _, tgt) if square_under_attack(occupancy_without_king, &other_side.pieces, tgt, colour, mbb) => true,
+        Move::EnPassant(src, tgt) if !en_passant_is_legal(side, other_side, src, tgt, colour, mbb) => true,
+        _ => emit(m),
+    };
 
-    // Cloning the whole board probably not the most efficient.
+    if !generate_pseudo_legal_moves(state, mbb, mask, &mut legal_emit) || captures_only || is_in_check {
+        return;
+    }
+
+    generate_castles(state, mbb, &mut emit);
+}
+
+// Only king moves, captures of the checker and blocks along the check ray can get out of check,
+// so generate those directly rather than filtering every pseudo-legal move.
+// `block_squares` are the squares non-king pieces may move to, which includes the checker.
+fn generate_evasions<F: FnMut(Move) -> bool>(
+    state: &GameState,
+    checkers: BitBoard,
+    block_squares: BitBoard,
+    pins: &[Option<BitBoard>; 64],
+    captures_only: bool,
+    mbb: &MagicBitBoards,
+    emit: &mut F,
+) {
+    let colour = state.active_colour;
+    let (side, other_side) = match colour {
+        Colour::White => (&state.white, &state.black),
+        Colour::Black => (&state.black, &state.white),
+    };
+
+    let king = BitCoord(side.pieces.king.0);
+    let occupancy = side.pieces.all() | other_side.pieces.all();
     let occupancy_without_king = occupancy & (!side.pieces.king);
 
-    // Remove king moves which would put the king in check.
-    let moves_without_suicidal_king = mostly_legal_moves.filter(|m| {
-        match m {
-            Move::Normal(piece, _, tgt) => {
-                if *piece == Piece::King {
-                    !square_under_attack(occupancy_without_king, &other_side.pieces, *tgt, colour, mbb)
-                } else {
-                    true
+    let king_targets = if captures_only {
+        other_side.pieces.all()
+    } else {
+        !side.pieces.all()
+    };
+
+    for tgt in (mbb.king_attacks(king) & king_targets).iter() {
+        if !square_under_attack(occupancy_without_king, &other_side.pieces, tgt, colour, mbb) && !emit(Move::Normal(Piece::King, king, tgt)) {
+            return;
+        }
+    }
+
+    // In double check only the king can move.
+    if checkers.count() > 1 {
+        return;
+    }
+
+    // Pinned pieces can't help either, since the pin and the check run along different lines
+    // out from the king.
+    let movers = side.pieces.all()
+        .iter()
+        .filter(|c| *c != king && pins[c.index() as usize].is_none())
+        .fold(BitBoard::EMPTY, |bb, c| bb | c);
+
+    let checker = BitCoord(checkers.0);
+    for src in (attackers_to(&side.pieces, colour, occupancy, checker, mbb) & movers).iter() {
+        let piece = side.pieces.get_piece(src).expect("No piece on square");
+        if !emit_piece_move(piece, src, checker, emit) {
+            return;
+        }
+    }
+
+    if !captures_only {
+        // Pawns attack diagonally but block by pushing, so they're handled separately.
+        let pawns = side.pieces.pawns & movers;
+        let pieces = movers & (!side.pieces.pawns);
+        for tgt in (block_squares & (!checkers)).iter() {
+            for src in (attackers_to(&side.pieces, colour, occupancy, tgt, mbb) & pieces).iter() {
+                let piece = side.pieces.get_piece(src).expect("No piece on square");
+                if !emit_piece_move(piece, src, tgt, emit) {
+                    return;
+                }
+            }
+
+            for src in (pawn_pushes_to(tgt, colour, occupancy) & pawns).iter() {
+                if !emit_piece_move(Piece::Pawn, src, tgt, emit) {
+                    return;
                 }
-            },
-            _ => true,
+            }
         }
-    });
+    }
 
-    // This is as far as we can reasonably go using Iterators.
-    // Collect now and do the rest in a loop.
-    let mut moves: Vec<Move> = Vec::with_capacity(256);
-    for m in moves_without_suicidal_king {
-        match m {
-            Move::Normal(piece, src, tgt) => {
-                let is_pawn = piece == Piece::Pawn;
-                if is_pawn {
-                    // Expand pawn moves to last rank.
-                    // Don't have to check colours since pawns can't move backwards.
-                    if BitBoard(0xFF_00_00_00_00_00_00_FF) & tgt != BitBoard::EMPTY {
-                        moves.push(Move::Promotion(src, tgt, Piece::Queen));
-                        moves.push(Move::Promotion(src, tgt, Piece::Rook));
-                        moves.push(Move::Promotion(src, tgt, Piece::Bishop));
-                        moves.push(Move::Promotion(src, tgt, Piece::Knight));
-                    } else if state.en_passant.map(|ep| ep == tgt.into()).unwrap_or(false) {
-                        // Remove en-passant if it would leave the king in check.
-                        // Can't think of a better way to do this than just evaluating the new
-                        // board for checks.
-                        let mut new_pieces = side.pieces.clone();
-                        let mut new_other_pieces = other_side.pieces.clone();
-                        let taken_coord = match colour {
-                            Colour::White => Into::<BitCoord>::into(tgt) >> 8,
-                            Colour::Black => Into::<BitCoord>::into(tgt) << 8,
-                        };
-
-                        new_pieces.put_piece(Piece::Pawn, tgt.into());
-                        new_pieces.clear_square(src.into());
-                        new_other_pieces.clear_square(taken_coord);
-                        let new_occupancy = new_pieces.all() | new_other_pieces.all();
-                        if !square_under_attack(new_occupancy, &new_other_pieces, BitCoord(new_pieces.king.0), colour, mbb) {
-                            moves.push(m);
-                        }
-                    } else {
-                        moves.push(m);
-                    }
-                } else {
-                    moves.push(m);
+    // En-passant can take a checking pawn, or land on the check ray.
+    if let Some(ep) = state.en_passant {
+        let taken = match colour {
+            Colour::White => ep >> 8,
+            Colour::Black => ep << 8,
+        };
+
+        if checkers.contains(taken) || block_squares.contains(ep) {
+            for src in (pawn_attacks(ep, Colour::other(colour)) & side.pieces.pawns).iter() {
+                if en_passant_is_legal(side, other_side, src, ep, colour, mbb) && !emit(Move::EnPassant(src, ep)) {
+                    return;
                 }
-            },
-            _ => panic!("Should only have normal moves at this stage"),
+            }
         }
     }
+}
+
+// The squares a pawn of the given colour could push from to reach the empty square `tgt`.
+fn pawn_pushes_to(tgt: BitCoord, colour: Colour, occupancy: BitBoard) -> BitBoard {
+    let (single, double_rank) = match colour {
+        Colour::White => (tgt >> 8, 3),
+        Colour::Black => (tgt << 8, 4),
+    };
+
+    if tgt.rank() == double_rank && !occupancy.contains(single) {
+        let double = match colour {
+            Colour::White => tgt >> 16,
+            Colour::Black => tgt << 16,
+        };
+        BitBoard(single.0) | double
+    } else {
+        BitBoard(single.0)
+    }
+}
+
+// Expands pawn moves to the last rank into each promotion.
+// Don't have to check colours since pawns can't move backwards.
+fn emit_piece_move<F: FnMut(Move) -> bool>(piece: Piece, src: BitCoord, tgt: BitCoord, emit: &mut F) -> bool {
+    if piece == Piece::Pawn && BitBoard(0xFF_00_00_00_00_00_00_FF) & tgt != BitBoard::EMPTY {
+        [Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight].iter().all(|pc| emit(Move::Promotion(src, tgt, *pc)))
+    } else {
+        emit(Move::Normal(piece, src, tgt))
+    }
+}
 
+// Whether en-passant would leave the king in check.
+// Can't think of a better way to do this than just evaluating the new board for checks.
+fn en_passant_is_legal(side: &SideState, other_side: &SideState, src: BitCoord, tgt: BitCoord, colour: Colour, mbb: &MagicBitBoards) -> bool {
+    let mut new_pieces = side.pieces.clone();
+    let mut new_other_pieces = other_side.pieces.clone();
+    let taken_coord = match colour {
+        Colour::White => tgt >> 8,
+        Colour::Black => tgt << 8,
+    };
+
+    new_pieces.put_piece(Piece::Pawn, tgt);
+    new_pieces.clear_square(src);
+    new_other_pieces.clear_square(taken_coord);
+    let new_occupancy = new_pieces.all() | new_other_pieces.all();
+    !square_under_attack(new_occupancy, &new_other_pieces, BitCoord(new_pieces.king.0), colour, mbb)
+}
 
-    // Add castling if legal.
+// Assumes the side has the castling right and isn't in check.
+// Written in terms of files so that it also handles Chess960, where the king and rook may start
+// anywhere on the back rank, but always land on the same squares as in standard chess.
+fn can_castle(side: &SideState, other_pieces: &Pieces, occupancy: BitBoard, colour: Colour, kingside: bool, mbb: &MagicBitBoards) -> bool {
     let home_rank = match colour {
-        Colour::White => BitBoard(0x00_00_00_00_00_00_00_FF),
-        Colour::Black => BitBoard(0xFF_00_00_00_00_00_00_00),
+        Colour::White => 0u32,
+        Colour::Black => 7u32,
     };
 
-    if side.can_castle_queenside {
-        if !(
-            is_in_check ||
-            occupancy & 0x70_70_70_70_70_70_70_70u64 & home_rank != BitBoard::EMPTY ||
-            square_under_attack(occupancy, &other_side.pieces, BitCoord(0x20_00_00_00_00_00_00_20) & home_rank, colour, mbb) ||
-            square_under_attack(occupancy, &other_side.pieces, BitCoord(0x10_00_00_00_00_00_00_10) & home_rank, colour, mbb)
-        ) {
-            moves.push(Move::LongCastle);
-        }
+    let (rook_file, king_target, rook_target) = if kingside {
+        (side.kingside_rook_file, 6u32, 5u32)
+    } else {
+        (side.queenside_rook_file, 2u32, 3u32)
+    };
+
+    let king: BitCoord = (side.king_file, home_rank).into();
+    let rook: BitCoord = (rook_file, home_rank).into();
+    if side.pieces.king & king == BitBoard::EMPTY || side.pieces.rooks & rook == BitBoard::EMPTY {
+        return false;
     }
 
-    if side.can_castle_kingside {
-        if !(
-            is_in_check ||
-            occupancy & 0x06_06_06_06_06_06_06_06u64 & home_rank != BitBoard::EMPTY ||
-            square_under_attack(occupancy, &other_side.pieces, BitCoord(0x04_00_00_00_00_00_00_04) & home_rank, colour, mbb) ||
-            square_under_attack(occupancy, &other_side.pieces, BitCoord(0x02_00_00_00_00_00_00_02) & home_rank, colour, mbb)
-        ) {
-            moves.push(Move::Castle);
-        }
+    // Everything the king and rook pass over or land on must be empty, apart from themselves.
+    let files = [side.king_file, rook_file, king_target, rook_target];
+    let span = rank_span(home_rank, *files.iter().min().unwrap(), *files.iter().max().unwrap());
+    let others = occupancy & !king & !rook;
+    if span & others != BitBoard::EMPTY {
+        return false;
     }
 
-    moves
+    // The king may not pass through or land on an attacked square.
+    // We already know the starting square isn't attacked.
+    let king_path = rank_span(home_rank, side.king_file.min(king_target), side.king_file.max(king_target)) & !king;
+    king_path.iter().all(|c| !square_under_attack(others, other_pieces, c, colour, mbb))
 }
 
-#[derive(Debug)]
-struct Pin(BitCoord, BitBoard);
+// The squares on the rank between the two files, inclusive.
+fn rank_span(rank: u32, from_file: u32, to_file: u32) -> BitBoard {
+    (from_file..=to_file).fold(BitBoard::EMPTY, |bb, file| bb | BitCoord::from((file, rank)))
+}
 
+// Whether any of `other_pieces` attack `coord`. `colour` is the side being attacked.
 pub fn square_under_attack(occupancy: BitBoard, other_pieces: &Pieces, coord: BitCoord, colour: Colour, mbb: &MagicBitBoards) -> bool {
-    let straight_atks = mbb.rook(coord).lookup(occupancy) & (other_pieces.rooks | other_pieces.queens);
-    if straight_atks != BitBoard::EMPTY {
-        return true;
-    }
+    !attackers_to(other_pieces, Colour::other(colour), occupancy, coord, mbb).is_empty()
+}
 
-    let diag_atks = mbb.bishop(coord).lookup(occupancy) & (other_pieces.bishops | other_pieces.queens);
-    if diag_atks != BitBoard::EMPTY {
-        return true;
-    }
+// The squares of all `pieces`, which belong to `colour`, that attack `sq`.
+// Sliders are blocked by anything in `occupancy`.
+pub fn attackers_to(pieces: &Pieces, colour: Colour, occupancy: BitBoard, sq: BitCoord, mbb: &MagicBitBoards) -> BitBoard {
+    let straight = mbb.rook_attacks(sq, occupancy) & (pieces.rooks | pieces.queens);
+    let diagonal = mbb.bishop_attacks(sq, occupancy) & (pieces.bishops | pieces.queens);
+    let knights = mbb.knight_attacks(sq) & pieces.knights;
+    let king = mbb.king_attacks(sq) & pieces.king;
 
-    // Knights
-    let knight_atks = mbb.knight(coord) & other_pieces.knights;
-    if knight_atks != BitBoard::EMPTY {
-        return true;
-    }
+    // Our pawns attack sq from the squares an opposing pawn on sq would attack.
+    let pawns = pawn_attacks(sq, Colour::other(colour)) & pieces.pawns;
 
-    // King
-    let king_atks = mbb.king(coord) & other_pieces.king;
-    if king_atks != BitBoard::EMPTY {
-        return true;
-    }
+    straight | diagonal | knights | king | pawns
+}
 
-    // Pawns
-    let pawn_atks = pawn_attacks(coord, colour) & other_pieces.pawns;
-    if pawn_atks != BitBoard::EMPTY {
+// Whether `mv` would put the opponent in check, without making it. Covers checks from the piece
+// where it lands and from sliders it uncovers, including by en passant and castling.
+pub fn gives_check(state: &GameState, mv: Move, mbb: &MagicBitBoards) -> bool {
+    let colour = state.active_colour;
+    let (side, other_side, home_rank) = match colour {
+        Colour::White => (&state.white, &state.black, 0u32),
+        Colour::Black => (&state.black, &state.white, 7u32),
+    };
+    let king = match other_side.pieces.king.iter().next() {
+        Some(king) => king,
+        None => return false,
+    };
+
+    // The piece which could give a direct check and where it lands, plus the squares the move
+    // empties and any other square it fills.
+    let (piece, tgt, vacated, also_filled) = match mv {
+        Move::Normal(piece, src, tgt) => (piece, tgt, BitBoard(src.0), BitBoard::EMPTY),
+        Move::Promotion(src, tgt, pc) => (pc, tgt, BitBoard(src.0), BitBoard::EMPTY),
+        Move::EnPassant(src, tgt) => (Piece::Pawn, tgt, BitBoard(src.0) | en_passant_victim(tgt, colour), BitBoard::EMPTY),
+        Move::Castle | Move::LongCastle => {
+            let (rook_file, king_target, rook_target) = if mv == Move::Castle {
+                (side.kingside_rook_file, 6u32, 5u32)
+            } else {
+                (side.queenside_rook_file, 2u32, 3u32)
+            };
+            let square = |file: u32| BitCoord::from((file, home_rank));
+            let vacated = BitBoard(square(side.king_file).0) | square(rook_file);
+            (Piece::Rook, square(rook_target), vacated, BitBoard(square(king_target).0))
+        },
+    };
+
+    let occupancy = (side.pieces.all() | other_side.pieces.all()) & !vacated | tgt | also_filled;
+
+    let direct = match piece {
+        Piece::Pawn => pawn_attacks(tgt, colour),
+        Piece::Knight => mbb.knight_attacks(tgt),
+        Piece::Bishop => mbb.bishop_attacks(tgt, occupancy),
+        Piece::Rook => mbb.rook_attacks(tgt, occupancy),
+        Piece::Queen => mbb.rook_attacks(tgt, occupancy) | mbb.bishop_attacks(tgt, occupancy),
+        // Kings can't give check.
+        Piece::King => BitBoard::EMPTY,
+    };
+    if direct.contains(king) {
         return true;
     }
 
-    false
+    // The moved piece was covered above, so only sliders that stayed put can be uncovered.
+    let straight = (side.pieces.rooks | side.pieces.queens) & !vacated;
+    let diagonal = (side.pieces.bishops | side.pieces.queens) & !vacated;
+    !(mbb.rook_attacks(king, occupancy) & straight).is_empty()
+        || !(mbb.bishop_attacks(king, occupancy) & diagonal).is_empty()
 }
 
+// Returns the squares non-king pieces may move to given any checks on the king at `coord`,
+// and fills in `pins` for friendly pieces pinned to it.
 fn attacks_on_square(
     pins: &mut [Option<BitBoard>; 64],
     occupancy: BitBoard,
@@ -206,62 +461,25 @@ fn attacks_on_square(
 ) -> BitBoard {
     let mut allowed_moves = BitBoard(0xFF_FF_FF_FF_FF_FF_FF_FF);
     let other_occupancy = other_pieces.all();
-
-    // Straight line pieces.
-    [1, -1, 8, -8].iter().for_each(|dir| {
-        let mut blocks = BitBoard::EMPTY;
-        let mut pin: Option<BitCoord> = None;
-        for c in Line::new(coord, *dir) {
-            blocks = blocks | c;
-            if (other_pieces.rooks | other_pieces.queens) & c != BitBoard::EMPTY {
-                // Attack is real.
-                match pin {
-                    None => allowed_moves = allowed_moves & blocks,
-                    Some(p) => pins[p.0.trailing_zeros() as usize] = Some(blocks),
-                }
-                break;
-            } else if other_occupancy & c != BitBoard::EMPTY {
-                // Blocked by opposing piece.
-                break;
-            } else if occupancy & c != BitBoard::EMPTY {
-                // Blocked by friendly piece.
-                if pin.is_some() {
-                    // Two blocking pieces, no attack.
-                    break;
-                } else {
-                    pin = Some(c);
-                }
-            }
-        }
-    });
-
-    // Diagonal line pieces.
-    [7, -7, 9, -9].iter().for_each(|dir| {
-        let mut blocks = BitBoard::EMPTY;
-        let mut pin: Option<BitCoord> = None;
-        for c in Line::new(coord, *dir) {
-            blocks = blocks | c;
-            if (other_pieces.bishops | other_pieces.queens) & c != BitBoard::EMPTY {
-                // Attack is real.
-                match pin {
-                    None => allowed_moves = allowed_moves & blocks,
-                    Some(p) => pins[p.0.trailing_zeros() as usize] = Some(blocks),
-                }
-                break;
-            } else if other_occupancy & c != BitBoard::EMPTY {
-                // Blocked by opposing piece.
-                break;
-            } else if occupancy & c != BitBoard::EMPTY {
-                // Blocked by friendly piece.
-                if pin.is_some() {
-                    // Two blocking pieces, no attack.
-                    break;
-                } else {
-                    pin = Some(c);
-                }
-            }
+    let friendly_occupancy = occupancy & !other_occupancy;
+    let king = BitBoard(coord.0);
+
+    // Look through our own pieces to find sliders which either attack the king directly, or
+    // would if exactly one friendly piece got out of the way.
+    let straight_sliders = mbb.rook_attacks(coord, other_occupancy) & (other_pieces.rooks | other_pieces.queens);
+    let diagonal_sliders = mbb.bishop_attacks(coord, other_occupancy) & (other_pieces.bishops | other_pieces.queens);
+
+    let straight = straight_sliders.iter().map(|s| (s, mbb.rook_attacks(coord, BitBoard(s.0)) & mbb.rook_attacks(s, king)));
+    let diagonal = diagonal_sliders.iter().map(|s| (s, mbb.bishop_attacks(coord, BitBoard(s.0)) & mbb.bishop_attacks(s, king)));
+
+    for (slider, between) in straight.chain(diagonal) {
+        let blockers = between & friendly_occupancy;
+        match blockers.count() {
+            0 => allowed_moves = allowed_moves & (between | slider),
+            1 => pins[blockers.0.trailing_zeros() as usize] = Some(between | slider),
+            _ => (),
         }
-    });
+    }
 
     // Knights
     let knight_atks = mbb.knight(coord) & other_pieces.knights;
@@ -284,31 +502,31 @@ fn attacks_on_square(
     allowed_moves
 }
 
-fn magic_piece_movement(
+fn magic_piece_movement<T: AttackTables>(
     piece: Piece,
     active_occupancy: BitBoard,
     other_occupancy: BitBoard,
-    occupancy: BitBoard,
     coord: BitCoord,
     colour: Colour,
     en_passant: Option<BitCoord>,
-    mbb: &MagicBitBoards,
+    tables: &T,
 ) -> BitBoard {
+    let occupancy = active_occupancy | other_occupancy;
     let moves = match piece {
         Piece::Queen => {
-            mbb.rook(coord).lookup(occupancy) | mbb.bishop(coord).lookup(occupancy)
+            tables.rook_attacks(coord, occupancy) | tables.bishop_attacks(coord, occupancy)
         },
         Piece::Rook => {
-            mbb.rook(coord).lookup(occupancy)
+            tables.rook_attacks(coord, occupancy)
         },
         Piece::Bishop => {
-            mbb.bishop(coord).lookup(occupancy)
+            tables.bishop_attacks(coord, occupancy)
         },
         Piece::Knight => {
-            mbb.knight(coord)
+            tables.knight_attacks(coord)
         },
         Piece::King => {
-            mbb.king(coord)
+            tables.king_attacks(coord)
         },
         Piece::Pawn => {
             magic_pawn_moves(active_occupancy, other_occupancy, coord, colour, en_passant)
@@ -348,11 +566,12 @@ fn magic_pawn_moves(active_occupancy: BitBoard, other_occupancy: BitBoard, coord
     moves | (atks & tgts)
 }
 
-fn pawn_attacks(coord: BitCoord, colour: Colour) -> BitBoard {
+// The squares a pawn of the given colour on `coord` attacks.
+pub fn pawn_attacks(coord: BitCoord, colour: Colour) -> BitBoard {
     match colour {
         Colour::White => {
             let mut atks = BitBoard::EMPTY;
-            let file = (63 - coord.0.trailing_zeros()) % 8;
+            let file = coord.file();
             if file != 0 {
                 atks = atks | (coord << 9);
             }
@@ -364,7 +583,7 @@ fn pawn_attacks(coord: BitCoord, colour: Colour) -> BitBoard {
         },
         Colour::Black => {
             let mut atks = BitBoard::EMPTY;
-            let file = (63 - coord.0.trailing_zeros()) % 8;
+            let file = coord.file();
             if file != 0 {
                 atks = atks | (coord >>  7);
             }
@@ -377,3 +596,343 @@ fn pawn_attacks(coord: BitCoord, colour: Colour) -> BitBoard {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::fen::{load_fen, STARTING_POSITION};
+    use crate::fmt::parse_coord;
+    use crate::magic::MagicBitBoards;
+    use crate::moves::*;
+
+    fn non_king_moves(fen: &str, mbb: &MagicBitBoards) -> Vec<Move> {
+        legal_moves(&load_fen(fen), mbb)
+            .into_iter()
+            .filter(|m| match m {
+                Move::Normal(Piece::King, _, _) => false,
+                _ => true,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pinned_pieces() {
+        let mbb = MagicBitBoards::default();
+
+        // Knight pinned on the file can't move at all.
+        assert_eq!(non_king_moves("4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1", &mbb), vec![]);
+
+        // Rook pinned on the file can slide along it, up to and including the pinner.
+        let rook_moves = non_king_moves("4k3/4r3/8/8/8/8/4R3/4K3 w - - 0 1", &mbb);
+        assert_eq!(rook_moves.len(), 5);
+        for m in rook_moves {
+            match m {
+                Move::Normal(Piece::Rook, _, tgt) => assert_eq!(tgt.file(), 4),
+                _ => panic!("Unexpected move {:?}", m),
+            }
+        }
+
+        // Bishop pinned on the diagonal.
+        let bishop_moves = non_king_moves("4k3/8/8/b7/8/8/3B4/4K3 w - - 0 1", &mbb);
+        assert_eq!(bishop_moves.len(), 3);
+
+        // Two friendly pieces in the way means neither is pinned.
+        assert_eq!(non_king_moves("4k3/4r3/8/8/4N3/8/4N3/4K3 w - - 0 1", &mbb).len(), 14);
+
+        // An enemy piece in the way means no pin either.
+        assert_eq!(non_king_moves("4k3/4r3/8/8/4p3/8/4N3/4K3 w - - 0 1", &mbb).len(), 6);
+    }
+
+    #[test]
+    fn pseudo_legal_pinned_piece() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1");
+        let is_knight_move = |m: &Move| matches!(m, Move::Normal(Piece::Knight, _, _));
+
+        assert_eq!(pseudo_legal_moves(&state, &mbb).iter().filter(|m| is_knight_move(m)).count(), 6);
+        assert_eq!(legal_moves(&state, &mbb).iter().filter(|m| is_knight_move(m)).count(), 0);
+    }
+
+    #[test]
+    fn pseudo_legal_moves_minus_legal_moves() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "8/8/3p4/KPp4r/1R3p1k/8/4P1P1/8 w - c6 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "4k3/4r3/8/8/1b6/8/8/R3K3 w - - 0 1",
+        ];
+
+        for fen in fens.iter() {
+            let state = load_fen(fen);
+            let legal = legal_moves(&state, &mbb);
+            let pseudo = pseudo_legal_moves(&state, &mbb);
+            assert!(legal.iter().all(|m| pseudo.contains(m)), "{}", fen);
+
+            // Exactly the moves left out leave the king in check.
+            for m in pseudo {
+                let mut new_state = state.clone();
+                new_state.make_move(m);
+                let side = match state.active_colour {
+                    Colour::White => &new_state.white,
+                    Colour::Black => &new_state.black,
+                };
+                let other_pieces = match state.active_colour {
+                    Colour::White => &new_state.black.pieces,
+                    Colour::Black => &new_state.white.pieces,
+                };
+                let occupancy = new_state.white.pieces.all() | new_state.black.pieces.all();
+                let in_check = square_under_attack(occupancy, other_pieces, BitCoord(side.pieces.king.0), state.active_colour, &mbb);
+                assert_eq!(in_check, !legal.contains(&m), "{} {:?}", fen, m);
+            }
+        }
+    }
+
+    #[test]
+    fn checks() {
+        let mbb = MagicBitBoards::default();
+
+        // Single check from a bishop can be blocked.
+        assert_eq!(
+            non_king_moves("4k3/8/8/8/1b6/8/8/2R1K3 w - - 0 1", &mbb),
+            vec![Move::Normal(Piece::Rook, parse_coord("c1"), parse_coord("c3"))],
+        );
+
+        // Knight checks can only be answered by capturing the knight.
+        assert_eq!(
+            non_king_moves("4k3/8/8/8/8/3n4/8/3RK3 w - - 0 1", &mbb),
+            vec![Move::Normal(Piece::Rook, parse_coord("d1"), parse_coord("d3"))],
+        );
+
+        // Double check means only the king may move.
+        assert_eq!(non_king_moves("4k3/4r3/8/8/1b6/8/8/R3K3 w - - 0 1", &mbb), vec![]);
+    }
+
+    #[test]
+    fn captures_are_subset_of_legal_moves() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/Pp2P3/2N2Q1p/1PPBBPPP/R3K2R b KQkq a3 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            "8/8/3p4/KPp4r/1R3p1k/8/4P1P1/8 w - c6 0 1",
+            "4k3/8/8/8/8/8/3q4/4K3 w - - 0 1",
+        ];
+
+        for fen in fens.iter() {
+            let state = load_fen(fen);
+            let other_side = match state.active_colour {
+                Colour::White => &state.black,
+                Colour::Black => &state.white,
+            };
+
+            let expected: Vec<Move> = legal_moves(&state, &mbb)
+                .into_iter()
+                .filter(|m| match m {
+                    Move::Normal(_, _, tgt) => other_side.pieces.all().contains(*tgt),
+                    Move::EnPassant(_, _) => true,
+                    Move::Promotion(_, tgt, _) => other_side.pieces.all().contains(*tgt),
+                    _ => false,
+                })
+                .collect();
+
+            assert_eq!(legal_captures(&state, &mbb), expected, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn count_matches_legal_moves() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+            "2nnrbkr/p1qppppp/8/1ppb4/6PP/3PP3/PPP2P2/BQNNRBKR w HEhe - 1 9",
+            // Checkmate and stalemate.
+            "6Qk/8/6K1/8/8/8/8/8 b - - 0 1",
+            "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1",
+        ];
+
+        for fen in fens.iter() {
+            let mut state = load_fen(fen);
+
+            // Check the children too, to cover positions reached by each kind of move.
+            for mv in legal_moves(&state, &mbb) {
+                let undo = state.make_move(mv);
+                let moves = legal_moves(&state, &mbb);
+                assert_eq!(count_legal_moves(&state, &mbb), moves.len(), "{} {:?}", fen, mv);
+                assert_eq!(has_legal_move(&state, &mbb), !moves.is_empty(), "{} {:?}", fen, mv);
+                state.unmake_move(mv, undo);
+            }
+
+            let moves = legal_moves(&state, &mbb);
+            assert_eq!(count_legal_moves(&state, &mbb), moves.len(), "{}", fen);
+            assert_eq!(has_legal_move(&state, &mbb), !moves.is_empty(), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn legal_moves_into_reuses_buffer() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/Pp2P3/2N2Q1p/1PPBBPPP/R3K2R b KQkq a3 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            "4k3/8/8/8/8/8/3q4/4K3 w - - 0 1",
+            "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1",
+        ];
+
+        // Start dirty, and carry the previous position's moves into the next.
+        let mut buf = vec![Move::Castle, Move::LongCastle];
+        for fen in fens.iter() {
+            let state = load_fen(fen);
+            legal_moves_into(&state, &mbb, &mut buf);
+            assert_eq!(buf, legal_moves(&state, &mbb), "{}", fen);
+        }
+    }
+
+    fn collect_moves(state: &GameState, mbb: &MagicBitBoards, captures_only: bool, use_evasions: bool) -> Vec<u16> {
+        let mut moves = vec![];
+        generate_moves(state, mbb, captures_only, use_evasions, |m| {
+            moves.push(m.to_u16());
+            true
+        });
+        moves.sort_unstable();
+        moves
+    }
+
+    // Walks the tree, comparing the evasion generator against plain filtering whenever in check.
+    fn compare_evasions(state: &mut GameState, depth: u8, mbb: &MagicBitBoards) -> usize {
+        let mut checked = 0;
+        if state.is_in_check(mbb) {
+            for captures_only in [false, true].iter() {
+                assert_eq!(
+                    collect_moves(state, mbb, *captures_only, true),
+                    collect_moves(state, mbb, *captures_only, false),
+                    "{}", crate::fen::to_fen(state),
+                );
+            }
+            checked += 1;
+        }
+
+        if depth > 0 {
+            for mv in legal_moves(state, mbb) {
+                let undo = state.make_move(mv);
+                checked += compare_evasions(state, depth - 1, mbb);
+                state.unmake_move(mv, undo);
+            }
+        }
+
+        checked
+    }
+
+    #[test]
+    fn evasions_match_filtered_moves() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            // Checks which can be answered by en-passant, taking the checker or blocking.
+            "8/8/8/2k5/3Pp3/8/8/4K3 b - d3 0 1",
+            "8/8/8/1k6/3Pp3/8/8/5B1K b - d3 0 1",
+        ];
+
+        let checked: usize = fens.iter()
+            .map(|fen| compare_evasions(&mut load_fen(fen), 3, &mbb))
+            .sum();
+        assert!(checked > 1000, "Only {} positions in check", checked);
+
+        // Both kinds of en-passant evasion.
+        let moves = legal_moves(&load_fen("8/8/8/2k5/3Pp3/8/8/4K3 b - d3 0 1"), &mbb);
+        assert!(moves.contains(&Move::EnPassant(parse_coord("e4"), parse_coord("d3"))));
+        let moves = legal_moves(&load_fen("8/8/8/1k6/3Pp3/8/8/5B1K b - d3 0 1"), &mbb);
+        assert!(moves.contains(&Move::EnPassant(parse_coord("e4"), parse_coord("d3"))));
+    }
+
+    #[test]
+    fn attackers() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let occupancy = state.white.pieces.all() | state.black.pieces.all();
+        let squares = |names: &[&str]| names.iter().fold(BitBoard::EMPTY, |bb, s| bb | parse_coord(s));
+
+        let white = |sq| attackers_to(&state.white.pieces, Colour::White, occupancy, parse_coord(sq), &mbb);
+        let black = |sq| attackers_to(&state.black.pieces, Colour::Black, occupancy, parse_coord(sq), &mbb);
+
+        assert_eq!(white("f3"), squares(&["e2", "g2", "g1"]));
+        assert_eq!(white("c3"), squares(&["b2", "d2", "b1"]));
+        assert_eq!(white("d2"), squares(&["b1", "c1", "d1", "e1"]));
+        assert_eq!(white("e4"), BitBoard::EMPTY);
+        assert_eq!(white("f6"), BitBoard::EMPTY);
+        assert_eq!(black("f6"), squares(&["e7", "g7", "g8"]));
+        assert_eq!(black("e6"), squares(&["d7", "f7"]));
+
+        // Sliders stop at the first piece in each direction.
+        let state = load_fen("4k3/8/8/1q1R4/8/8/4B3/4K3 w - - 0 1");
+        let occupancy = state.white.pieces.all() | state.black.pieces.all();
+        assert_eq!(
+            attackers_to(&state.white.pieces, Colour::White, occupancy, parse_coord("c4"), &mbb),
+            squares(&["e2"]),
+        );
+        assert_eq!(
+            attackers_to(&state.black.pieces, Colour::Black, occupancy, parse_coord("d5"), &mbb),
+            squares(&["b5"]),
+        );
+    }
+
+    #[test]
+    fn gives_check_matches_making_the_move() {
+        let mbb = MagicBitBoards::default();
+        let fens = [
+            STARTING_POSITION,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+            // Castling with check, and en passant uncovering the rook.
+            "5k2/8/8/8/8/8/8/4K2R w K - 0 1",
+            "8/8/8/R1pP3k/8/8/8/4K3 w - c6 0 1",
+        ];
+
+        let mut checks = 0;
+        for fen in fens.iter() {
+            let root = load_fen(fen);
+            let mut positions = vec![root.clone()];
+            for mv in legal_moves(&root, &mbb) {
+                let mut child = root.clone();
+                child.make_move(mv);
+                positions.push(child);
+            }
+
+            for state in positions.iter() {
+                for mv in legal_moves(state, &mbb) {
+                    let mut after = state.clone();
+                    after.make_move(mv);
+                    let expected = after.is_in_check(&mbb);
+                    assert_eq!(gives_check(state, mv, &mbb), expected, "{:?} in {}", mv, fen);
+                    checks += expected as usize;
+                }
+            }
+        }
+
+        // Make sure the positions actually exercise the checking cases.
+        assert!(checks > 50);
+
+        let state = load_fen("5k2/8/8/8/8/8/8/4K2R w K - 0 1");
+        assert!(gives_check(&state, Move::Castle, &mbb));
+        let state = load_fen("8/8/8/R1pP3k/8/8/8/4K3 w - c6 0 1");
+        assert!(gives_check(&state, Move::EnPassant(parse_coord("d5"), parse_coord("c6")), &mbb));
+    }
+}
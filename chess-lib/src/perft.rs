@@ -1,62 +1,455 @@
 use std::collections::HashMap;
-use crate::fmt::{format_move};
+use std::fmt;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use crate::fen::load_fen;
+use crate::fmt::{format_move, format_uci_move};
 use crate::magic::MagicBitBoards;
-use crate::moves::legal_moves;
-use crate::types::{GameState};
+use crate::moves::{count_legal_moves, legal_moves};
+use crate::tt::TranspositionTable;
+use crate::types::{GameState, Move};
+
+// The functions below count deeper requests to this depth instead, rather than recursing without
+// limit. Nothing near it could ever finish anyway. Use try_perft to reject them.
+pub const MAX_PERFT_DEPTH: u8 = 32;
+
+fn capped(depth: u8) -> u8 {
+    depth.min(MAX_PERFT_DEPTH)
+}
 
 pub fn perft(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> u64 {
-    let moves = legal_moves(state, &mbb);
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut state = state.clone();
+    perft_recursive(&mut state, capped(depth), mbb)
+}
+
+// Like perft, but None if the depth is over MAX_PERFT_DEPTH.
+pub fn try_perft(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> Option<u64> {
+    if depth > MAX_PERFT_DEPTH {
+        return None;
+    }
+
+    Some(perft(state, depth, mbb))
+}
 
+fn perft_recursive(state: &mut GameState, depth: u8, mbb: &MagicBitBoards) -> u64 {
+    // Bulk counting: the leaves don't need to be made, or even collected.
     if depth == 1 {
-        return moves.len() as u64;
+        return count_legal_moves(state, mbb) as u64;
     }
 
+    let moves = legal_moves(state, mbb);
     return moves.iter().map(|m| {
-        let mut new_state = state.clone();
-        new_state.make_move(*m);
-        perft(&new_state, depth - 1, mbb)
+        let undo = state.make_move(*m);
+        let count = perft_recursive(state, depth - 1, mbb);
+        state.unmake_move(*m, undo);
+        count
     }).sum();
 }
 
+// Memoizes subtree counts by zobrist hash. Entries store the depth they were computed at, and
+// are only reused at exactly that depth.
+pub fn perft_hashed(state: &GameState, depth: u8, mbb: &MagicBitBoards, tt: &mut TranspositionTable<(u8, u64)>) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut state = state.clone();
+    perft_hashed_recursive(&mut state, capped(depth), mbb, tt)
+}
+
+fn perft_hashed_recursive(state: &mut GameState, depth: u8, mbb: &MagicBitBoards, tt: &mut TranspositionTable<(u8, u64)>) -> u64 {
+    if let Some((cached_depth, count)) = tt.get(state.zh) {
+        if cached_depth == depth {
+            return count;
+        }
+    }
+
+    let count = if depth == 1 {
+        count_legal_moves(state, mbb) as u64
+    } else {
+        legal_moves(state, mbb).iter().map(|m| {
+            let undo = state.make_move(*m);
+            let count = perft_hashed_recursive(state, depth - 1, mbb, tt);
+            state.unmake_move(*m, undo);
+            count
+        }).sum()
+    };
+
+    tt.insert(state.zh, (depth, count));
+    count
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PerftStats {
+    // The same as perft's count.
+    pub nodes: u64,
+    // Move generation runs once at every position above the leaves, including mates and
+    // stalemates, so this is the number of interior nodes.
+    pub movegen_calls: u64,
+    // Ply of the deepest position reached. Less than the requested depth if every line ends in
+    // mate or stalemate first, or if the depth was capped.
+    pub max_depth_reached: u8,
+}
+
+// Like perft, but also reports how much work it took. No bulk counting at the leaves, so each
+// movegen call corresponds to one position.
+pub fn perft_stats(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> PerftStats {
+    let mut state = state.clone();
+    let mut stats = PerftStats::default();
+    perft_stats_recursive(&mut state, capped(depth), 0, mbb, &mut stats);
+    stats
+}
+
+fn perft_stats_recursive(state: &mut GameState, depth: u8, ply: u8, mbb: &MagicBitBoards, stats: &mut PerftStats) {
+    stats.max_depth_reached = stats.max_depth_reached.max(ply);
+    if depth == 0 {
+        stats.nodes += 1;
+        return;
+    }
+
+    stats.movegen_calls += 1;
+    for m in legal_moves(state, mbb) {
+        let undo = state.make_move(m);
+        perft_stats_recursive(state, depth - 1, ply + 1, mbb, stats);
+        state.unmake_move(m, undo);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PerftCounts {
+    pub nodes: u64,
+    pub captures: u64,
+    pub en_passant: u64,
+    pub castles: u64,
+    pub promotions: u64,
+    pub checks: u64,
+    pub checkmates: u64,
+}
+
+// Like perft, but breaks the leaf nodes down by the type of move that reached them.
+pub fn perft_detailed(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> PerftCounts {
+    // Just the root, which no move led to.
+    if depth == 0 {
+        return PerftCounts{nodes: 1, ..PerftCounts::default()};
+    }
+
+    let mut state = state.clone();
+    let mut counts = PerftCounts::default();
+    perft_detailed_recursive(&mut state, capped(depth), mbb, &mut counts);
+    counts
+}
+
+fn perft_detailed_recursive(state: &mut GameState, depth: u8, mbb: &MagicBitBoards, counts: &mut PerftCounts) {
+    for m in legal_moves(state, mbb) {
+        if depth > 1 {
+            let undo = state.make_move(m);
+            perft_detailed_recursive(state, depth - 1, mbb, counts);
+            state.unmake_move(m, undo);
+            continue;
+        }
+
+        let is_en_passant = matches!(m, Move::EnPassant(_, _));
+
+        let is_capture = match m {
+            Move::Normal(_, _, tgt) | Move::Promotion(_, tgt, _) => state.find_piece(tgt).is_some(),
+            _ => false,
+        };
+
+        counts.nodes += 1;
+        if is_capture || is_en_passant {
+            counts.captures += 1;
+        }
+        if is_en_passant {
+            counts.en_passant += 1;
+        }
+        match m {
+            Move::Castle | Move::LongCastle => counts.castles += 1,
+            Move::Promotion(_, _, _) => counts.promotions += 1,
+            _ => (),
+        }
+
+        let undo = state.make_move(m);
+        if state.is_in_check(mbb) {
+            counts.checks += 1;
+            if legal_moves(state, mbb).is_empty() {
+                counts.checkmates += 1;
+            }
+        }
+        state.unmake_move(m, undo);
+    }
+}
+
 pub fn divide(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> HashMap<String, u64> {
+    divide_moves(state, depth, mbb).into_iter().map(|(m, c)| (format_move(m), c)).collect()
+}
+
+// Node counts below each root move, sorted in the same order as their formatted strings.
+pub fn divide_moves(state: &GameState, depth: u8, mbb: &MagicBitBoards) -> Vec<(Move, u64)> {
     if depth < 1 {
         panic!("Divide requires depth at least 1");
     }
+    let depth = capped(depth);
+
+    let mut state = state.clone();
+    let mut results: Vec<(Move, u64)> = legal_moves(&state, mbb).into_iter().map(|m| {
+        let undo = state.make_move(m);
+        let count = if depth == 1 { 1 } else { perft_recursive(&mut state, depth - 1, mbb) };
+        state.unmake_move(m, undo);
+        (m, count)
+    }).collect();
 
-    let moves = legal_moves(state, &mbb);
+    results.sort_by_cached_key(|(m, _)| format_move(*m));
+    results
+}
 
-    let results: Vec<(String, u64)> = moves.iter().map(|m| {
-        let move_str = format_move(*m);
-        let mut state_2 = state.clone();
-        state_2.make_move(*m);
-        (move_str, perft(&state_2, depth - 1, mbb))
-    }).collect();
-    
-    let mut counts: HashMap<String, u64> = HashMap::new();
-    results.iter().for_each(|(m, c)| {
-        counts.insert(m.clone(), *c);
+pub fn perft_parallel(state: &GameState, depth: u8, mbb: &MagicBitBoards, threads: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    divide_parallel_moves(state, depth, mbb, threads).iter().map(|(_, c)| c).sum()
+}
+
+// Same as divide, but each root move's subtree is counted on a separate worker.
+pub fn divide_parallel(state: &GameState, depth: u8, mbb: &MagicBitBoards, threads: usize) -> HashMap<String, u64> {
+    divide_parallel_moves(state, depth, mbb, threads).into_iter().map(|(m, c)| (format_move(m), c)).collect()
+}
+
+// Same as divide_moves, but each root move's subtree is counted on a separate worker.
+pub fn divide_parallel_moves(state: &GameState, depth: u8, mbb: &MagicBitBoards, threads: usize) -> Vec<(Move, u64)> {
+    if depth < 1 {
+        panic!("Divide requires depth at least 1");
+    }
+    let depth = capped(depth);
+
+    let moves = legal_moves(state, mbb);
+    // Workers take the next root move until there are none left, borrowing the shared tables.
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(Move, u64)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1)).map(|_| scope.spawn(|| {
+            let mut state = state.clone();
+            let mut counts = vec![];
+            while let Some(m) = moves.get(next.fetch_add(1, Ordering::Relaxed)).copied() {
+                let undo = state.make_move(m);
+                let count = if depth == 1 { 1 } else { perft_recursive(&mut state, depth - 1, mbb) };
+                state.unmake_move(m, undo);
+                counts.push((m, count));
+            }
+            counts
+        })).collect();
+
+        workers.into_iter().flat_map(|w| w.join().expect("perft worker panicked")).collect()
     });
+    results.sort_by_cached_key(|(m, _)| format_move(*m));
+    results
+}
 
-    counts
+// Lines like "e2e4: 20" as printed by Stockfish's "go perft", sorted so they can be diffed
+// against another engine's output.
+pub fn format_divide_uci(state: &GameState, counts: &[(Move, u64)]) -> Vec<String> {
+    let mut lines: Vec<String> = counts.iter()
+        .map(|(m, c)| format!("{}: {}", format_uci_move(state, *m), c))
+        .collect();
+    lines.sort();
+    lines
+}
+
+// A position from a perft suite, along with the expected node counts at each depth.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PerftCase {
+    pub fen: String,
+    pub depths: Vec<(u8, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PerftSuiteError {
+    pub line: usize,
+    pub text: String,
+}
+
+impl Display for PerftSuiteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid perft entry on line {}: {}", self.line, self.text)
+    }
+}
+
+impl std::error::Error for PerftSuiteError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PerftMismatch {
+    pub fen: String,
+    pub depth: u8,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl Display for PerftMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at depth {}: expected {}, got {}", self.fen, self.depth, self.expected, self.actual)
+    }
+}
+
+// Parses the EPD perft format, one position per line: "<fen> ;D1 20 ;D2 400 ;D3 8902".
+// Blank lines and lines starting with '#' are skipped.
+pub fn parse_perft_suite(text: &str) -> Result<Vec<PerftCase>, PerftSuiteError> {
+    let mut cases = vec![];
+
+    for (ix, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = || PerftSuiteError{line: ix + 1, text: line.to_owned()};
+        let mut fields = line.split(';');
+        let fen = fields.next().unwrap().trim().to_owned();
+
+        let mut depths = vec![];
+        for field in fields {
+            let mut parts = field.split_whitespace();
+            let depth = parts.next()
+                .and_then(|d| d.strip_prefix('D'))
+                .and_then(|d| d.parse().ok())
+                .ok_or_else(error)?;
+            let count = parts.next().and_then(|c| c.parse().ok()).ok_or_else(error)?;
+            depths.push((depth, count));
+        }
+
+        if fen.is_empty() || depths.is_empty() {
+            return Err(error());
+        }
+
+        cases.push(PerftCase{fen, depths});
+    }
+
+    Ok(cases)
+}
+
+// Runs every case up to `max_depth`, returning the counts which didn't match. Entries deeper
+// than MAX_PERFT_DEPTH are skipped.
+pub fn run_perft_suite(cases: &[PerftCase], max_depth: u8, mbb: &MagicBitBoards) -> Vec<PerftMismatch> {
+    let mut mismatches = vec![];
+
+    for case in cases {
+        let state = load_fen(&case.fen);
+        for (depth, expected) in case.depths.iter().filter(|(d, _)| *d <= max_depth.min(MAX_PERFT_DEPTH)) {
+            let actual = perft(&state, *depth, mbb);
+            if actual != *expected {
+                mismatches.push(PerftMismatch{fen: case.fen.clone(), depth: *depth, expected: *expected, actual});
+            }
+        }
+    }
+
+    mismatches
 }
 
 #[cfg(test)]
 mod tests {
     use crate::fen::{load_fen, STARTING_POSITION};
     use crate::magic::MagicBitBoards;
-    use crate::perft::{perft};
+    use crate::fmt::{format_move, parse_uci_move};
+    use crate::perft::{divide, divide_moves, divide_parallel_moves, format_divide_uci, perft, perft_detailed, perft_hashed, perft_parallel, perft_stats, try_perft, PerftCounts, PerftStats, MAX_PERFT_DEPTH};
+    use crate::tt::{always_replace, TranspositionTable};
 
     macro_rules! perft_test {
         [ $name:ident: Starting at position $position:expr, at depth $depth:expr, the number of possible moves is: $count:expr ] => {
             #[test]
             fn $name() {
                 let state = load_fen($position);
-                let mbb = MagicBitBoards::default();
-                assert_eq!(perft(&state, $depth, &mbb), $count);
+                let mbb = MagicBitBoards::shared();
+                assert_eq!(perft(&state, $depth, mbb), $count);
+                assert_eq!(perft_parallel(&state, $depth, mbb, 4), $count);
             }
         };
     }
 
+    #[test]
+    fn hashed_matches_serial() {
+        let state = load_fen(STARTING_POSITION);
+        let mbb = MagicBitBoards::shared();
+        let mut tt = TranspositionTable::new(1 << 20, always_replace);
+
+        assert_eq!(perft_hashed(&state, 5, mbb, &mut tt), 4_865_609);
+        assert!(tt.stats().hit_rate() > 0.0);
+
+        // Reusing the table at a different depth must not return stale counts.
+        assert_eq!(perft_hashed(&state, 4, mbb, &mut tt), 197_281);
+    }
+
+    #[test]
+    fn divide_moves_sums_to_perft() {
+        let state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - ");
+        let mbb = MagicBitBoards::shared();
+
+        let counts = divide_moves(&state, 2, mbb);
+        assert_eq!(counts.len(), 48);
+        assert_eq!(counts.iter().map(|(_, c)| c).sum::<u64>(), perft(&state, 2, mbb));
+
+        let names: Vec<String> = counts.iter().map(|(m, _)| format_move(*m)).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+
+        // The string version agrees, and depth 1 counts each move once.
+        let strings = divide(&state, 2, mbb);
+        assert!(counts.iter().all(|(m, c)| strings[&format_move(*m)] == *c));
+        assert!(divide_moves(&state, 1, mbb).iter().all(|(_, c)| *c == 1));
+    }
+
+    #[test]
+    fn divide_uci_format() {
+        let state = load_fen("4k3/1P6/8/8/8/8/8/R3K3 w Q - 0 1");
+        let mbb = MagicBitBoards::shared();
+
+        let lines = format_divide_uci(&state, &divide_moves(&state, 1, mbb));
+        assert_eq!(lines.len(), 20);
+        assert_eq!(lines[0], "a1a2: 1");
+        assert!(lines.contains(&"e1c1: 1".to_owned()));
+        assert!(lines.contains(&"b7b8q: 1".to_owned()));
+        assert!(lines.contains(&"b7b8n: 1".to_owned()));
+
+        let mut sorted = lines.clone();
+        sorted.sort();
+        assert_eq!(lines, sorted);
+
+        // Same results from the parallel version, which also agrees with perft at depth 0.
+        assert_eq!(perft_parallel(&state, 0, mbb, 4), perft(&state, 0, mbb));
+        assert_eq!(format_divide_uci(&state, &divide_parallel_moves(&state, 2, mbb, 4)), format_divide_uci(&state, &divide_moves(&state, 2, mbb)));
+    }
+
+    #[test]
+    fn detailed_counts() {
+        let state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - ");
+        let mbb = MagicBitBoards::shared();
+
+        assert_eq!(perft_detailed(&state, 0, mbb), PerftCounts{nodes: 1, ..PerftCounts::default()});
+
+        assert_eq!(perft_detailed(&state, 1, mbb), PerftCounts {
+            nodes: 48,
+            captures: 8,
+            en_passant: 0,
+            castles: 2,
+            promotions: 0,
+            checks: 0,
+            checkmates: 0,
+        });
+
+        assert_eq!(perft_detailed(&state, 2, mbb), PerftCounts {
+            nodes: 2_039,
+            captures: 351,
+            en_passant: 1,
+            castles: 91,
+            promotions: 0,
+            checks: 3,
+            checkmates: 0,
+        });
+    }
+
     // Example positions and results taken from https://www.chessprogramming.org/Perft_Results
     perft_test![ starting_1:
         Starting at position STARTING_POSITION,
@@ -190,10 +583,80 @@ mod tests {
         at depth 3, the number of possible moves is: 9_483
     ];
 
+    // Chess960 positions, from https://www.chessprogramming.org/Chess960_Perft_Results
+    perft_test![ chess960_1_3:
+        Starting at position "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+        at depth 3, the number of possible moves is: 12_189
+    ];
+
+    perft_test![ chess960_1_4:
+        Starting at position "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+        at depth 4, the number of possible moves is: 326_672
+    ];
+
+    perft_test![ chess960_2_3:
+        Starting at position "2nnrbkr/p1qppppp/8/1ppb4/6PP/3PP3/PPP2P2/BQNNRBKR w HEhe - 1 9",
+        at depth 3, the number of possible moves is: 18_002
+    ];
+
+    perft_test![ chess960_3_3:
+        Starting at position "b1q1rrkb/pppppppp/3nn3/8/P7/1PPP4/4PPPP/BQNNRKRB w GE - 1 9",
+        at depth 3, the number of possible moves is: 10_471
+    ];
+
+    perft_test![ chess960_4_3:
+        Starting at position "qbbnnrkr/2pp2pp/p7/1p2pp2/8/P3PP2/1PPP1KPP/QBBNNR1R w hf - 0 9",
+        at depth 3, the number of possible moves is: 13_440
+    ];
+
     /*
     perft_test![ position_5_4:
         Starting at position "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
         at depth 4, the number of possible moves is: 2_103_487
     ];
     */
+
+    #[test]
+    fn perft_stats_counts_movegen_calls() {
+        let state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        let mbb = MagicBitBoards::shared();
+
+        let stats = perft_stats(&state, 3, mbb);
+        assert_eq!(stats.nodes, perft(&state, 3, mbb));
+        // One call for each position above the leaves.
+        let interior: u64 = (0..3).map(|d| perft(&state, d, mbb)).sum();
+        assert_eq!(stats.movegen_calls, interior);
+        assert_eq!(stats.max_depth_reached, 3);
+
+        // Every line ends in mate after Qh5#, one ply short of the requested depth.
+        let mated = load_fen("rnbqkbnr/ppppp2p/5p2/6p1/4P3/3P4/PPP2PPP/RNBQKBNR w KQkq g6 0 3");
+        let mate = parse_uci_move(&mated, "d1h5", mbb).unwrap();
+        let mut after = mated.clone();
+        after.make_move(mate);
+        let stats = perft_stats(&after, 2, mbb);
+        assert_eq!(stats, PerftStats{nodes: 0, movegen_calls: 1, max_depth_reached: 0});
+    }
+
+    #[test]
+    fn perft_depth_edge_cases() {
+        let state = load_fen(STARTING_POSITION);
+        let mbb = MagicBitBoards::shared();
+        let mut tt = TranspositionTable::new(1 << 10, always_replace);
+
+        assert_eq!(perft(&state, 0, mbb), 1);
+        assert_eq!(perft_hashed(&state, 0, mbb, &mut tt), 1);
+        assert_eq!(perft_stats(&state, 0, mbb).nodes, 1);
+
+        // Stalemate, so a huge depth finishes immediately rather than running into the cap.
+        let stalemate = load_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert_eq!(perft(&stalemate, u8::MAX, mbb), 0);
+        assert_eq!(perft_stats(&stalemate, u8::MAX, mbb).max_depth_reached, 0);
+        assert_eq!(perft_detailed(&stalemate, u8::MAX, mbb), PerftCounts::default());
+        assert!(divide_parallel_moves(&stalemate, u8::MAX, mbb, 2).is_empty());
+
+        // Only the checked version reports going over the cap.
+        assert_eq!(try_perft(&stalemate, MAX_PERFT_DEPTH, mbb), Some(0));
+        assert_eq!(try_perft(&stalemate, MAX_PERFT_DEPTH + 1, mbb), None);
+        assert_eq!(try_perft(&state, 2, mbb), Some(400));
+    }
 }
@@ -0,0 +1,148 @@
+use crate::magic::{
+    bishop_mask, bishop_moves, boards_for_mask, king_moves, knight_moves, rook_mask, rook_moves,
+    AttackTables,
+};
+use crate::types::{BitBoard, BitCoord};
+
+// Sliding piece lookups indexed by extracting the masked occupancy bits, rather than by a magic
+// multiply. Tables are always perfectly sized.
+#[derive(Clone)]
+pub struct PextBitBoards {
+    rooks: Vec<Pext>,
+    bishops: Vec<Pext>,
+
+    kings: Vec<BitBoard>,
+    knights: Vec<BitBoard>,
+}
+
+impl Default for PextBitBoards {
+    fn default() -> PextBitBoards {
+        let mut rooks: Vec<Pext> = Vec::with_capacity(64);
+        let mut bishops: Vec<Pext> = Vec::with_capacity(64);
+        let mut kings: Vec<BitBoard> = Vec::with_capacity(64);
+        let mut knights: Vec<BitBoard> = Vec::with_capacity(64);
+
+        for c in 0..64 {
+            let coord = BitCoord(1 << c);
+            rooks.push(Pext::generate(coord, rook_mask(coord), rook_moves));
+            bishops.push(Pext::generate(coord, bishop_mask(coord), bishop_moves));
+            kings.push(king_moves(coord));
+            knights.push(knight_moves(coord));
+        }
+
+        PextBitBoards{rooks, bishops, kings, knights}
+    }
+}
+
+impl PextBitBoards {
+    pub fn rook(&self, coord: BitCoord) -> &Pext {
+        &self.rooks[coord.index() as usize]
+    }
+
+    pub fn bishop(&self, coord: BitCoord) -> &Pext {
+        &self.bishops[coord.index() as usize]
+    }
+
+    pub fn king(&self, coord: BitCoord) -> BitBoard {
+        self.kings[coord.index() as usize]
+    }
+
+    pub fn knight(&self, coord: BitCoord) -> BitBoard {
+        self.knights[coord.index() as usize]
+    }
+}
+
+impl AttackTables for PextBitBoards {
+    fn rook_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard {
+        self.rook(coord).lookup(occupancy)
+    }
+
+    fn bishop_attacks(&self, coord: BitCoord, occupancy: BitBoard) -> BitBoard {
+        self.bishop(coord).lookup(occupancy)
+    }
+
+    fn king_attacks(&self, coord: BitCoord) -> BitBoard {
+        self.king(coord)
+    }
+
+    fn knight_attacks(&self, coord: BitCoord) -> BitBoard {
+        self.knight(coord)
+    }
+}
+
+#[derive(Clone)]
+pub struct Pext {
+    table: Vec<BitBoard>,
+    mask: BitBoard,
+}
+
+impl Pext {
+    fn generate(coord: BitCoord, mask: BitBoard, movegen: fn (BitCoord, BitBoard) -> BitBoard) -> Pext {
+        let mut table = vec![BitBoard::EMPTY; 1 << mask.count()];
+        for o in boards_for_mask(mask) {
+            table[pext(o.0, mask.0) as usize] = movegen(coord, o);
+        }
+        Pext{table, mask}
+    }
+
+    pub fn size(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn lookup(&self, bb: BitBoard) -> BitBoard {
+        self.table[pext(bb.0, self.mask.0) as usize]
+    }
+}
+
+#[cfg(all(feature = "pext", target_arch = "x86_64"))]
+fn pext(value: u64, mask: u64) -> u64 {
+    // Safety: enabling the pext feature asserts that the target CPU supports BMI2.
+    unsafe { core::arch::x86_64::_pext_u64(value, mask) }
+}
+
+#[cfg(not(all(feature = "pext", target_arch = "x86_64")))]
+fn pext(value: u64, mask: u64) -> u64 {
+    let mut result = 0;
+    let mut bit = 1;
+    let mut mask = mask;
+    while mask != 0 {
+        let lowest = mask & mask.wrapping_neg();
+        if value & lowest != 0 {
+            result |= bit;
+        }
+        bit <<= 1;
+        mask &= mask - 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+    use rand_chacha::ChaCha8Rng;
+    use crate::magic::MagicBitBoards;
+    use crate::pext::*;
+
+    #[test]
+    fn test_pext() {
+        assert_eq!(pext(0b1011_0110, 0b1111_0000), 0b1011);
+        assert_eq!(pext(0b1011_0110, 0b0101_0101), 0b0110);
+        assert_eq!(pext(u64::MAX, 0), 0);
+    }
+
+    #[test]
+    fn test_matches_magic() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let magic_bbs = MagicBitBoards::default();
+        let pext_bbs = PextBitBoards::default();
+
+        for c in 0..64 {
+            let coord = BitCoord(1 << c);
+            for _ in 0..100_000 {
+                let board = BitBoard(rng.gen::<u64>());
+                assert_eq!(pext_bbs.rook(coord).lookup(board), magic_bbs.rook(coord).lookup(board));
+                assert_eq!(pext_bbs.bishop(coord).lookup(board), magic_bbs.bishop(coord).lookup(board));
+            }
+        }
+    }
+}
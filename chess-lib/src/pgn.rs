@@ -1,8 +1,198 @@
 use std::fmt;
 use std::fmt::Display;
+use crate::fen::{to_fen, load_fen, STARTING_POSITION};
 use crate::fmt::{format_file, format_rank, format_piece};
 use crate::magic::MagicBitBoards;
-use crate::types::{BitCoord, GameState, Move, Piece};
+use crate::moves::legal_moves;
+use crate::types::{BitCoord, Colour, GameState, Move, Piece};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PgnParseError {
+    InvalidSan(String),
+    NoMatchingMove(String),
+    AmbiguousMove(String),
+}
+
+impl Display for PgnParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgnParseError::InvalidSan(san) => write!(f, "Invalid SAN: {}", san),
+            PgnParseError::NoMatchingMove(san) => write!(f, "No legal move matches: {}", san),
+            PgnParseError::AmbiguousMove(san) => write!(f, "More than one legal move matches: {}", san),
+        }
+    }
+}
+
+impl std::error::Error for PgnParseError {}
+
+pub fn parse_pgn_moves(state: &GameState, movetext: &str, mbb: &MagicBitBoards) -> Result<Vec<Move>, PgnParseError> {
+    let mut state = state.clone();
+    let mut moves: Vec<Move> = vec![];
+
+    for token in tokenize_movetext(movetext) {
+        let mv = resolve_san(&state, &token, mbb)?;
+        state.make_move(mv);
+        moves.push(mv);
+    }
+
+    Ok(moves)
+}
+
+// Maps a single SAN token to the matching legal move in this position.
+// Returns None if the SAN is invalid, or doesn't match exactly one legal move.
+pub fn parse_san(state: &GameState, san: &str, mbb: &MagicBitBoards) -> Option<Move> {
+    resolve_san(state, san, mbb).ok()
+}
+
+fn tokenize_movetext(movetext: &str) -> Vec<String> {
+    // Strip comments first, since they may contain arbitrary text.
+    let mut stripped = String::with_capacity(movetext.len());
+    let mut in_brace_comment = false;
+    let mut in_line_comment = false;
+    for c in movetext.chars() {
+        if in_brace_comment {
+            in_brace_comment = c != '}';
+        } else if in_line_comment {
+            in_line_comment = c != '\n';
+        } else if c == '{' {
+            in_brace_comment = true;
+        } else if c == ';' {
+            in_line_comment = true;
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped.split_whitespace()
+        .filter(|t| !is_result_marker(t) && !t.starts_with('$'))
+        .map(strip_move_number)
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_owned())
+        .collect()
+}
+
+fn is_result_marker(token: &str) -> bool {
+    matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*")
+}
+
+// Strips a leading move number like "12." or "12..." from a token.
+fn strip_move_number(token: &str) -> &str {
+    let digits_end = token.find(|c: char| !c.is_ascii_digit()).unwrap_or(token.len());
+    if token[digits_end..].starts_with('.') {
+        token[digits_end..].trim_start_matches('.')
+    } else {
+        token
+    }
+}
+
+fn resolve_san(state: &GameState, san: &str, mbb: &MagicBitBoards) -> Result<Move, PgnParseError> {
+    let pgn_move = PGNMove::parse(san).ok_or_else(|| PgnParseError::InvalidSan(san.to_owned()))?;
+
+    let candidates: Vec<Move> = legal_moves(state, mbb)
+        .into_iter()
+        .filter(|m| pgn_move.matches(state, *m))
+        .collect();
+
+    match candidates.len() {
+        0 => Err(PgnParseError::NoMatchingMove(san.to_owned())),
+        1 => Ok(candidates[0]),
+        _ => Err(PgnParseError::AmbiguousMove(san.to_owned())),
+    }
+}
+
+pub struct PgnGame {
+    pub event: String,
+    pub site: String,
+    pub date: String,
+    pub round: String,
+    pub white: String,
+    pub black: String,
+    pub result: String,
+    pub start: GameState,
+    pub moves: Vec<Move>,
+}
+
+impl PgnGame {
+    const MAX_LINE_LENGTH: usize = 80;
+
+    pub fn new(start: GameState, moves: Vec<Move>) -> PgnGame {
+        PgnGame{
+            event: "?".to_owned(),
+            site: "?".to_owned(),
+            date: "????.??.??".to_owned(),
+            round: "?".to_owned(),
+            white: "?".to_owned(),
+            black: "?".to_owned(),
+            result: "*".to_owned(),
+            start,
+            moves,
+        }
+    }
+
+    fn tags(&self) -> Vec<(&str, String)> {
+        let mut tags = vec![
+            ("Event", self.event.clone()),
+            ("Site", self.site.clone()),
+            ("Date", self.date.clone()),
+            ("Round", self.round.clone()),
+            ("White", self.white.clone()),
+            ("Black", self.black.clone()),
+            ("Result", self.result.clone()),
+        ];
+
+        // Compare FENs, since == also looks at the history and other state FEN doesn't record.
+        if to_fen(&self.start) != to_fen(&load_fen(STARTING_POSITION)) {
+            tags.push(("SetUp", "1".to_owned()));
+            tags.push(("FEN", to_fen(&self.start)));
+        }
+
+        tags
+    }
+
+    fn movetext_tokens(&self, mbb: &MagicBitBoards) -> Vec<String> {
+        let mut tokens: Vec<String> = vec![];
+        let mut state = self.start.clone();
+
+        for (ix, mv) in self.moves.iter().enumerate() {
+            let san = PGNMove::from_internal(&state, *mv, mbb).to_string();
+            match state.active_colour {
+                Colour::White => tokens.push(format!("{}. {}", state.fullmove_number(), san)),
+                Colour::Black if ix == 0 => tokens.push(format!("{}... {}", state.fullmove_number(), san)),
+                Colour::Black => tokens.push(san),
+            }
+
+            state.make_move(*mv);
+        }
+
+        tokens.push(self.result.clone());
+        tokens
+    }
+}
+
+impl Display for PgnGame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mbb = MagicBitBoards::shared();
+
+        for (name, value) in self.tags() {
+            writeln!(f, "[{} \"{}\"]", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+        }
+        writeln!(f)?;
+
+        let mut line = String::new();
+        for token in self.movetext_tokens(mbb) {
+            if !line.is_empty() && line.len() + 1 + token.len() > Self::MAX_LINE_LENGTH {
+                writeln!(f, "{}", line)?;
+                line.clear();
+            }
+
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        writeln!(f, "{}", line)
+    }
+}
 
 pub enum PGNMove {
     Normal(PGNMoveData),
@@ -24,25 +214,26 @@ pub struct PGNMoveData {
 impl PGNMove {
     pub fn from_internal(state: &GameState, mv: Move, mbb: &MagicBitBoards) -> PGNMove {
         let mut new_state = state.clone();
-        new_state.make_move(mv);
+        let (_, info) = new_state.make_move_info(mv, mbb);
 
-        let is_check = new_state.is_in_check(mbb);
+        // Mate is annotated with '#' instead of '+', so only one of these is set.
+        let in_check = info.gives_check;
+        let is_checkmate = in_check && legal_moves(&new_state, mbb).is_empty();
+        let is_check = in_check && !is_checkmate;
 
         match mv {
-            Move::Normal(piece, _, tgt) => {
-                let is_capture = match state.find_piece(tgt) {
-                    Some(_) => true,
-                    _ => panic!("Source square is empty"),
-                };
+            Move::Normal(piece, src, tgt) => {
+                let is_capture = info.captured.is_some();
+                let (disambiguate_file, disambiguate_rank) = disambiguate(state, piece, src, tgt, is_capture, mbb);
 
                 PGNMove::Normal(PGNMoveData{
                     piece,
                     to_square: tgt,
                     is_capture,
                     is_check,
-                    is_checkmate: false,
-                    disambiguate_file: None,
-                    disambiguate_rank: None,
+                    is_checkmate,
+                    disambiguate_file,
+                    disambiguate_rank,
                     promote_to: None,
                 })
             },
@@ -52,24 +243,129 @@ impl PGNMove {
                     _ => panic!("Source square is empty"),
                 };
 
-                let is_capture = match state.find_piece(tgt) {
-                    Some(_) => true,
-                    _ => panic!("Source square is empty"),
-                };
+                let is_capture = info.captured.is_some();
+                let (disambiguate_file, disambiguate_rank) = disambiguate(state, piece, src, tgt, is_capture, mbb);
 
                 PGNMove::Normal(PGNMoveData{
                     piece,
                     to_square: tgt,
                     is_capture,
                     is_check,
-                    is_checkmate: false,
-                    disambiguate_file: None,
-                    disambiguate_rank: None,
+                    is_checkmate,
+                    disambiguate_file,
+                    disambiguate_rank,
                     promote_to: Some(promote_to),
                 })
             },
-            Move::Castle => PGNMove::Castle(is_check, false),
-            Move::LongCastle => PGNMove::LongCastle(is_check, false),
+            Move::EnPassant(src, tgt) => {
+                PGNMove::Normal(PGNMoveData{
+                    piece: Piece::Pawn,
+                    to_square: tgt,
+                    is_capture: true,
+                    is_check,
+                    is_checkmate,
+                    disambiguate_file: Some(src.file() as u8),
+                    disambiguate_rank: None,
+                    promote_to: None,
+                })
+            },
+            Move::Castle => PGNMove::Castle(is_check, is_checkmate),
+            Move::LongCastle => PGNMove::LongCastle(is_check, is_checkmate),
+        }
+    }
+
+    // Parses a single SAN token.
+    // Only checks the syntax, the move may not be legal in any given position.
+    pub fn parse(san: &str) -> Option<PGNMove> {
+        // Check/mate markers and annotations are irrelevant to which move was played.
+        let is_checkmate = san.contains('#');
+        let is_check = san.contains('+') && !is_checkmate;
+        let san = san.trim_end_matches(['+', '#', '!', '?']);
+
+        match san {
+            "O-O" | "0-0" => return Some(PGNMove::Castle(is_check, is_checkmate)),
+            "O-O-O" | "0-0-0" => return Some(PGNMove::LongCastle(is_check, is_checkmate)),
+            _ => (),
+        };
+
+        let mut chars: Vec<char> = san.chars().collect();
+
+        let piece = match chars.first().and_then(|c| parse_piece(*c)) {
+            Some(pc) => {
+                chars.remove(0);
+                pc
+            },
+            None => Piece::Pawn,
+        };
+
+        // Promotion, with or without the '='.
+        let promote_to = match chars.last().and_then(|c| parse_piece(*c)) {
+            Some(pc) => {
+                chars.pop();
+                if chars.last() == Some(&'=') {
+                    chars.pop();
+                }
+                Some(pc)
+            },
+            None => None,
+        };
+
+        if chars.len() < 2 {
+            return None;
+        }
+
+        let rank_char = chars.pop().unwrap();
+        let file_char = chars.pop().unwrap();
+        let to_square: BitCoord = (parse_file_char(file_char)?, parse_rank_char(rank_char)?).into();
+
+        let is_capture = chars.last() == Some(&'x');
+        if is_capture {
+            chars.pop();
+        }
+
+        let mut disambiguate_file: Option<u8> = None;
+        let mut disambiguate_rank: Option<u8> = None;
+        for c in chars {
+            match (parse_file_char(c), parse_rank_char(c)) {
+                (Some(f), _) if disambiguate_file.is_none() && disambiguate_rank.is_none() => {
+                    disambiguate_file = Some(f as u8);
+                },
+                (_, Some(r)) if disambiguate_rank.is_none() => {
+                    disambiguate_rank = Some(r as u8);
+                },
+                _ => return None,
+            }
+        }
+
+        Some(PGNMove::Normal(PGNMoveData{
+            piece,
+            disambiguate_file,
+            disambiguate_rank,
+            to_square,
+            is_capture,
+            is_check,
+            is_checkmate,
+            promote_to,
+        }))
+    }
+
+    // Whether the given legal move in this position is described by this SAN.
+    // Check, mate and capture markers are ignored.
+    fn matches(&self, state: &GameState, mv: Move) -> bool {
+        match (self, mv) {
+            (PGNMove::Castle(_, _), Move::Castle) => true,
+            (PGNMove::LongCastle(_, _), Move::LongCastle) => true,
+            (PGNMove::Normal(data), Move::Normal(piece, src, tgt)) => {
+                data.promote_to.is_none() && data.matches_squares(piece, src, tgt)
+            },
+            (PGNMove::Normal(data), Move::EnPassant(src, tgt)) => {
+                data.promote_to.is_none() && data.matches_squares(Piece::Pawn, src, tgt)
+            },
+            (PGNMove::Normal(data), Move::Promotion(src, tgt, promote_to)) => {
+                let piece = state.find_piece(src).map(|(_, pc)| pc);
+                data.promote_to == Some(promote_to) && piece.map(|pc| data.matches_squares(pc, src, tgt)).unwrap_or(false)
+            },
+            _ => false,
         }
     }
 
@@ -81,6 +377,14 @@ impl PGNMove {
                     s.push(format_piece(data.piece));
                 }
 
+                if let Some(f) = data.disambiguate_file {
+                    s.push(format_file(f as u32));
+                }
+
+                if let Some(r) = data.disambiguate_rank {
+                    s.push(format_rank(r as u32));
+                }
+
                 if data.is_capture {
                     s.push('x');
                 }
@@ -136,8 +440,304 @@ impl PGNMove {
     }
 }
 
+// Works out which parts of the source square are needed to uniquely identify the move.
+// Pawn captures always include the source file.
+fn disambiguate(
+    state: &GameState,
+    piece: Piece,
+    src: BitCoord,
+    tgt: BitCoord,
+    is_capture: bool,
+    mbb: &MagicBitBoards,
+) -> (Option<u8>, Option<u8>) {
+    let file = Some(src.file() as u8);
+    let rank = Some(src.rank() as u8);
+
+    if piece == Piece::Pawn {
+        return if is_capture { (file, None) } else { (None, None) };
+    }
+
+    let others: Vec<BitCoord> = legal_moves(state, mbb)
+        .into_iter()
+        .filter_map(|m| match m {
+            Move::Normal(pc, s, t) if pc == piece && t == tgt && s != src => Some(s),
+            _ => None,
+        })
+        .collect();
+
+    if others.is_empty() {
+        (None, None)
+    } else if others.iter().all(|s| s.file() != src.file()) {
+        (file, None)
+    } else if others.iter().all(|s| s.rank() != src.rank()) {
+        (None, rank)
+    } else {
+        (file, rank)
+    }
+}
+
+impl PGNMoveData {
+    fn matches_squares(&self, piece: Piece, src: BitCoord, tgt: BitCoord) -> bool {
+        self.piece == piece
+            && self.to_square == tgt
+            && self.disambiguate_file.map(|f| f as u32 == src.file()).unwrap_or(true)
+            && self.disambiguate_rank.map(|r| r as u32 == src.rank()).unwrap_or(true)
+    }
+}
+
+fn parse_piece(c: char) -> Option<Piece> {
+    match c {
+        'K' => Some(Piece::King),
+        'Q' => Some(Piece::Queen),
+        'R' => Some(Piece::Rook),
+        'B' => Some(Piece::Bishop),
+        'N' => Some(Piece::Knight),
+        _ => None,
+    }
+}
+
+fn parse_file_char(c: char) -> Option<u32> {
+    match c {
+        'a'..='h' => Some(c as u32 - 'a' as u32),
+        _ => None,
+    }
+}
+
+fn parse_rank_char(c: char) -> Option<u32> {
+    match c {
+        '1'..='8' => Some(c as u32 - '1' as u32),
+        _ => None,
+    }
+}
+
 impl Display for PGNMove {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::fen::{load_fen, STARTING_POSITION};
+    use crate::fmt::parse_coord;
+    use crate::magic::MagicBitBoards;
+    use crate::pgn::*;
+
+    #[test]
+    fn parse_simple_game() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let moves = parse_pgn_moves(&state, "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O Be7 1/2-1/2", &mbb).unwrap();
+
+        assert_eq!(moves, vec![
+            Move::Normal(Piece::Pawn, parse_coord("e2"), parse_coord("e4")),
+            Move::Normal(Piece::Pawn, parse_coord("e7"), parse_coord("e5")),
+            Move::Normal(Piece::Knight, parse_coord("g1"), parse_coord("f3")),
+            Move::Normal(Piece::Knight, parse_coord("b8"), parse_coord("c6")),
+            Move::Normal(Piece::Bishop, parse_coord("f1"), parse_coord("b5")),
+            Move::Normal(Piece::Pawn, parse_coord("a7"), parse_coord("a6")),
+            Move::Normal(Piece::Bishop, parse_coord("b5"), parse_coord("a4")),
+            Move::Normal(Piece::Knight, parse_coord("g8"), parse_coord("f6")),
+            Move::Castle,
+            Move::Normal(Piece::Bishop, parse_coord("f8"), parse_coord("e7")),
+        ]);
+    }
+
+    #[test]
+    fn parse_strips_comments_and_nags() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let moves = parse_pgn_moves(&state, "1.e4 $1 {best by test} 1... c5 ; sicilian\n2.Nf3 *", &mbb).unwrap();
+        assert_eq!(moves.len(), 3);
+    }
+
+    #[test]
+    fn parse_disambiguation_captures_and_promotions() {
+        let mbb = MagicBitBoards::default();
+
+        // Knights on b1 and f1 can both reach d2.
+        let state = load_fen("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1");
+        assert_eq!(
+            parse_pgn_moves(&state, "Nbd2", &mbb).unwrap(),
+            vec![Move::Normal(Piece::Knight, parse_coord("b1"), parse_coord("d2"))],
+        );
+        assert_eq!(
+            parse_pgn_moves(&state, "Nfd2", &mbb).unwrap(),
+            vec![Move::Normal(Piece::Knight, parse_coord("f1"), parse_coord("d2"))],
+        );
+        assert_eq!(parse_pgn_moves(&state, "Nd2", &mbb), Err(PgnParseError::AmbiguousMove("Nd2".to_owned())));
+
+        // Rooks on a1 and a5 can both reach a3.
+        let state = load_fen("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1");
+        assert_eq!(
+            parse_pgn_moves(&state, "R1a3", &mbb).unwrap(),
+            vec![Move::Normal(Piece::Rook, parse_coord("a1"), parse_coord("a3"))],
+        );
+
+        // Capturing promotion.
+        let state = load_fen("1r2k3/P7/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(
+            parse_pgn_moves(&state, "axb8=N+", &mbb).unwrap(),
+            vec![Move::Promotion(parse_coord("a7"), parse_coord("b8"), Piece::Knight)],
+        );
+
+        // Long castling.
+        let state = load_fen("r3k3/8/8/8/8/8/8/4K3 b q - 0 1");
+        assert_eq!(parse_pgn_moves(&state, "O-O-O", &mbb).unwrap(), vec![Move::LongCastle]);
+    }
+
+    #[test]
+    fn export_scholars_mate() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        let moves = parse_pgn_moves(&state, "1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7", &mbb).unwrap();
+
+        let mut game = PgnGame::new(state, moves);
+        game.event = "Casual Game".to_owned();
+        game.white = "Scholar".to_owned();
+        game.result = "1-0".to_owned();
+
+        assert_eq!(game.to_string(), [
+            "[Event \"Casual Game\"]",
+            "[Site \"?\"]",
+            "[Date \"????.??.??\"]",
+            "[Round \"?\"]",
+            "[White \"Scholar\"]",
+            "[Black \"?\"]",
+            "[Result \"1-0\"]",
+            "",
+            "1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7# 1-0",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn setup_tags_only_when_fen_differs() {
+        let mbb = MagicBitBoards::default();
+        let has_setup = |state: GameState| PgnGame::new(state, vec![]).to_string().contains("[SetUp ");
+
+        // Carries history from earlier moves, but is still the standard start.
+        let mut state = load_fen(STARTING_POSITION);
+        state.history.push(state.zh);
+        assert!(!has_setup(state));
+
+        // Same board after shuffling the knights, but the clocks have moved on.
+        let mut state = load_fen(STARTING_POSITION);
+        for mv in parse_pgn_moves(&state.clone(), "Nf3 Nf6 Ng1 Ng8", &mbb).unwrap() {
+            state.make_move(mv);
+        }
+        assert!(has_setup(state));
+    }
+
+    #[test]
+    fn export_from_position_wraps_lines() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("4k3/8/8/8/8/8/8/R3K3 b - - 0 1");
+        let shuffle = "Kd8 Ra2 Ke8 Ra1 ".repeat(6);
+        let moves = parse_pgn_moves(&state, &shuffle, &mbb).unwrap();
+
+        let pgn = PgnGame::new(state, moves).to_string();
+        let lines: Vec<&str> = pgn.lines().collect();
+
+        assert_eq!(lines[7], "[SetUp \"1\"]");
+        assert_eq!(lines[8], "[FEN \"4k3/8/8/8/8/8/8/R3K3 b - - 0 1\"]");
+        assert!(lines[10].starts_with("1... Kd8 2. Ra2 Ke8 3. Ra1 Kd8"));
+        assert!(lines.iter().all(|l| l.len() <= 80));
+        assert!(lines.len() > 11);
+        assert!(lines.last().unwrap().ends_with("13. Ra1 *"));
+    }
+
+    #[test]
+    fn format_disambiguation() {
+        let mbb = MagicBitBoards::default();
+
+        let state = load_fen("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1");
+        let nbd2 = Move::Normal(Piece::Knight, parse_coord("b1"), parse_coord("d2"));
+        let nfd2 = Move::Normal(Piece::Knight, parse_coord("f1"), parse_coord("d2"));
+        let nc3 = Move::Normal(Piece::Knight, parse_coord("b1"), parse_coord("c3"));
+        assert_eq!(PGNMove::from_internal(&state, nbd2, &mbb).to_string(), "Nbd2");
+        assert_eq!(PGNMove::from_internal(&state, nfd2, &mbb).to_string(), "Nfd2");
+        assert_eq!(PGNMove::from_internal(&state, nc3, &mbb).to_string(), "Nc3");
+
+        let state = load_fen("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1");
+        let r1a3 = Move::Normal(Piece::Rook, parse_coord("a1"), parse_coord("a3"));
+        let r5a3 = Move::Normal(Piece::Rook, parse_coord("a5"), parse_coord("a3"));
+        assert_eq!(PGNMove::from_internal(&state, r1a3, &mbb).to_string(), "R1a3");
+        assert_eq!(PGNMove::from_internal(&state, r5a3, &mbb).to_string(), "R5a3");
+
+        // Three queens can reach e4, so the one on h4 needs a full square.
+        let state = load_fen("4k3/8/8/8/Q6Q/8/8/K6Q w - - 0 1");
+        let qh4e4 = Move::Normal(Piece::Queen, parse_coord("h4"), parse_coord("e4"));
+        assert_eq!(PGNMove::from_internal(&state, qh4e4, &mbb).to_string(), "Qh4e4+");
+
+        // Pawn captures always include the source file.
+        let state = load_fen("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1");
+        let exd5 = Move::Normal(Piece::Pawn, parse_coord("e4"), parse_coord("d5"));
+        assert_eq!(PGNMove::from_internal(&state, exd5, &mbb).to_string(), "exd5");
+
+        let state = load_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1");
+        let exd6 = Move::EnPassant(parse_coord("e5"), parse_coord("d6"));
+        assert_eq!(PGNMove::from_internal(&state, exd6, &mbb).to_string(), "exd6");
+        assert_eq!(parse_san(&state, "exd6", &mbb), Some(exd6));
+    }
+
+    #[test]
+    fn format_checkmate() {
+        let mbb = MagicBitBoards::default();
+
+        // Back rank mate.
+        let state = load_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+        let ra8 = Move::Normal(Piece::Rook, parse_coord("a1"), parse_coord("a8"));
+        assert_eq!(PGNMove::from_internal(&state, ra8, &mbb).to_string(), "Ra8#");
+
+        // Escape square, so just check.
+        let state = load_fen("6k1/5pp1/8/8/8/8/8/R5K1 w - - 0 1");
+        assert_eq!(PGNMove::from_internal(&state, ra8, &mbb).to_string(), "Ra8+");
+
+        // Stalemate is unmarked.
+        let state = load_fen("k7/8/8/8/8/8/1Q6/K7 w - - 0 1");
+        let qb6 = Move::Normal(Piece::Queen, parse_coord("b2"), parse_coord("b6"));
+        assert_eq!(PGNMove::from_internal(&state, qb6, &mbb).to_string(), "Qb6");
+    }
+
+    #[test]
+    fn parse_single_san() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+
+        assert_eq!(parse_san(&state, "O-O", &mbb), Some(Move::Castle));
+        assert_eq!(parse_san(&state, "O-O-O", &mbb), Some(Move::LongCastle));
+        assert_eq!(
+            parse_san(&state, "Qxf6", &mbb),
+            Some(Move::Normal(Piece::Queen, parse_coord("f3"), parse_coord("f6"))),
+        );
+        assert_eq!(
+            parse_san(&state, "dxe6!?", &mbb),
+            Some(Move::Normal(Piece::Pawn, parse_coord("d5"), parse_coord("e6"))),
+        );
+        assert_eq!(
+            parse_san(&state, "Nxf7", &mbb),
+            Some(Move::Normal(Piece::Knight, parse_coord("e5"), parse_coord("f7"))),
+        );
+        assert_eq!(
+            parse_san(&state, "Nb5+", &mbb),
+            Some(Move::Normal(Piece::Knight, parse_coord("c3"), parse_coord("b5"))),
+        );
+
+        // Illegal, ambiguous, and syntactically invalid.
+        assert_eq!(parse_san(&state, "Ke3", &mbb), None);
+        assert_eq!(parse_san(&state, "e4e5", &mbb), None);
+        assert_eq!(parse_san(&state, "", &mbb), None);
+
+        let state = load_fen("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1");
+        assert_eq!(parse_san(&state, "Nd2", &mbb), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mbb = MagicBitBoards::default();
+        let state = load_fen(STARTING_POSITION);
+        assert_eq!(parse_pgn_moves(&state, "1. e5", &mbb), Err(PgnParseError::NoMatchingMove("e5".to_owned())));
+        assert_eq!(parse_pgn_moves(&state, "1. Zz9", &mbb), Err(PgnParseError::InvalidSan("Zz9".to_owned())));
+    }
+}
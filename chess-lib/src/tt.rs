@@ -1,21 +1,25 @@
+use std::sync::{Mutex, MutexGuard};
 use crate::types::ZobristHash;
 
 pub struct TranspositionTable<T: Clone + Copy> {
     shift: u32,
     table: Vec<Option<TTNode<T>>>,
-    collision_policy: CollisionPolicy<T>,
+    replacement: Replacement<T>,
     stats: TTStats,
+    // Bumped for each new search, so entries left over from older searches can be evicted first.
+    generation: u8,
 }
 
 #[derive(Clone, Copy)]
 pub struct TTNode<T: Clone + Copy> {
     zh: ZobristHash,
     data: T,
+    generation: u8,
 }
 
 impl <T: Clone + Copy> TTNode<T> {
     pub fn new(zh: ZobristHash, data: T) -> TTNode<T> {
-        TTNode{zh, data}
+        TTNode{zh, data, generation: 0}
     }
 }
 
@@ -39,6 +43,11 @@ impl TTStats {
     pub fn fill_rate(&self) -> f64 {
         (self.filled as f64) / (self.size as f64)
     }
+
+    // Number of entries the table can hold.
+    pub fn size(&self) -> usize {
+        self.size
+    }
 }
 
 pub enum PolicyResult {
@@ -48,6 +57,23 @@ pub enum PolicyResult {
 
 pub type CollisionPolicy<T> = fn (T, T) -> PolicyResult;
 
+pub type DepthFn<T> = fn (&T) -> u32;
+
+#[derive(Clone, Copy)]
+enum Replacement<T> {
+    Single(CollisionPolicy<T>),
+    TwoBucket(DepthFn<T>),
+}
+
+impl <T> Replacement<T> {
+    fn bucket_size(&self) -> usize {
+        match self {
+            Replacement::Single(_) => 1,
+            Replacement::TwoBucket(_) => 2,
+        }
+    }
+}
+
 pub fn always_replace<T>(_: T, _: T) -> PolicyResult {
     PolicyResult::Replace
 }
@@ -58,12 +84,21 @@ pub fn never_replace<T>(_: T, _: T) -> PolicyResult {
 
 impl <T: Clone + Copy> TranspositionTable<T> {
     pub fn new(max_bytes: usize, collision_policy: CollisionPolicy<T>) -> TranspositionTable<T> {
-        let entry_size = std::mem::size_of::<TTNode<T>>();
-        let num_entries = (max_bytes / entry_size).next_power_of_two() >> 1;
+        Self::with_replacement(max_bytes, Replacement::Single(collision_policy))
+    }
+
+    // Each index holds two entries: one which is only replaced by deeper (or equally deep)
+    // searches, and one which is always replaced.
+    pub fn two_bucket(max_bytes: usize, depth: DepthFn<T>) -> TranspositionTable<T> {
+        Self::with_replacement(max_bytes, Replacement::TwoBucket(depth))
+    }
+
+    fn with_replacement(max_bytes: usize, replacement: Replacement<T>) -> TranspositionTable<T> {
+        let num_entries = Self::num_entries(max_bytes);
         TranspositionTable{
-            shift: 64 - num_entries.trailing_zeros(),
+            shift: Self::shift(num_entries, replacement),
             table: vec![None; num_entries],
-            collision_policy,
+            replacement,
             stats: TTStats{
                 hits: 0,
                 collisions: 0,
@@ -71,70 +106,147 @@ impl <T: Clone + Copy> TranspositionTable<T> {
                 size: num_entries,
                 filled: 0,
             },
+            generation: 0,
         }
     }
 
+    // The largest power of two number of entries that fits in `max_bytes`.
+    fn num_entries(max_bytes: usize) -> usize {
+        let entry_size = std::mem::size_of::<TTNode<T>>();
+        (max_bytes / entry_size).next_power_of_two() >> 1
+    }
+
+    // Indices are taken from the top bits of the hash.
+    fn shift(num_entries: usize, replacement: Replacement<T>) -> u32 {
+        let num_indices = num_entries / replacement.bucket_size();
+        64 - num_indices.trailing_zeros()
+    }
+
+    // Reallocates the table to fit in `max_bytes`, which throws away everything in it.
+    pub fn resize(&mut self, max_bytes: usize) {
+        let num_entries = Self::num_entries(max_bytes);
+        self.shift = Self::shift(num_entries, self.replacement);
+        self.table = vec![None; num_entries];
+        self.stats.size = num_entries;
+        self.clear();
+    }
+
+    // Empties the table without reallocating it.
+    pub fn clear(&mut self) {
+        self.table.iter_mut().for_each(|nd| *nd = None);
+        self.stats.hits = 0;
+        self.stats.collisions = 0;
+        self.stats.total = 0;
+        self.stats.filled = 0;
+        self.generation = 0;
+    }
+
+    pub fn new_search(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+    }
+
     pub fn stats(&self) -> &TTStats {
         &self.stats
     }
 
     pub fn get(&mut self, zh: ZobristHash) -> Option<T> {
         self.stats.total += 1;
-        self.table[self.index(zh)].and_then(|nd| {
-            if nd.zh == zh {
-                self.stats.hits += 1;
-                Some(nd.data)
-            } else {
-                self.stats.collisions += 1;
-                None
-            }
-        })
+        let result = self.peek(zh);
+        if result.is_some() {
+            self.stats.hits += 1;
+        } else if self.bucket(zh).any(|slot| self.table[slot].is_some()) {
+            self.stats.collisions += 1;
+        }
+        result
     }
 
+    // Like get, but doesn't affect the stats.
+    pub fn peek(&self, zh: ZobristHash) -> Option<T> {
+        self.bucket(zh)
+            .filter_map(|slot| self.table[slot])
+            .find(|nd| nd.zh == zh)
+            .map(|nd| nd.data)
+    }
+
+    // On an index collision the value is always computed and returned, even if the collision
+    // policy then decides to keep the existing entry. Use peek first to avoid the work if that
+    // matters.
     pub fn get_or_compute<F: FnOnce() -> T>(&mut self, zh: ZobristHash, compute: F) -> T {
-        self.stats.total += 1;
-        let ix = self.index(zh);
-        match self.table[ix].take() {
-            Some(prev) => {
-                println!("{:?} =? {:?}  {}", prev.zh, zh, prev.zh == zh);
-                if prev.zh == zh {
-                    self.stats.hits += 1;
-                    let data = prev.data;
-                    self.table[ix].replace(prev);
-                    data
-                } else {
-                    self.stats.collisions += 1;
-                    let data = compute();
-                    match (self.collision_policy)(prev.data, data) {
-                        PolicyResult::Keep => self.table[ix].replace(prev),
-                        PolicyResult::Replace => self.table[ix].replace(TTNode::new(zh, data)),
-                    };
-                    data
-                }
-            },
+        match self.get(zh) {
+            Some(data) => data,
             None => {
-                self.stats.filled += 1;
                 let data = compute();
-                self.table[ix].replace(TTNode::new(zh, data));
+                self.insert(zh, data);
                 data
             },
         }
     }
 
     pub fn insert(&mut self, zh: ZobristHash, data: T) {
-        let ix = self.index(zh);
-        match self.table[ix].take() {
-            Some(prev) => {
-                match (self.collision_policy)(prev.data, data) {
-                    PolicyResult::Keep => self.table[ix].replace(prev),
-                    PolicyResult::Replace => self.table[ix].replace(TTNode::new(zh, data)),
+        match self.replacement {
+            Replacement::Single(policy) => {
+                let ix = self.index(zh);
+                let node = self.node(zh, data);
+                match self.table[ix] {
+                    Some(prev) if prev.generation != self.generation => self.table[ix] = Some(node),
+                    Some(prev) => {
+                        if let PolicyResult::Replace = policy(prev.data, data) {
+                            self.table[ix] = Some(node);
+                        }
+                    },
+                    None => self.put(ix, node),
                 }
             },
-            None => {
-                self.stats.filled += 1;
-                self.table[ix].replace(TTNode::new(zh, data))
+            Replacement::TwoBucket(depth) => {
+                let depth_slot = self.index(zh) * 2;
+                let always_slot = depth_slot + 1;
+
+                // The same position can be updated by an equally deep search, but a different one
+                // is only displaced by a strictly deeper search, or if it's from an older search.
+                let node = self.node(zh, data);
+                let prev = self.table[depth_slot];
+                let keep_prev = match prev {
+                    Some(nd) if nd.generation != self.generation => false,
+                    Some(nd) if nd.zh == zh => depth(&data) < depth(&nd.data),
+                    Some(nd) => depth(&data) <= depth(&nd.data),
+                    None => false,
+                };
+
+                if keep_prev {
+                    self.put(always_slot, node);
+                } else {
+                    // Don't leave a stale copy of this position behind.
+                    if self.table[always_slot].map(|nd| nd.zh == zh).unwrap_or(false) {
+                        self.table[always_slot] = None;
+                        self.stats.filled -= 1;
+                    }
+
+                    self.put(depth_slot, node);
+
+                    // The old deep entry is still useful, so demote it rather than drop it.
+                    if let Some(prev) = prev.filter(|nd| nd.zh != zh) {
+                        self.put(always_slot, prev);
+                    }
+                }
             },
-        };
+        }
+    }
+
+    fn node(&self, zh: ZobristHash, data: T) -> TTNode<T> {
+        TTNode{zh, data, generation: self.generation}
+    }
+
+    fn put(&mut self, slot: usize, node: TTNode<T>) {
+        if self.table[slot].is_none() {
+            self.stats.filled += 1;
+        }
+        self.table[slot] = Some(node);
+    }
+
+    fn bucket(&self, zh: ZobristHash) -> std::ops::Range<usize> {
+        let size = self.replacement.bucket_size();
+        let start = self.index(zh) * size;
+        start..start + size
     }
 
     fn index(&self, zh: ZobristHash) -> usize {
@@ -142,6 +254,47 @@ impl <T: Clone + Copy> TranspositionTable<T> {
     }
 }
 
+// A table which can be shared between threads. Entries are split across independently locked
+// shards, so threads only contend when they touch the same shard.
+pub struct SharedTranspositionTable<T: Clone + Copy> {
+    shards: Vec<Mutex<TranspositionTable<T>>>,
+}
+
+impl <T: Clone + Copy> SharedTranspositionTable<T> {
+    pub fn two_bucket(max_bytes: usize, num_shards: usize, depth: DepthFn<T>) -> SharedTranspositionTable<T> {
+        let shards = (0..num_shards)
+            .map(|_| Mutex::new(TranspositionTable::two_bucket(max_bytes / num_shards, depth)))
+            .collect();
+        SharedTranspositionTable{shards}
+    }
+
+    pub fn get(&self, zh: ZobristHash) -> Option<T> {
+        self.shard(zh).get(zh)
+    }
+
+    pub fn peek(&self, zh: ZobristHash) -> Option<T> {
+        self.shard(zh).peek(zh)
+    }
+
+    pub fn insert(&self, zh: ZobristHash, data: T) {
+        self.shard(zh).insert(zh, data)
+    }
+
+    pub fn clear(&self) {
+        self.shards.iter().for_each(|shard| shard.lock().unwrap().clear());
+    }
+
+    pub fn new_search(&self) {
+        self.shards.iter().for_each(|shard| shard.lock().unwrap().new_search());
+    }
+
+    // Shards are chosen by the low bits of the hash, while the index within a shard uses the high
+    // bits, so the two are independent.
+    fn shard(&self, zh: ZobristHash) -> MutexGuard<'_, TranspositionTable<T>> {
+        self.shards[(zh.0 % self.shards.len() as u64) as usize].lock().unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::prelude::*;
@@ -153,7 +306,32 @@ mod tests {
     #[test]
     fn test_size() {
         let tt = TranspositionTable::<ZobristHash>::new(1 << 16, always_replace);
-        assert_eq!(tt.table.len() * std::mem::size_of::<TTNode<ZobristHash>>(), 32768);
+        assert_eq!(tt.table.len() * std::mem::size_of::<TTNode<ZobristHash>>(), 49152);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut tt = TranspositionTable::<u64>::two_bucket(1 << 16, |_| 0);
+        tt.insert(ZobristHash(12345), 1);
+        assert_eq!(tt.stats().size(), 2048);
+
+        tt.resize(1 << 20);
+        assert_eq!(tt.stats().size(), 32768);
+        assert_eq!(tt.table.len(), 32768);
+        assert_eq!(tt.get(ZobristHash(12345)), None);
+
+        // Every index is still reachable.
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        for _ in 0..10_000 {
+            let zh = ZobristHash(rng.gen());
+            tt.insert(zh, zh.0);
+            assert_eq!(tt.get(zh), Some(zh.0));
+        }
+
+        tt.resize(1 << 12);
+        assert_eq!(tt.stats().size(), 128);
+        tt.insert(ZobristHash(u64::MAX), 2);
+        assert_eq!(tt.get(ZobristHash(u64::MAX)), Some(2));
     }
 
     #[test]
@@ -227,6 +405,131 @@ mod tests {
         assert_eq!(tt.get(zh), None);
     }
 
+    #[test]
+    fn populated_probe() {
+        let mut tt = TranspositionTable::<u64>::new(1 << 8, always_replace);
+        let zh = ZobristHash(12345);
+        tt.insert(zh, 42);
+        assert_eq!(tt.get_or_compute(zh, || 0), 42);
+        assert_eq!(tt.get_or_compute(generate_collision(&tt, zh), || 0), 0);
+    }
+
+    #[test]
+    fn get_or_compute_is_silent() {
+        // Re-run the probe test in a child process so that its stdout can be inspected.
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&["--exact", "tt::tests::populated_probe", "--nocapture", "-q"])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        assert_eq!(output.status.success(), true);
+        assert_eq!(stdout.contains("ZobristHash"), false);
+    }
+
+    #[test]
+    fn test_two_bucket() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let mut tt = TranspositionTable::<u32>::two_bucket(1 << 8, |d| *d);
+
+        let zh = ZobristHash(rng.gen());
+        let collision = generate_collision(&tt, zh);
+        let collision_2 = loop {
+            let candidate = ZobristHash(rng.gen());
+            if tt.index(candidate) == tt.index(zh) && candidate != zh && candidate != collision {
+                break candidate;
+            }
+        };
+
+        tt.insert(zh, 5);
+
+        // A shallower entry goes in the always-replace slot, keeping the deep entry.
+        tt.insert(collision, 1);
+        assert_eq!(tt.get(zh), Some(5));
+        assert_eq!(tt.get(collision), Some(1));
+
+        // ...and is itself replaced by the next shallow entry.
+        tt.insert(collision_2, 2);
+        assert_eq!(tt.get(zh), Some(5));
+        assert_eq!(tt.get(collision), None);
+        assert_eq!(tt.get(collision_2), Some(2));
+
+        // A deeper entry takes the depth-preferred slot, and the old one is demoted.
+        tt.insert(collision, 6);
+        assert_eq!(tt.get(collision), Some(6));
+        assert_eq!(tt.get(zh), Some(5));
+        assert_eq!(tt.get(collision_2), None);
+
+        // Updating a position doesn't leave a stale duplicate behind.
+        tt.insert(zh, 7);
+        assert_eq!(tt.get(zh), Some(7));
+        assert_eq!(tt.get(collision), Some(6));
+        assert_eq!(tt.stats().filled, 2);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut tt = TranspositionTable::<u64>::new(1 << 8, always_replace);
+        let zh = ZobristHash(12345);
+        tt.insert(zh, 42);
+        assert_eq!(tt.get(zh), Some(42));
+
+        tt.clear();
+        assert_eq!(tt.get(zh), None);
+        assert_eq!(tt.stats().filled, 0);
+    }
+
+    #[test]
+    fn test_new_search_evicts_old_entries() {
+        let mut rng = ChaCha8Rng::seed_from_u64(12345);
+        let mut tt = TranspositionTable::<u64>::new(1 << 8, never_replace);
+        let zh = ZobristHash(rng.gen());
+        let collision = generate_collision(&tt, zh);
+
+        tt.insert(zh, 42);
+        tt.insert(collision, 0);
+        assert_eq!(tt.get(zh), Some(42));
+
+        tt.new_search();
+        tt.insert(collision, 0);
+        assert_eq!(tt.get(zh), None);
+        assert_eq!(tt.get(collision), Some(0));
+
+        // Deep entries from an old search give way to shallower ones from the current search, and
+        // are demoted to the always-replace slot where they are soon overwritten.
+        let mut tt = TranspositionTable::<u32>::two_bucket(1 << 8, |d| *d);
+        tt.insert(zh, 5);
+        tt.new_search();
+        tt.insert(collision, 1);
+        assert_eq!(tt.get(collision), Some(1));
+        assert_eq!(tt.get(zh), Some(5));
+        tt.insert(ZobristHash(zh.0 ^ 1), 1);
+        assert_eq!(tt.get(zh), None);
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        let tt = SharedTranspositionTable::<u32>::two_bucket(1 << 16, 4, |d| *d);
+
+        std::thread::scope(|scope| {
+            for t in 0..4u64 {
+                let tt = &tt;
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        tt.insert(ZobristHash((i << 40) | t), i as u32);
+                    }
+                });
+            }
+        });
+
+        for t in 0..4 {
+            assert_eq!(tt.get(ZobristHash((99 << 40) | t)), Some(99));
+        }
+
+        tt.clear();
+        assert_eq!(tt.peek(ZobristHash(99 << 40)), None);
+    }
+
     fn generate_collision<T: Copy>(tt: &TranspositionTable<T>, zh: ZobristHash) -> ZobristHash {
         let mut rng = ChaCha8Rng::seed_from_u64(12345);
         loop {
@@ -1,4 +1,6 @@
 
+// Note that == compares the move clocks and history too, so two visits to the same position
+// usually aren't equal. Use repetition_key to compare positions.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GameState {
     pub active_colour: Colour,
@@ -6,12 +8,44 @@ pub struct GameState {
     pub black: SideState,
     pub en_passant: Option<BitCoord>,
     pub fifty_move_clock: u8,
+    // Starts at 1 and goes up after each of Black's moves, as in FEN.
+    pub fullmove_number: u16,
+    // Half-moves played since the start of the game.
+    pub ply: u32,
     pub zh: ZobristHash,
+    // Hashes of all previous positions in the game, oldest first.
+    pub history: Vec<ZobristHash>,
+    // Index into history of the first position since the last capture or pawn move. Earlier
+    // positions can never recur.
+    pub last_irreversible: usize,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ZobristHash(pub u64);
 
+// Everything needed to reverse a move, returned from make_move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Undo {
+    pub captured: Option<Piece>,
+    pub white_castling: (bool, bool),
+    pub black_castling: (bool, bool),
+    pub en_passant: Option<BitCoord>,
+    pub fifty_move_clock: u8,
+    pub last_irreversible: usize,
+    pub white_score: MaterialScore,
+    pub black_score: MaterialScore,
+    pub zh_delta: u64,
+}
+
+// What kind of move was just made, returned from make_move_info.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MoveInfo {
+    pub captured: Option<Piece>,
+    pub gives_check: bool,
+    pub is_castle: bool,
+    pub is_pawn_move: bool,
+}
+
 impl std::ops::BitXor<u64> for ZobristHash {
     type Output = ZobristHash;
 
@@ -20,11 +54,44 @@ impl std::ops::BitXor<u64> for ZobristHash {
     }
 }
 
+// Running totals of one side's material and piece-square values, in centipawns, kept up to date
+// as pieces move so the evaluation can read them directly.
+// The piece-square values have midgame and endgame versions, which only differ for the king.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaterialScore {
+    pub material: i64,
+    pub pst_mg: i64,
+    pub pst_eg: i64,
+}
+
+impl std::ops::AddAssign for MaterialScore {
+    fn add_assign(&mut self, rhs: MaterialScore) {
+        self.material += rhs.material;
+        self.pst_mg += rhs.pst_mg;
+        self.pst_eg += rhs.pst_eg;
+    }
+}
+
+impl std::ops::SubAssign for MaterialScore {
+    fn sub_assign(&mut self, rhs: MaterialScore) {
+        self.material -= rhs.material;
+        self.pst_mg -= rhs.pst_mg;
+        self.pst_eg -= rhs.pst_eg;
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SideState {
     pub pieces: Pieces,
+    // Seeded by GameState::new and updated by make_move.
+    pub score: MaterialScore,
     pub can_castle_kingside: bool,
     pub can_castle_queenside: bool,
+    // Files of the king and rooks the castling rights refer to.
+    // Always e, h and a in standard chess, but may differ in Chess960.
+    pub king_file: u32,
+    pub kingside_rook_file: u32,
+    pub queenside_rook_file: u32,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -97,6 +164,17 @@ impl Pieces {
         self.put_piece(pc, to);
     }
 
+    pub fn of(&self, piece: Piece) -> BitBoard {
+        match piece {
+            Piece::King => self.king,
+            Piece::Queen => self.queens,
+            Piece::Rook => self.rooks,
+            Piece::Bishop => self.bishops,
+            Piece::Knight => self.knights,
+            Piece::Pawn => self.pawns,
+        }
+    }
+
     fn piece_bb(&mut self, piece: Piece) -> &mut BitBoard {
         match piece {
             Piece::King => &mut self.king,
@@ -134,17 +212,18 @@ pub enum Piece {
     Bishop,
 }
 
+// Whether the game is over, and if so, why.
+// Checkmate means the active colour has lost.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Square {
-    Empty,
-    Occupied(Colour, Piece),
+pub enum Status {
+    Ongoing,
+    Checkmate,
+    Stalemate,
+    FiftyMoveDraw,
+    InsufficientMaterial,
+    ThreefoldRepetition,
 }
 
-// Note: because of how our coordinates work, we need 120 slots here.
-//       (max coord is 0x77 = 119)
-// Only 64 will ever be filled.
-pub type Board = [Square; 120];
-
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct BitBoard(pub u64);
 
@@ -181,6 +260,15 @@ impl std::ops::Shr<u32> for BitBoard {
     }
 }
 
+impl std::ops::Shl<u32> for BitBoard {
+    type Output = BitBoard;
+
+    fn shl(self, rhs: u32) -> Self::Output {
+        let Self(lhs) = self;
+        Self(lhs << rhs)
+    }
+}
+
 impl Into<u64> for BitBoard {
     fn into(self) -> u64 {
         self.0
@@ -222,6 +310,19 @@ impl BitBoard {
     pub fn count(self) -> u32 {
         return self.0.count_ones();
     }
+
+    pub fn contains(self, c: BitCoord) -> bool {
+        self.0 & c.0 != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    // (file, rank) of each set square.
+    pub fn squares(self) -> impl Iterator<Item=(u8, u8)> {
+        self.iter().map(|c| (c.file() as u8, c.rank() as u8))
+    }
 }
 
 pub struct BitBoardIter {
@@ -255,11 +356,24 @@ pub struct BitCoord(pub u64);
 
 impl BitCoord {
     pub fn rank(self) -> u32 {
-        self.0.trailing_zeros() / 8
+        self.index() as u32 / 8
     }
 
     pub fn file(self) -> u32 {
-        (63 - self.0.trailing_zeros()) % 8
+        (63 - self.index() as u32) % 8
+    }
+
+    // Position of the bit, so h1 is 0 and a8 is 63.
+    pub fn index(self) -> u8 {
+        self.0.trailing_zeros() as u8
+    }
+
+    pub fn to_algebraic(self) -> String {
+        crate::fmt::format_coord(self)
+    }
+
+    pub fn from_algebraic(s: &str) -> Option<BitCoord> {
+        s.parse().ok()
     }
 }
 
@@ -333,10 +447,59 @@ impl From<u64> for BitCoord {
 pub enum Move {
     Normal(Piece, BitCoord, BitCoord),
     Promotion(BitCoord, BitCoord, Piece),
+    // A pawn capturing en passant, from and to. The captured pawn is behind the target square.
+    EnPassant(BitCoord, BitCoord),
     Castle,
     LongCastle,
 }
 
+// Packed as source square in bits 0-5, target square in bits 6-11 and a flag in bits 12-15.
+// Squares count from a1 = 0 along the ranks, so h8 = 63.
+// The flag records the moving piece for normal moves, so no position is needed to unpack them.
+// Castles are stored as the king's standard two-square move for white (e1g1 or e1c1) whatever
+// the colour or starting files; the position decides where the pieces actually go.
+const MOVE_FLAG_NORMAL: [Piece; 6] = [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen, Piece::King];
+const MOVE_FLAG_PROMOTION: [Piece; 4] = [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen];
+const MOVE_FLAG_EN_PASSANT: u16 = 10;
+const MOVE_FLAG_CASTLE: u16 = 11;
+const MOVE_FLAG_LONG_CASTLE: u16 = 12;
+
+impl Move {
+    pub fn to_u16(self) -> u16 {
+        let square = |c: BitCoord| (c.rank() * 8 + c.file()) as u16;
+        let pack = |src: u16, tgt: u16, flag: u16| src | tgt << 6 | flag << 12;
+        let flag_of = |pieces: &[Piece], piece: Piece| {
+            pieces.iter().position(|&p| p == piece).expect("No flag for piece") as u16
+        };
+
+        match self {
+            Move::Normal(piece, src, tgt) => pack(square(src), square(tgt), flag_of(&MOVE_FLAG_NORMAL, piece)),
+            Move::Promotion(src, tgt, piece) => pack(square(src), square(tgt), 6 + flag_of(&MOVE_FLAG_PROMOTION, piece)),
+            Move::EnPassant(src, tgt) => pack(square(src), square(tgt), MOVE_FLAG_EN_PASSANT),
+            Move::Castle => pack(4, 6, MOVE_FLAG_CASTLE),
+            Move::LongCastle => pack(4, 2, MOVE_FLAG_LONG_CASTLE),
+        }
+    }
+
+    // None for the unused flags 13-15, e.g. from a corrupt table or book entry.
+    pub fn from_u16(bits: u16) -> Option<Move> {
+        let square = |bits: u16| BitCoord::from(((bits & 7) as u32, (bits >> 3 & 7) as u32));
+        let src = square(bits);
+        let tgt = square(bits >> 6);
+
+        let mv = match bits >> 12 {
+            flag @ 0..=5 => Move::Normal(MOVE_FLAG_NORMAL[flag as usize], src, tgt),
+            flag @ 6..=9 => Move::Promotion(src, tgt, MOVE_FLAG_PROMOTION[flag as usize - 6]),
+            MOVE_FLAG_EN_PASSANT => Move::EnPassant(src, tgt),
+            MOVE_FLAG_CASTLE => Move::Castle,
+            MOVE_FLAG_LONG_CASTLE => Move::LongCastle,
+            _ => return None,
+        };
+
+        Some(mv)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::types::*;
@@ -347,4 +510,77 @@ mod test {
         pieces.put_piece(Piece::Knight, BitCoord(27));
         assert_eq!(pieces.get_piece(BitCoord(27)), Some(Piece::Knight));
     }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(BitBoard::EMPTY.count(), 0);
+        assert_eq!(BitBoard(0x00_00_00_00_00_00_00_08).count(), 1);
+        assert_eq!(BitBoard(0x00_00_00_00_00_00_FF_00).count(), 8);
+        assert_eq!(BitBoard(0x81_00_00_00_00_00_00_81).count(), 4);
+        assert_eq!(BitBoard(0xFF_FF_FF_FF_FF_FF_FF_FF).count(), 64);
+    }
+
+    #[test]
+    fn test_contains() {
+        let bb = BitBoard(0x00_00_00_00_00_00_FF_00);
+        assert!(bb.contains(BitCoord(0x00_00_00_00_00_00_10_00)));
+        assert!(!bb.contains(BitCoord(0x00_00_00_00_00_10_00_00)));
+        assert!(!bb.is_empty());
+        assert!(BitBoard::EMPTY.is_empty());
+        assert_eq!(bb << 8, BitBoard(0x00_00_00_00_00_FF_00_00));
+    }
+
+    #[test]
+    fn test_coord_conversions() {
+        let mut all = BitBoard::EMPTY;
+        for file in 0..8u8 {
+            for rank in 0..8u8 {
+                let c = BitCoord::from((file, rank));
+                let name = c.to_algebraic();
+                assert_eq!(BitCoord::from_algebraic(&name), Some(c));
+                assert_eq!(BitCoord(1 << c.index()), c);
+                assert_eq!(BitBoard(c.0).squares().collect::<Vec<_>>(), vec![(file, rank)]);
+                all = all | c;
+            }
+        }
+
+        assert_eq!(BitCoord::from((0u8, 0u8)).to_algebraic(), "a1");
+        assert_eq!(BitCoord::from_algebraic("h8").map(|c| c.index()), Some(56));
+        assert_eq!(BitCoord::from_algebraic("i9"), None);
+        assert_eq!(all.squares().count(), 64);
+    }
+
+    #[test]
+    fn test_move_u16_round_trip() {
+        let sq = |file: u32, rank: u32| BitCoord::from((file, rank));
+
+        let mut moves = vec![
+            Move::EnPassant(sq(4, 4), sq(3, 5)),
+            Move::EnPassant(sq(1, 3), sq(0, 2)),
+            Move::Castle,
+            Move::LongCastle,
+        ];
+        for piece in [Piece::Pawn, Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen, Piece::King].iter() {
+            moves.push(Move::Normal(*piece, sq(0, 0), sq(7, 7)));
+            moves.push(Move::Normal(*piece, sq(7, 7), sq(0, 0)));
+            moves.push(Move::Normal(*piece, sq(3, 1), sq(4, 6)));
+        }
+        for piece in [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen].iter() {
+            moves.push(Move::Promotion(sq(6, 6), sq(7, 7), *piece));
+            moves.push(Move::Promotion(sq(0, 1), sq(0, 0), *piece));
+        }
+
+        for mv in moves {
+            assert_eq!(Move::from_u16(mv.to_u16()), Some(mv), "{:?}", mv);
+        }
+
+        for flag in 13..16 {
+            assert_eq!(Move::from_u16(12 | 28 << 6 | flag << 12), None);
+        }
+
+        // e2e4 by a pawn, and the documented castling squares.
+        assert_eq!(Move::Normal(Piece::Pawn, sq(4, 1), sq(4, 3)).to_u16(), 12 | 28 << 6);
+        assert_eq!(Move::Castle.to_u16(), 4 | 6 << 6 | 11 << 12);
+        assert_eq!(Move::LongCastle.to_u16(), 4 | 2 << 6 | 12 << 12);
+    }
 }
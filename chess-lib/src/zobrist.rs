@@ -1,9 +1,11 @@
+use std::sync::OnceLock;
+
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 
 use crate::types::{BitBoard, BitCoord, Colour, GameState, Piece, ZobristHash};
 
-static mut DEFAULT_HASHER: Option<ZobristHasher> = None;
+static DEFAULT_HASHER: OnceLock<ZobristHasher> = OnceLock::new();
 
 
 #[derive(Clone, Copy, Debug)]
@@ -15,15 +17,14 @@ impl ZobristHasher {
     const DEFAULT_SEED: u64 = 26355;
     const BLACK_TO_MOVE: usize = 12 * 64;
     const WHITE_QUEENSIDE: usize = 12 * 64 + 1;
-    const WHITE_KINGSIDE: usize = 12 * 64 + 1;
-    const BLACK_QUEENSIDE: usize = 12 * 64 + 2;
-    const BLACK_KINGSIDE: usize = 12 * 64 + 3;
-    const EN_PASSANT: usize = 12 * 64 + 4;
+    const WHITE_KINGSIDE: usize = 12 * 64 + 2;
+    const BLACK_QUEENSIDE: usize = 12 * 64 + 3;
+    const BLACK_KINGSIDE: usize = 12 * 64 + 4;
+    // One per file, so this is the last 8 entries.
+    const EN_PASSANT: usize = 12 * 64 + 5;
 
     pub fn default() -> &'static ZobristHasher {
-        unsafe {
-            DEFAULT_HASHER.get_or_insert_with(|| Self::from_seed(Self::DEFAULT_SEED))
-        }
+        DEFAULT_HASHER.get_or_init(|| Self::from_seed(Self::DEFAULT_SEED))
     }
 
     pub fn from_seed(seed: u64) -> ZobristHasher {
@@ -129,7 +130,7 @@ impl ZobristHasher {
     }
 
     fn piece_index(colour: Colour, piece: Piece, coord: BitCoord) -> usize {
-        let coord_ix: usize = coord.0.trailing_zeros() as usize;
+        let coord_ix: usize = coord.index() as usize;
 
         let piece_ix: usize = match piece {
             Piece::King => 0 * 64,
@@ -160,7 +161,41 @@ mod tests {
         let hasher = ZobristHasher::default();
         let state = load_fen(STARTING_POSITION);
         let zh = hasher.hash(&state);
-        assert_eq!(zh, ZobristHash(0x5aba4aaed7d93fd));
+        assert_eq!(zh, ZobristHash(0x82c2a351a83b26ae));
+    }
+
+    #[test]
+    fn default_shared_across_threads() {
+        let hashers: Vec<usize> = (0..8)
+            .map(|_| std::thread::spawn(|| ZobristHasher::default() as *const ZobristHasher as usize))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect();
+
+        assert!(hashers.iter().all(|h| *h == hashers[0]));
+    }
+
+    #[test]
+    fn castling_rights_distinct() {
+        let hasher = ZobristHasher::default();
+        let zh = ZobristHash(0);
+        let hashes = [
+            hasher.toggle_white_queenside(zh),
+            hasher.toggle_white_kingside(zh),
+            hasher.toggle_black_queenside(zh),
+            hasher.toggle_black_kingside(zh),
+        ];
+
+        for ix in 0..hashes.len() {
+            assert_ne!(hashes[ix], zh);
+            for jx in (ix + 1)..hashes.len() {
+                assert_ne!(hashes[ix], hashes[jx]);
+            }
+        }
+
+        // Toggling both white rights must not cancel out.
+        assert_ne!(hasher.toggle_white_kingside(hasher.toggle_white_queenside(zh)), zh);
     }
 }
 
@@ -10,13 +10,13 @@ use chess_lib::zobrist::ZobristHasher;
 fn zobrist_chaos() {
     // Tests that incrementally updated zobrist hash equals one computed from scratch.
     let mut rng = ChaCha8Rng::seed_from_u64(12345);
-    let mbb = MagicBitBoards::default();
+    let mbb = MagicBitBoards::shared();
     let hasher = ZobristHasher::default();
     let mut state = load_fen(STARTING_POSITION);
     let mut sequence: Vec<Move> = vec![];
 
     for _ in 0..100_000 {
-        let moves = legal_moves(&state, &mbb);
+        let moves = legal_moves(&state, mbb);
         match moves.choose(&mut rng) {
             Some(mv) => {
                 state.make_move(*mv);
@@ -0,0 +1,43 @@
+use chess_lib::magic::MagicBitBoards;
+use chess_lib::perft::{parse_perft_suite, run_perft_suite, PerftMismatch};
+
+const SUITE: &str = include_str!("data/perft_suite.epd");
+
+// Deeper entries take too long for a debug build. Run through the CLI for the full suite.
+const MAX_DEPTH: u8 = 3;
+
+#[test]
+fn perft_suite() {
+    let mbb = MagicBitBoards::shared();
+    let cases = parse_perft_suite(SUITE).unwrap();
+    assert_eq!(cases.len(), 6);
+
+    let mismatches = run_perft_suite(&cases, MAX_DEPTH, mbb);
+    for m in mismatches.iter() {
+        println!("{}", m);
+    }
+    assert!(mismatches.is_empty());
+}
+
+#[test]
+fn reports_mismatches() {
+    let mbb = MagicBitBoards::shared();
+    let cases = parse_perft_suite("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1 ;D1 14 ;D2 190\n").unwrap();
+
+    assert_eq!(run_perft_suite(&cases, MAX_DEPTH, mbb), vec![PerftMismatch{
+        fen: "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1".to_owned(),
+        depth: 2,
+        expected: 190,
+        actual: 191,
+    }]);
+}
+
+#[test]
+fn invalid_entries() {
+    assert_eq!(parse_perft_suite("\n# comment\n8/8/8/8/8/8/8/K6k w - - 0 1 ;D1 3").unwrap().len(), 1);
+
+    let err = parse_perft_suite("\n8/8/8/8/8/8/8/K6k w - - 0 1 ;X1 3").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert!(parse_perft_suite("8/8/8/8/8/8/8/K6k w - - 0 1").is_err());
+    assert!(parse_perft_suite("8/8/8/8/8/8/8/K6k w - - 0 1 ;D1").is_err());
+}